@@ -1,9 +1,22 @@
+#![allow(clippy::needless_return)]
+
+pub mod limiter;
+pub mod probability;
+pub mod roll;
+pub mod script;
+pub mod selector;
+
+use limiter::{LimitMode, LimitViolation, Limited, LimiterError, LimiterRegistry};
+use script::{ScriptError, Value};
+use selector::{SelectorError, SelectorRegistry};
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use types::character_sheet_collection::{
-    CalculatedValue, FeatureModifier, FeatureSet, Script, StaticValueType,
+    CalculatedValue, FeatureModifier, FeatureSet, PropertyDefinition, Script, StaticValueType,
 };
+use types::fraction::Fraction;
 
 pub type ResultValue = Result<StaticValueType, ValueCalculationError>;
 
@@ -31,11 +44,52 @@ pub enum ValueCalculationError {
     /// A cyclic dependency was found.
     Cycle(Vec<CycleNode>),
     /// Evaluation of the script threw some error.
-    ScriptError(String),
+    ScriptError(ScriptError),
     /// A property had no value or feature reference, but is required as a dependency.
     MissingDependency(MissingDependency),
+    /// The values of the property could not be combined by its selector.
+    SelectorError(SelectorError),
+    /// The value is outside of the limits of the property.
+    /// Only reported with [`LimitMode::Report`].
+    LimitViolated(LimitViolation),
+    /// A limiter of the property could not be applied.
+    LimiterError(LimiterError),
+}
+
+impl fmt::Display for ValueCalculationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueCalculationError::Cycle(cycle) => {
+                write!(f, "Cyclic dependency: ")?;
+                for (index, node) in cycle.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " -> ")?;
+                    }
+                    write!(f, "`{}` of `{}`", node.property, node.feature)?;
+                }
+                return Ok(());
+            }
+            ValueCalculationError::ScriptError(error) => write!(f, "{}", error),
+            ValueCalculationError::MissingDependency(missing) => write!(
+                f,
+                "`{}` has no value, but `{}` of `{}` depends on it",
+                missing.missing_dependency, missing.found_in_property, missing.found_in_feature
+            ),
+            ValueCalculationError::SelectorError(error) => write!(f, "{}", error),
+            ValueCalculationError::LimitViolated(violation) => {
+                write!(f, "The value violates the limiter `{}", violation.limiter)?;
+                for argument in &violation.arguments {
+                    write!(f, " {}", argument)?;
+                }
+                return write!(f, "`");
+            }
+            ValueCalculationError::LimiterError(error) => write!(f, "{}", error),
+        }
+    }
 }
 
+impl std::error::Error for ValueCalculationError {}
+
 #[cfg_attr(
     feature = "serde",
     derive(Deserialize, Serialize),
@@ -57,6 +111,17 @@ pub struct CycleNode {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum IllegalSheetError {}
 
+/// Configures how the values of a character sheet are calculated.
+#[derive(Debug, Default)]
+pub struct CalculationOptions {
+    /// The selectors that property definitions may use.
+    pub selectors: SelectorRegistry,
+    /// The limiters that property definitions may use.
+    pub limiters: LimiterRegistry,
+    /// Whether values outside of their limits are clamped or reported.
+    pub limit_mode: LimitMode,
+}
+
 /// The data behind a character sheet.
 /// The base class of the engine.
 #[cfg_attr(
@@ -70,6 +135,7 @@ pub struct CharacterSheet {
     /// Generally speaking this includes two different use cases:
     /// - Selections during the character creation (e.g. stat spread, level...).
     /// - Overwrites by the user.
+    ///
     /// User values will always overwrite those set by features.
     pub user_values: HashMap<String, StaticValueType>,
     /// Active features will apply their modifications to the properties of the character.
@@ -81,6 +147,12 @@ pub struct CharacterSheet {
     pub inactive_features: Vec<FeatureSet>,
 }
 
+impl Default for CharacterSheet {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
 impl CharacterSheet {
     pub fn new() -> CharacterSheet {
         return CharacterSheet {
@@ -121,41 +193,74 @@ impl CharacterSheet {
         return &required_properties - &specified_properties;
     }
 
+    /// Calculates and returns all values using the built-in selectors and limiters.
+    /// Values outside of their limits are clamped.
+    pub fn calculate_all_values(&self) -> Result<HashMap<String, ResultValue>, IllegalSheetError> {
+        return self.calculate_all_values_with(&CalculationOptions::default());
+    }
+
     /// Calculates and returns all values.
-    pub fn calculate_all_values<'a>(
+    ///
+    /// All modifiers of a property are combined:
+    /// 1. The values of all "set" modifiers (static values and scripts that don't reference the
+    ///    current value `@`) are combined using the selector of the property's definition.
+    ///    Without a definition the last one wins.
+    /// 2. All "bonus" modifiers (scripts that reference the current value `@`) are applied on top
+    ///    in the order of the features. If there is no "set" modifier, they start from 0.
+    /// 3. Fractional values are rounded with the rounding mode of the property's definition.
+    ///    Without a rounding mode they are kept as fractions.
+    /// 4. The limiters of the property's definition are applied.
+    ///
+    /// The rounding and limiters are applied to user values, too.
+    pub fn calculate_all_values_with<'a>(
         &'a self,
+        options: &'a CalculationOptions,
     ) -> Result<HashMap<String, ResultValue>, IllegalSheetError> {
         let mut values: HashMap<String, ResultValue> = HashMap::new();
 
         self.add_user_values(&mut values);
 
-        let mut calc_map: HashMap<String, CalcInfo<'a>> = HashMap::new();
+        let mut calc_map: HashMap<&'a str, Vec<CalcInfo<'a>>> = HashMap::new();
+        let mut definitions: HashMap<&'a str, &'a PropertyDefinition> = HashMap::new();
         for feature_set in &self.active_features {
             for feature in &feature_set.features {
+                for definition in &feature.definitions {
+                    definitions.entry(&definition.name).or_insert(definition);
+                }
                 for modifier in &feature.modifiers {
-                    calc_map.insert(
-                        modifier.property.clone(),
-                        CalcInfo {
+                    calc_map
+                        .entry(&modifier.property)
+                        .or_default()
+                        .push(CalcInfo {
                             feature_set: &feature_set.name,
                             feature: &feature.name,
-                            modifier: &modifier,
-                        },
-                    );
+                            modifier,
+                        });
                 }
             }
         }
 
-        let mut currently_calculating: HashSet<String> = HashSet::new();
-        for (_, calc_info) in &calc_map {
-            self.add_or_calc(
-                &mut values,
-                &mut currently_calculating,
-                &calc_map,
-                calc_info,
-            );
+        let mut calculation = Calculation {
+            sheet: self,
+            options,
+            calc_map: &calc_map,
+            definitions: &definitions,
+            values,
+            stack: vec![],
+        };
+        for property in self.user_values.keys() {
+            if let Some(Ok(value)) = calculation.values.remove(property) {
+                let limited = calculation
+                    .round(property, value)
+                    .and_then(|value| calculation.limit(property, value));
+                calculation.values.insert(property.clone(), limited);
+            }
+        }
+        for property in calc_map.keys() {
+            calculation.add_or_calc(property);
         }
 
-        return Ok(values);
+        return Ok(calculation.values);
     }
 
     fn add_user_values(
@@ -166,155 +271,235 @@ impl CharacterSheet {
             values.insert(name.clone(), Ok(value.clone()));
         }
     }
+}
 
-    fn add_or_calc<'a>(
-        &self,
-        values: &mut HashMap<String, ResultValue>,
-        currently_calculating: &mut HashSet<String>,
-        calc_map: &'a HashMap<String, CalcInfo<'a>>,
-        calc_info: &'a CalcInfo<'a>,
-    ) -> AddOrCalcResult {
-        let curr_property = &calc_info.modifier.property;
-
-        if currently_calculating.contains(curr_property) {
-            // the cycle node vec will be completed when resolving the recursive stack frame
-            let value: ResultValue = Err(ValueCalculationError::Cycle(Vec::from([CycleNode {
-                feature_set: calc_info.feature_set.to_string(),
-                feature: calc_info.feature.to_string(),
-                property: calc_info.modifier.property.clone(),
-            }])));
-            values.insert(curr_property.clone(), value);
-            return AddOrCalcResult::Cycle;
+/// State of a single call to [`CharacterSheet::calculate_all_values`].
+struct Calculation<'a> {
+    sheet: &'a CharacterSheet,
+    options: &'a CalculationOptions,
+    calc_map: &'a HashMap<&'a str, Vec<CalcInfo<'a>>>,
+    definitions: &'a HashMap<&'a str, &'a PropertyDefinition>,
+    values: HashMap<String, ResultValue>,
+    /// The modifiers that are currently being calculated, because one of their dependencies is
+    /// calculated first.
+    stack: Vec<CycleNode>,
+}
+
+impl Calculation<'_> {
+    /// Calculates the given property (and all of its dependencies) unless it already has a value.
+    fn add_or_calc(&mut self, property: &str) {
+        if self.values.contains_key(property) {
+            return;
         }
+        let calc_infos = match self.calc_map.get(property) {
+            Some(calc_infos) => calc_infos,
+            None => return,
+        };
 
-        match &calc_info.modifier.value {
-            CalculatedValue::StaticValue(ref value) => {
-                values.insert(curr_property.clone(), Ok(value.clone()));
-                return AddOrCalcResult::Success;
-            }
-            CalculatedValue::Script(script) => {
-                currently_calculating.insert(curr_property.clone());
-                for dep in &script.dependencies {
-                    if values.contains_key(dep) {
-                        continue;
+        let mut set_values: Vec<StaticValueType> = vec![];
+        let mut bonuses: Vec<&Script> = vec![];
+        for calc_info in calc_infos {
+            match &calc_info.modifier.value {
+                CalculatedValue::StaticValue(value) => set_values.push(value.clone()),
+                CalculatedValue::Script(script) => {
+                    if let Err(err) = self.calc_dependencies(calc_info, script) {
+                        self.values.insert(property.to_string(), Err(err));
+                        return;
                     }
-
-                    if !calc_map.contains_key(dep) {
-                        let missing_dep = MissingDependency {
-                            missing_dependency: dep.clone(),
-                            found_in_feature_set: calc_info.feature_set.to_string(),
-                            found_in_feature: calc_info.feature.to_string(),
-                            found_in_property: calc_info.modifier.property.to_string(),
-                        };
-
-                        values.insert(
-                            curr_property.clone(),
-                            Err(ValueCalculationError::MissingDependency(
-                                missing_dep.clone(),
-                            )),
-                        );
-                        return AddOrCalcResult::MissingDependency(missing_dep);
+                    if self.values.contains_key(property) {
+                        // the property is part of a cycle and already got its error
+                        return;
                     }
 
-                    match self.add_or_calc(
-                        values,
-                        currently_calculating,
-                        calc_map,
-                        calc_map.get(dep).expect(&format!(
-                            "No calc info for dep {:?}. calc map: {:?}",
-                            dep, calc_map
-                        )),
-                    ) {
-                        AddOrCalcResult::Success => {}
-                        AddOrCalcResult::MissingDependency(missing_dep) => {
-                            values.insert(
-                                curr_property.clone(),
-                                Err(ValueCalculationError::MissingDependency(
-                                    missing_dep.clone(),
-                                )),
-                            );
-                            return AddOrCalcResult::MissingDependency(missing_dep);
-                        }
-                        AddOrCalcResult::Cycle => {
-                            // expand cycle vector and also set the value to it
-                            match values.get_mut(dep) {
-                                Some(Err(ValueCalculationError::Cycle(cycle))) => {
-                                    cycle.insert(
-                                        0,
-                                        CycleNode {
-                                            feature_set: calc_info.feature_set.to_string(),
-                                            feature: calc_info.feature.to_string(),
-                                            property: calc_info.modifier.property.clone(),
-                                        },
-                                    );
-                                }
-                                None => {
-                                    panic!("Value not calculated after call to add_or_calc with result Cycle.
-                                           Property resolution that claims cycle: {:?}
-                                           Currently calculating: {:?}
-                                           Calculation map: {:?}
-                                           Values until now: {:?}",
-                                           dep, currently_calculating, calc_map, values);
-                                }
-                                Some(Ok(_)) => {
-                                    panic!(
-                                        "Claimed cycle does not exist.
-                                           Property resolution that claims cycle: {:?}
-                                           Currently calculating: {:?}
-                                           Calculation map: {:?}
-                                           Values until now: {:?}",
-                                        dep, currently_calculating, calc_map, values
-                                    );
-                                }
-                                Some(Err(err)) => {
-                                    let err2 = err.clone();
-                                    panic!(
-                                        "Claimed cycle, but different error encountered.
-                                                   Actual error: {:?}
-                                                   Property resolution that claims cycle: {:?}
-                                                   Currently calculating: {:?}
-                                                   Calculation map: {:?}
-                                                   Values until now: {:?}",
-                                        err2, dep, currently_calculating, calc_map, values
-                                    );
-                                }
-                            }
-                            return AddOrCalcResult::Cycle;
+                    if script::references_current_value(&script.script) {
+                        bonuses.push(script);
+                        continue;
+                    }
+                    match self.sheet.evaluate_script(script, &self.values, None) {
+                        Ok(value) => set_values.push(value),
+                        Err(err) => {
+                            self.values.insert(property.to_string(), Err(err));
+                            return;
                         }
                     }
                 }
-                currently_calculating.remove(curr_property);
+            }
+        }
+
+        let mut value = match (set_values.is_empty(), self.definitions.get(property)) {
+            (true, _) => Ok(StaticValueType::Number(0)),
+            (false, None) => self.options.selectors.select("", &[], set_values),
+            (false, Some(definition)) => self.options.selectors.select(
+                &definition.selector.identifier,
+                &definition.selector.arguments,
+                set_values,
+            ),
+        }
+        .map_err(ValueCalculationError::SelectorError);
+        for bonus in bonuses {
+            value = value.and_then(|current| {
+                self.sheet
+                    .evaluate_script(bonus, &self.values, Some(&current))
+            });
+        }
+
+        let value = value
+            .and_then(|value| self.round(property, value))
+            .and_then(|value| self.limit(property, value));
+        self.values.insert(property.to_string(), value);
+    }
 
-                values.insert(curr_property.clone(), self.evaluate_script(script, values));
-                return AddOrCalcResult::Success;
+    /// Rounds fractional values with the rounding mode of the property's definition.
+    /// Without a rounding mode the value stays as it is.
+    fn round(&self, property: &str, value: StaticValueType) -> ResultValue {
+        let mode = match self.definitions.get(property).and_then(|d| d.rounding) {
+            Some(mode) => mode,
+            None => return Ok(value),
+        };
+        return match value {
+            StaticValueType::Fraction(f) => Ok(f.round_with(mode).into()),
+            value => Ok(value),
+        };
+    }
+
+    /// Applies the limiters of the property's definition to the value.
+    fn limit(&self, property: &str, mut value: StaticValueType) -> ResultValue {
+        let definition = match self.definitions.get(property) {
+            Some(definition) => definition,
+            None => return Ok(value),
+        };
+        for limiter in &definition.limiters {
+            let limited = self
+                .options
+                .limiters
+                .limit(&limiter.identifier, &limiter.arguments, &value)
+                .map_err(ValueCalculationError::LimiterError)?;
+            match (limited, self.options.limit_mode) {
+                (Limited::Within, _) => {}
+                (Limited::Violated(allowed), LimitMode::Clamp) => value = allowed,
+                (Limited::Violated(allowed), LimitMode::Report) => {
+                    return Err(ValueCalculationError::LimitViolated(LimitViolation {
+                        limiter: limiter.identifier.clone(),
+                        arguments: limiter.arguments.clone(),
+                        value,
+                        allowed,
+                    }));
+                }
             }
         }
+        return Ok(value);
     }
 
+    /// Makes sure that all dependencies of the script have been calculated.
+    /// Errors of the dependencies that prevent the script from being calculated are returned.
+    fn calc_dependencies(
+        &mut self,
+        calc_info: &CalcInfo<'_>,
+        script: &Script,
+    ) -> Result<(), ValueCalculationError> {
+        let node = CycleNode {
+            feature_set: calc_info.feature_set.to_string(),
+            feature: calc_info.feature.to_string(),
+            property: calc_info.modifier.property.clone(),
+        };
+
+        for dep in &script.dependencies {
+            if let Some(start) = self.stack.iter().position(|n| &n.property == dep) {
+                let mut cycle = self.stack[start..].to_vec();
+                cycle.push(node);
+                for member in &cycle {
+                    self.values.insert(
+                        member.property.clone(),
+                        Err(ValueCalculationError::Cycle(cycle.clone())),
+                    );
+                }
+                return Ok(());
+            }
+            if node.property == *dep {
+                return Err(ValueCalculationError::Cycle(vec![node]));
+            }
+
+            if !self.values.contains_key(dep) {
+                if !self.calc_map.contains_key(dep.as_str()) {
+                    return Err(ValueCalculationError::MissingDependency(
+                        MissingDependency {
+                            missing_dependency: dep.clone(),
+                            found_in_feature_set: node.feature_set,
+                            found_in_feature: node.feature,
+                            found_in_property: node.property,
+                        },
+                    ));
+                }
+
+                self.stack.push(node.clone());
+                self.add_or_calc(dep);
+                self.stack.pop();
+                if self.values.contains_key(&node.property) {
+                    return Ok(());
+                }
+            }
+
+            match self.values.get(dep) {
+                Some(Err(err @ ValueCalculationError::MissingDependency(_)))
+                | Some(Err(err @ ValueCalculationError::Cycle(_))) => return Err(err.clone()),
+                _ => {}
+            }
+        }
+        return Ok(());
+    }
+}
+
+impl CharacterSheet {
     fn evaluate_script(
         &self,
         script: &Script,
-        _values: &HashMap<String, ResultValue>,
+        values: &HashMap<String, ResultValue>,
+        current: Option<&StaticValueType>,
     ) -> ResultValue {
-        // todo: proper parsing
-        // for now we only parse integers
-        return match script.script.parse::<i32>() {
-            Ok(val) => Ok(StaticValueType::Number(val)),
-            Err(err) => Err(ValueCalculationError::ScriptError(err.to_string())),
+        let resolve = |name: &str| -> Option<Result<Value, String>> {
+            let value = if name == script::CURRENT_VALUE {
+                Some(Ok(current?))
+            } else if script.dependencies.iter().any(|dep| dep == name) {
+                values.get(name).map(|value| value.as_ref())
+            } else {
+                return None;
+            };
+            return Some(match value {
+                Some(Ok(StaticValueType::Number(n))) => Ok(Value::Number(Fraction::from(*n))),
+                Some(Ok(StaticValueType::Fraction(f))) => Ok(Value::Number(*f)),
+                Some(Ok(StaticValueType::Dice(d))) => Ok(Value::Dice(d.clone())),
+                Some(Err(_)) => Err(format!("`{}` could not be calculated", name)),
+                None => Err(format!("`{}` has no value", name)),
+            });
+        };
+
+        let (result, position) = script::evaluate_with_position(&script.script, resolve)
+            .map_err(ValueCalculationError::ScriptError)?;
+        return match result {
+            Value::Number(n) => number(n, position),
+            Value::Dice(d) => Ok(StaticValueType::Dice(d)),
+            Value::Bool(_) => Err(script_result_error(
+                position,
+                "The result must be a number, but is a boolean".to_string(),
+            )),
         };
     }
 }
 
-#[cfg_attr(
-    feature = "serde",
-    derive(Deserialize, Serialize),
-    serde(rename_all = "camelCase", deny_unknown_fields)
-)]
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum AddOrCalcResult {
-    Success,
-    Cycle,
-    MissingDependency(MissingDependency),
+/// Converts the number to a static value, fractions are kept as they are.
+fn number(n: Fraction, position: usize) -> ResultValue {
+    if n.is_integer() && i32::try_from(n.numerator()).is_err() {
+        return Err(script_result_error(
+            position,
+            format!("The result {} is too large", n),
+        ));
+    }
+    return Ok(n.into());
+}
+
+/// An error about the result of a script, at the position of its outermost expression.
+fn script_result_error(position: usize, message: String) -> ValueCalculationError {
+    return ValueCalculationError::ScriptError(ScriptError { position, message });
 }
 
 #[cfg_attr(
@@ -337,47 +522,39 @@ struct CalcInfo<'a> {
     pub modifier: &'a FeatureModifier,
 }
 
-fn dump<T: std::fmt::Debug>(str: &str, val: T) -> T {
-    println!("{}{:?}", str, val);
-    return val;
-}
-
 #[cfg(test)]
 mod tests {
     use std::collections::{HashMap, HashSet};
 
     use types::character_sheet_collection::{
-        CSCollection, CalculatedValue, Feature, FeatureModifier, FeatureSet, Script,
-        StaticValueType,
+        CalculatedValue, Feature, FeatureModifier, FeatureSet, Limiter, PropertyDefinition,
+        RoundingMode, Script, Selector, StaticValueType,
     };
 
     use crate::ResultValue;
 
     #[test]
     fn complex_test() {
-        let mut collection = CSCollection::new();
         let mut sheet = super::CharacterSheet::new();
 
-        add_active_featureset(
-            &mut collection,
-            &mut sheet,
-            FeatureSet {
-                name: "base".to_string(),
-                description: "The base rules".to_string(),
-                features: vec![Feature {
-                    name: "Attributes".to_string(),
-                    description: "Your character has basic attributes.".to_string(),
-                    base_type: "base_rules".to_string(),
-                    modifiers: vec![FeatureModifier {
-                        property: "MeleeAttack".to_string(),
-                        value: CalculatedValue::Script(Script {
-                            script: "11".to_string(), // todo: make it 1 + strength
-                            dependencies: vec!["Strength".to_string()],
-                        }),
-                    }],
+        sheet.active_features.push(FeatureSet {
+            name: "base".to_string(),
+            description: "The base rules".to_string(),
+            source: "Basic rules".to_string(),
+            features: vec![Feature {
+                name: "Attributes".to_string(),
+                description: "Your character has basic attributes.".to_string(),
+                base_type: "base_rules".to_string(),
+                definitions: vec![],
+                modifiers: vec![FeatureModifier {
+                    property: "MeleeAttack".to_string(),
+                    value: CalculatedValue::Script(Script {
+                        script: "1 + Strength".to_string(),
+                        dependencies: vec!["Strength".to_string()],
+                    }),
                 }],
-            },
-        );
+            }],
+        });
 
         let mut expected_values: HashMap<String, ResultValue> = HashMap::new();
         expected_values.insert(
@@ -396,6 +573,13 @@ mod tests {
             Ok(expected_values),
             "Missing dependency"
         );
+        assert_eq!(
+            sheet.calculate_all_values().unwrap()["MeleeAttack"]
+                .as_ref()
+                .unwrap_err()
+                .to_string(),
+            "`Strength` has no value, but `MeleeAttack` of `Attributes` depends on it"
+        );
 
         let mut expected_required_user_values = HashSet::new();
         expected_required_user_values.insert("Strength".to_string());
@@ -425,12 +609,310 @@ mod tests {
         );
     }
 
-    fn add_active_featureset(
-        collection: &mut CSCollection,
-        sheet: &mut crate::CharacterSheet,
-        fs: FeatureSet,
-    ) {
-        collection.items.push(fs.clone());
-        sheet.active_features.push(fs);
+    #[test]
+    fn dice_values() {
+        let mut sheet = super::CharacterSheet::new();
+        sheet.active_features.push(feature_set(
+            "fighter",
+            vec![],
+            vec![
+                modifier(
+                    "Weapon",
+                    CalculatedValue::StaticValue(StaticValueType::Dice("1d8".parse().unwrap())),
+                ),
+                modifier(
+                    "Damage",
+                    script("Weapon + Strength", &["Weapon", "Strength"]),
+                ),
+                modifier("CriticalDamage", script("Damage * 2", &["Damage"])),
+                modifier("Weapon", script("@ + 1d6", &[])),
+            ],
+        ));
+        sheet
+            .user_values
+            .insert("Strength".to_string(), StaticValueType::Number(3));
+
+        let values = sheet.calculate_all_values().unwrap();
+        let dice = |notation: &str| Ok(StaticValueType::Dice(notation.parse().unwrap()));
+        assert_eq!(values["Weapon"], dice("1d8+1d6"));
+        assert_eq!(values["Damage"], dice("1d8+1d6+3"));
+        assert_eq!(values["CriticalDamage"], dice("2d8+2d6+3"));
+    }
+
+    #[test]
+    fn stacking_modifiers() {
+        let mut sheet = super::CharacterSheet::new();
+        sheet.active_features.push(feature_set(
+            "race",
+            vec![
+                definition("ArmorClass", "highest"),
+                definition("Speed", "sum"),
+            ],
+            vec![
+                modifier("ArmorClass", number(10)),
+                modifier("ArmorClass", script("@ + 1", &[])),
+                modifier("Speed", number(25)),
+                modifier("Initiative", number(1)),
+            ],
+        ));
+        sheet.active_features.push(feature_set(
+            "items",
+            vec![],
+            vec![
+                modifier("ArmorClass", script("11 + Dexterity", &["Dexterity"])),
+                modifier("ArmorClass", script("$@ + 2", &[])),
+                modifier("Speed", number(5)),
+                modifier("Initiative", number(3)),
+                modifier("Perception", script("@ + Dexterity", &["Dexterity"])),
+            ],
+        ));
+        sheet
+            .user_values
+            .insert("Dexterity".to_string(), StaticValueType::Number(2));
+
+        let values = sheet.calculate_all_values().unwrap();
+        assert_eq!(
+            values.get("ArmorClass"),
+            Some(&Ok(StaticValueType::Number(16))),
+            "The highest base value (13) gets both bonuses."
+        );
+        assert_eq!(values.get("Speed"), Some(&Ok(StaticValueType::Number(30))));
+        assert_eq!(
+            values.get("Initiative"),
+            Some(&Ok(StaticValueType::Number(3))),
+            "Without a definition the last value wins."
+        );
+        assert_eq!(
+            values.get("Perception"),
+            Some(&Ok(StaticValueType::Number(2))),
+            "Bonuses without a base value start at 0."
+        );
+
+        sheet.active_features[1].features[0]
+            .definitions
+            .push(definition("Initiative", "median"));
+        assert_eq!(
+            sheet.calculate_all_values().unwrap().get("Initiative"),
+            Some(&Err(crate::ValueCalculationError::SelectorError(
+                crate::selector::SelectorError::Unknown("median".to_string())
+            ))),
+            "Unknown selectors are reported."
+        );
+
+        sheet
+            .user_values
+            .insert("ArmorClass".to_string(), StaticValueType::Number(20));
+        assert_eq!(
+            sheet.calculate_all_values().unwrap().get("ArmorClass"),
+            Some(&Ok(StaticValueType::Number(20))),
+            "User values overwrite calculated ones."
+        );
+    }
+
+    #[test]
+    fn limiters() {
+        let mut sheet = super::CharacterSheet::new();
+        let mut strength = definition("Strength", "highest");
+        strength.limiters = vec![limiter("min", &["0"]), limiter("max", &["20"])];
+        let mut speed = definition("Speed", "sum");
+        speed.limiters = vec![limiter("step", &["5"])];
+        sheet.active_features.push(feature_set(
+            "base",
+            vec![strength, speed],
+            vec![
+                modifier("Speed", number(25)),
+                modifier("Speed", script("Strength", &["Strength"])),
+                modifier("Carry", script("Strength * 10", &["Strength"])),
+            ],
+        ));
+        sheet
+            .user_values
+            .insert("Strength".to_string(), StaticValueType::Number(-3));
+
+        let values = sheet.calculate_all_values().unwrap();
+        assert_eq!(
+            values.get("Strength"),
+            Some(&Ok(StaticValueType::Number(0))),
+            "User values are limited, too."
+        );
+        assert_eq!(values.get("Carry"), Some(&Ok(StaticValueType::Number(0))));
+
+        sheet
+            .user_values
+            .insert("Strength".to_string(), StaticValueType::Number(13));
+        let values = sheet.calculate_all_values().unwrap();
+        assert_eq!(values.get("Speed"), Some(&Ok(StaticValueType::Number(35))));
+
+        let options = crate::CalculationOptions {
+            limit_mode: crate::limiter::LimitMode::Report,
+            ..Default::default()
+        };
+        let values = sheet.calculate_all_values_with(&options).unwrap();
+        assert_eq!(
+            values.get("Speed"),
+            Some(&Err(crate::ValueCalculationError::LimitViolated(
+                crate::limiter::LimitViolation {
+                    limiter: "step".to_string(),
+                    arguments: vec!["5".to_string()],
+                    value: StaticValueType::Number(38),
+                    allowed: StaticValueType::Number(35),
+                }
+            )))
+        );
+        assert_eq!(
+            values.get("Strength"),
+            Some(&Ok(StaticValueType::Number(13)))
+        );
+    }
+
+    #[test]
+    fn fractions() {
+        let fraction = |value: &str| StaticValueType::Fraction(value.parse().unwrap());
+        let mut sheet = super::CharacterSheet::new();
+        let mut damage = definition("Damage", "");
+        damage.rounding = Some(RoundingMode::Down);
+        let mut hit_points = definition("HitPoints", "");
+        hit_points.rounding = Some(RoundingMode::Nearest);
+        sheet.active_features.push(feature_set(
+            "base",
+            vec![damage, hit_points],
+            vec![
+                modifier("Damage", script("Strength * 1.5", &["Strength"])),
+                modifier("HitPoints", script("Constitution / 2", &["Constitution"])),
+                modifier("Speed", number(30)),
+                modifier("Speed", script("@ * 0.75", &[])),
+                modifier("Weight", CalculatedValue::StaticValue(fraction("2.5"))),
+            ],
+        ));
+        sheet
+            .user_values
+            .insert("Strength".to_string(), StaticValueType::Number(3));
+        sheet
+            .user_values
+            .insert("Constitution".to_string(), fraction("5/3"));
+
+        let values = sheet.calculate_all_values().unwrap();
+        assert_eq!(values["Damage"], Ok(StaticValueType::Number(4)));
+        assert_eq!(values["HitPoints"], Ok(StaticValueType::Number(1)));
+        assert_eq!(
+            values["Speed"],
+            Ok(fraction("22.5")),
+            "Without a rounding mode the fraction is kept."
+        );
+        assert_eq!(values["Weight"], Ok(fraction("2.5")));
+        assert_eq!(values["Constitution"], Ok(fraction("5/3")));
+    }
+
+    #[test]
+    fn script_results() {
+        let mut sheet = super::CharacterSheet::new();
+        sheet.active_features.push(feature_set(
+            "base",
+            vec![],
+            vec![
+                modifier("Proficient", script("1 + 2 < 4", &[])),
+                modifier("Huge", script("  2147483647 * 2", &[])),
+            ],
+        ));
+
+        let values = sheet.calculate_all_values().unwrap();
+        let position = |property: &str| match &values[property] {
+            Err(crate::ValueCalculationError::ScriptError(e)) => e.position,
+            other => panic!("Expected a script error for {}, got {:?}", property, other),
+        };
+        assert_eq!(position("Proficient"), 6, "The error points at the `<`.");
+        assert_eq!(position("Huge"), 13, "The error points at the `*`.");
+    }
+
+    #[test]
+    fn cycles() {
+        let mut sheet = super::CharacterSheet::new();
+        sheet.active_features.push(feature_set(
+            "base",
+            vec![],
+            vec![
+                modifier("A", script("B + 1", &["B"])),
+                modifier("B", script("A + 1", &["A"])),
+                modifier("C", script("A", &["A"])),
+                modifier("D", script("D", &["D"])),
+            ],
+        ));
+
+        let values = sheet.calculate_all_values().unwrap();
+        for property in ["A", "B", "C"] {
+            match values.get(property) {
+                Some(Err(crate::ValueCalculationError::Cycle(cycle))) => {
+                    let mut properties: Vec<&str> =
+                        cycle.iter().map(|n| n.property.as_str()).collect();
+                    properties.sort();
+                    assert_eq!(properties, vec!["A", "B"], "Cycle of {}", property);
+                }
+                other => panic!("Expected a cycle for {}, got {:?}", property, other),
+            }
+        }
+        assert_eq!(
+            values.get("D"),
+            Some(&Err(crate::ValueCalculationError::Cycle(vec![
+                crate::CycleNode {
+                    feature_set: "base".to_string(),
+                    feature: "base".to_string(),
+                    property: "D".to_string(),
+                }
+            ])))
+        );
+    }
+
+    fn feature_set(
+        name: &str,
+        definitions: Vec<PropertyDefinition>,
+        modifiers: Vec<FeatureModifier>,
+    ) -> FeatureSet {
+        FeatureSet {
+            name: name.to_string(),
+            features: vec![Feature {
+                name: name.to_string(),
+                definitions,
+                modifiers,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn definition(name: &str, selector: &str) -> PropertyDefinition {
+        PropertyDefinition {
+            name: name.to_string(),
+            selector: Selector {
+                identifier: selector.to_string(),
+                arguments: vec![],
+            },
+            limiters: vec![],
+            rounding: None,
+        }
+    }
+
+    fn limiter(identifier: &str, arguments: &[&str]) -> Limiter {
+        Limiter {
+            identifier: identifier.to_string(),
+            arguments: arguments.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    fn modifier(property: &str, value: CalculatedValue) -> FeatureModifier {
+        FeatureModifier {
+            property: property.to_string(),
+            value,
+        }
+    }
+
+    fn number(n: i32) -> CalculatedValue {
+        CalculatedValue::StaticValue(StaticValueType::Number(n))
+    }
+
+    fn script(script: &str, dependencies: &[&str]) -> CalculatedValue {
+        CalculatedValue::Script(Script {
+            script: script.to_string(),
+            dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+        })
     }
 }
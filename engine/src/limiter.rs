@@ -0,0 +1,411 @@
+//! Limiters restrict the values a property may have.
+//!
+//! The limiters of a property are specified by its
+//! [`PropertyDefinition`](types::character_sheet_collection::PropertyDefinition) and applied in
+//! order after its value was selected. Whether a value outside of the limits is clamped or
+//! reported as an error is configured by the [`LimitMode`].
+//!
+//! # Built-in limiters
+//!
+//! * `min n` - at least `n`
+//! * `max n` - at most `n`
+//! * `clamp min max` - between `min` and `max` (inclusive)
+//! * `oneOf a b ...` - one of the listed values, clamps to the closest one (the lower one on ties)
+//! * `step n [offset]` - `offset` plus a multiple of `n` (offset default: 0), clamps down
+//! * `nonZero` - anything but 0, clamps to 1
+//!
+//! The built-in limiters only restrict numbers and fractions, dice values are always within their
+//! limits. Their arguments may be fractions, too, e.g. `step 0.5`.
+//!
+//! Additional limiters can be added by implementing [`ValueLimiter`] and registering them in a
+//! [`LimiterRegistry`].
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use types::character_sheet_collection::StaticValueType;
+use types::fraction::Fraction;
+
+use crate::selector::number_value;
+
+/// Result of checking a value against a limiter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Limited {
+    /// The value is within the limits.
+    Within,
+    /// The value is outside of the limits. Contains the closest allowed value.
+    Violated(StaticValueType),
+}
+
+/// Restricts the values a property may have.
+pub trait ValueLimiter {
+    /// Checks whether the value is within the limits.
+    ///
+    /// Returns a message describing the problem if the value or arguments are not supported.
+    fn limit(&self, value: &StaticValueType, arguments: &[String]) -> Result<Limited, String>;
+}
+
+/// What happens with values that are outside of the limits of their property.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LimitMode {
+    /// The value is replaced with the closest allowed value.
+    #[default]
+    Clamp,
+    /// The value is replaced with a [`LimitViolated`](crate::ValueCalculationError::LimitViolated)
+    /// error.
+    Report,
+}
+
+/// A value that is outside of the limits of its property.
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitViolation {
+    /// The identifier of the violated limiter.
+    pub limiter: String,
+    pub arguments: Vec<String>,
+    /// The value before it was limited.
+    pub value: StaticValueType,
+    /// The closest allowed value.
+    pub allowed: StaticValueType,
+}
+
+/// Errors during the application of a limiter.
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimiterError {
+    /// No limiter with the given identifier is registered.
+    Unknown(String),
+    /// The limiter could not check the value.
+    Failed { limiter: String, message: String },
+}
+
+impl fmt::Display for LimiterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimiterError::Unknown(identifier) => write!(f, "Unknown limiter `{}`", identifier),
+            LimiterError::Failed { limiter, message } => {
+                write!(f, "Limiter `{}` failed: {}", limiter, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimiterError {}
+
+/// All limiters that are available during a calculation, by their identifier.
+pub struct LimiterRegistry {
+    limiters: HashMap<String, Box<dyn ValueLimiter>>,
+}
+
+impl LimiterRegistry {
+    /// Creates a registry without any limiters.
+    pub fn empty() -> LimiterRegistry {
+        return LimiterRegistry {
+            limiters: HashMap::new(),
+        };
+    }
+
+    /// Creates a registry with all built-in limiters.
+    pub fn new() -> LimiterRegistry {
+        let mut registry = LimiterRegistry::empty();
+        registry.register("min", Min);
+        registry.register("max", Max);
+        registry.register("clamp", Clamp);
+        registry.register("oneOf", OneOf);
+        registry.register("step", Step);
+        registry.register("nonZero", NonZero);
+        return registry;
+    }
+
+    /// Registers a limiter under the given identifier.
+    /// Replaces any limiter that was previously registered with the same identifier.
+    pub fn register(&mut self, identifier: &str, limiter: impl ValueLimiter + 'static) {
+        self.limiters
+            .insert(identifier.to_string(), Box::new(limiter));
+    }
+
+    pub fn get(&self, identifier: &str) -> Option<&dyn ValueLimiter> {
+        return self.limiters.get(identifier).map(|l| l.as_ref());
+    }
+
+    /// Checks the value with the limiter of the given identifier.
+    pub fn limit(
+        &self,
+        identifier: &str,
+        arguments: &[String],
+        value: &StaticValueType,
+    ) -> Result<Limited, LimiterError> {
+        let limiter = self
+            .get(identifier)
+            .ok_or_else(|| LimiterError::Unknown(identifier.to_string()))?;
+        return limiter
+            .limit(value, arguments)
+            .map_err(|message| LimiterError::Failed {
+                limiter: identifier.to_string(),
+                message,
+            });
+    }
+}
+
+impl Default for LimiterRegistry {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl fmt::Debug for LimiterRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut identifiers: Vec<&String> = self.limiters.keys().collect();
+        identifiers.sort();
+        f.debug_struct("LimiterRegistry")
+            .field("limiters", &identifiers)
+            .finish()
+    }
+}
+
+/// Parses all arguments as numbers and makes sure that there are as many as expected.
+fn number_arguments(arguments: &[String], min: usize, max: usize) -> Result<Vec<Fraction>, String> {
+    if arguments.len() < min || arguments.len() > max {
+        let expected = if min == max {
+            min.to_string()
+        } else if max == usize::MAX {
+            format!("at least {}", min)
+        } else {
+            format!("{} to {}", min, max)
+        };
+        return Err(format!(
+            "expected {} arguments, got {}",
+            expected,
+            arguments.len()
+        ));
+    }
+    return arguments
+        .iter()
+        .map(|a| {
+            a.parse::<Fraction>()
+                .map_err(|_| format!("`{}` is not a number", a))
+        })
+        .collect();
+}
+
+/// Checks numbers with the given function, which returns the closest allowed value or `None` if
+/// it can not be calculated.
+/// Dice values are always within the limits.
+fn limit_number(
+    value: &StaticValueType,
+    limit: impl Fn(Fraction) -> Option<Fraction>,
+) -> Result<Limited, String> {
+    let n = match value {
+        StaticValueType::Number(n) => Fraction::from(*n),
+        StaticValueType::Fraction(f) => *f,
+        StaticValueType::Dice(_) => return Ok(Limited::Within),
+    };
+    let limited = limit(n).ok_or_else(|| "the calculation overflowed".to_string())?;
+    if limited == n {
+        return Ok(Limited::Within);
+    }
+    return number_value(limited).map(Limited::Violated);
+}
+
+struct Min;
+
+impl ValueLimiter for Min {
+    fn limit(&self, value: &StaticValueType, arguments: &[String]) -> Result<Limited, String> {
+        let min = number_arguments(arguments, 1, 1)?[0];
+        return limit_number(value, |n| Some(n.max(min)));
+    }
+}
+
+struct Max;
+
+impl ValueLimiter for Max {
+    fn limit(&self, value: &StaticValueType, arguments: &[String]) -> Result<Limited, String> {
+        let max = number_arguments(arguments, 1, 1)?[0];
+        return limit_number(value, |n| Some(n.min(max)));
+    }
+}
+
+struct Clamp;
+
+impl ValueLimiter for Clamp {
+    fn limit(&self, value: &StaticValueType, arguments: &[String]) -> Result<Limited, String> {
+        let bounds = number_arguments(arguments, 2, 2)?;
+        if bounds[0] > bounds[1] {
+            return Err(format!(
+                "the minimum {} is larger than the maximum {}",
+                bounds[0], bounds[1]
+            ));
+        }
+        return limit_number(value, |n| Some(n.clamp(bounds[0], bounds[1])));
+    }
+}
+
+struct OneOf;
+
+impl ValueLimiter for OneOf {
+    fn limit(&self, value: &StaticValueType, arguments: &[String]) -> Result<Limited, String> {
+        let mut allowed = number_arguments(arguments, 1, usize::MAX)?;
+        allowed.sort_unstable();
+        return limit_number(value, |n| {
+            let mut closest = None;
+            for a in &allowed {
+                let distance = a.checked_sub(n)?.max(n.checked_sub(*a)?);
+                if closest.is_none_or(|(_, d)| distance < d) {
+                    closest = Some((*a, distance));
+                }
+            }
+            return Some(closest.map_or(n, |(a, _)| a));
+        });
+    }
+}
+
+struct Step;
+
+impl ValueLimiter for Step {
+    fn limit(&self, value: &StaticValueType, arguments: &[String]) -> Result<Limited, String> {
+        let arguments = number_arguments(arguments, 1, 2)?;
+        let step = arguments[0];
+        let offset = arguments
+            .get(1)
+            .copied()
+            .unwrap_or(Fraction::from_integer(0));
+        if step <= Fraction::from_integer(0) {
+            return Err(format!("the step {} is not positive", step));
+        }
+        return limit_number(value, |n| {
+            let steps = n.checked_sub(offset)?.checked_div(step)?.floor();
+            return offset.checked_add(steps.checked_mul(step)?);
+        });
+    }
+}
+
+struct NonZero;
+
+impl ValueLimiter for NonZero {
+    fn limit(&self, value: &StaticValueType, arguments: &[String]) -> Result<Limited, String> {
+        number_arguments(arguments, 0, 0)?;
+        return limit_number(value, |n| {
+            if n == Fraction::from_integer(0) {
+                return Some(Fraction::from_integer(1));
+            }
+            return Some(n);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::character_sheet_collection::{DiceValue, StaticValueType};
+    use types::fraction::Fraction;
+
+    use super::{Limited, LimiterError, LimiterRegistry};
+
+    fn limit(
+        identifier: &str,
+        arguments: &[&str],
+        value: i32,
+    ) -> Result<Option<i32>, LimiterError> {
+        let arguments: Vec<String> = arguments.iter().map(|a| a.to_string()).collect();
+        match LimiterRegistry::new().limit(
+            identifier,
+            &arguments,
+            &StaticValueType::Number(value),
+        )? {
+            Limited::Within => Ok(None),
+            Limited::Violated(StaticValueType::Number(n)) => Ok(Some(n)),
+            other => panic!("Expected a number, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn builtins() {
+        assert_eq!(limit("min", &["0"], 3), Ok(None));
+        assert_eq!(limit("min", &["0"], -3), Ok(Some(0)));
+        assert_eq!(limit("max", &["5"], 5), Ok(None));
+        assert_eq!(limit("max", &["5"], 6), Ok(Some(5)));
+        assert_eq!(limit("clamp", &["1", "20"], 0), Ok(Some(1)));
+        assert_eq!(limit("clamp", &["1", "20"], 21), Ok(Some(20)));
+        assert_eq!(limit("clamp", &["1", "20"], 7), Ok(None));
+        assert_eq!(limit("oneOf", &["2", "4", "8"], 4), Ok(None));
+        assert_eq!(limit("oneOf", &["2", "4", "8"], 6), Ok(Some(4)));
+        assert_eq!(limit("oneOf", &["8", "4", "2"], 7), Ok(Some(8)));
+        assert_eq!(limit("step", &["5"], 15), Ok(None));
+        assert_eq!(limit("step", &["5"], 17), Ok(Some(15)));
+        assert_eq!(limit("step", &["5"], -1), Ok(Some(-5)));
+        assert_eq!(limit("step", &["2", "1"], 5), Ok(None));
+        assert_eq!(limit("step", &["2", "1"], 4), Ok(Some(3)));
+        assert_eq!(limit("nonZero", &[], 0), Ok(Some(1)));
+        assert_eq!(limit("nonZero", &[], -2), Ok(None));
+
+        assert_eq!(
+            LimiterRegistry::new().limit(
+                "min",
+                &["0".to_string()],
+                &StaticValueType::Dice(DiceValue::default())
+            ),
+            Ok(Limited::Within),
+            "Dice values are not limited."
+        );
+    }
+
+    #[test]
+    fn fractions() {
+        let limit = |identifier: &str, arguments: &[&str], value: &str| {
+            let arguments: Vec<String> = arguments.iter().map(|a| a.to_string()).collect();
+            LimiterRegistry::new().limit(
+                identifier,
+                &arguments,
+                &StaticValueType::from(value.parse::<Fraction>().unwrap()),
+            )
+        };
+        let violated = |value: &str| {
+            Ok(Limited::Violated(StaticValueType::from(
+                value.parse::<Fraction>().unwrap(),
+            )))
+        };
+        assert_eq!(limit("max", &["2.5"], "3"), violated("2.5"));
+        assert_eq!(limit("min", &["0"], "-0.5"), violated("0"));
+        assert_eq!(limit("step", &["0.5"], "1.75"), violated("1.5"));
+        assert_eq!(limit("step", &["0.5"], "1.5"), Ok(Limited::Within));
+        assert_eq!(limit("oneOf", &["1", "1.5"], "1.2"), violated("1"));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            limit("even", &[], 1),
+            Err(LimiterError::Unknown("even".to_string()))
+        );
+        assert_eq!(
+            limit("clamp", &["1"], 1),
+            Err(LimiterError::Failed {
+                limiter: "clamp".to_string(),
+                message: "expected 2 arguments, got 1".to_string()
+            })
+        );
+        assert_eq!(
+            limit("min", &["zero"], 1),
+            Err(LimiterError::Failed {
+                limiter: "min".to_string(),
+                message: "`zero` is not a number".to_string()
+            })
+        );
+        assert_eq!(
+            limit("oneOf", &[], 1),
+            Err(LimiterError::Failed {
+                limiter: "oneOf".to_string(),
+                message: "expected at least 1 arguments, got 0".to_string()
+            })
+        );
+    }
+}
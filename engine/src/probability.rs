@@ -0,0 +1,686 @@
+//! Exact probability distributions of [`DiceValue`]s.
+//!
+//! The modifiers are interpreted exactly like when [rolling](crate::roll) the dice, with two
+//! restrictions:
+//!
+//! * Explosions are only followed up to [`AnalysisOptions::explode_limit`] times per die, which
+//!   makes the distribution of exploding dice a (usually very close) approximation.
+//! * `Reroll` and `Explode` must come before all `Keep`, `Drop` and `Count` modifiers of a group
+//!   and can not use `Highest(n)` or `Lowest(n)`.
+//!
+//! Example: the chance that `1d20 + 5` beats an armor class of 15
+//!
+//! ```
+//! # use engine::probability::Distribution;
+//! # use types::character_sheet_collection::{Dice, DiceValue};
+//! let attack = DiceValue {
+//!     dice: vec![Dice { amount: 1, sides: 20, modifiers: vec![] }],
+//!     bonus: 5,
+//! };
+//! let distribution = Distribution::of(&attack).unwrap();
+//! assert!((distribution.at_least(15) - 0.55).abs() < 1e-9);
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use types::character_sheet_collection::{Dice, DiceModifier, DiceSelector, DiceValue};
+
+use crate::roll::{is_positional, matches, selector_of, MAX_DICE};
+
+/// The maximum amount of sides of dice that are analyzed. Every side is a separate result.
+pub const MAX_SIDES: u32 = 10_000;
+
+/// Configures how distributions are calculated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnalysisOptions {
+    /// The maximum amount of times a single die may explode.
+    pub explode_limit: u32,
+    /// The maximum amount of distinct dice combinations that are evaluated for a group using
+    /// `Highest(n)` or `Lowest(n)` in `Keep`, `Drop` or `Count` modifiers.
+    ///
+    /// Also limits the estimated work of summing up the dice, which grows with the amount of dice
+    /// times the amount of possible results.
+    pub max_combinations: u64,
+}
+
+impl Default for AnalysisOptions {
+    fn default() -> Self {
+        return AnalysisOptions {
+            explode_limit: 10,
+            max_combinations: 1_000_000,
+        };
+    }
+}
+
+/// Errors that prevent the calculation of a distribution.
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnalysisError {
+    /// Dice need at least one side.
+    NoSides,
+    /// A group has more than [`MAX_DICE`] dice.
+    TooManyDice(i32),
+    /// The dice of a group have more than [`MAX_SIDES`] sides.
+    TooManySides(u32),
+    /// The amount of dice combinations exceeds [`AnalysisOptions::max_combinations`].
+    TooComplex(u64),
+    /// The combination of modifiers is not supported.
+    Unsupported(String),
+}
+
+impl fmt::Display for AnalysisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnalysisError::NoSides => write!(f, "Dice need at least one side"),
+            AnalysisError::TooManyDice(amount) => write!(
+                f,
+                "Can not analyze {} dice at once, the maximum is {}",
+                amount, MAX_DICE
+            ),
+            AnalysisError::TooManySides(sides) => write!(
+                f,
+                "Can not analyze dice with {} sides, the maximum is {}",
+                sides, MAX_SIDES
+            ),
+            AnalysisError::TooComplex(combinations) => write!(
+                f,
+                "Too many combinations to analyze ({} or more)",
+                combinations
+            ),
+            AnalysisError::Unsupported(message) => write!(f, "Unsupported modifiers: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for AnalysisError {}
+
+/// The probability of every possible result of a dice roll.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution {
+    /// Probabilities by result. Only contains results that are possible.
+    probabilities: BTreeMap<i64, f64>,
+}
+
+impl Distribution {
+    /// Calculates the distribution with the default [`AnalysisOptions`].
+    pub fn of(dice: &DiceValue) -> Result<Distribution, AnalysisError> {
+        return Distribution::of_with(dice, &AnalysisOptions::default());
+    }
+
+    /// Calculates the distribution of the results of rolling the dice.
+    pub fn of_with(
+        dice: &DiceValue,
+        options: &AnalysisOptions,
+    ) -> Result<Distribution, AnalysisError> {
+        let mut distribution = Distribution::constant(dice.bonus as i64);
+        for group in &dice.dice {
+            let group = group_distribution(group, options)?;
+            let work = distribution.probabilities.len() as u64 * group.probabilities.len() as u64;
+            if work > options.max_combinations {
+                return Err(AnalysisError::TooComplex(work));
+            }
+            distribution = distribution.add(&group);
+        }
+        return Ok(distribution);
+    }
+
+    /// A distribution that always results in the same value.
+    pub fn constant(value: i64) -> Distribution {
+        return Distribution {
+            probabilities: BTreeMap::from([(value, 1.0)]),
+        };
+    }
+
+    /// The distribution of the sum of two independent results.
+    pub fn add(&self, other: &Distribution) -> Distribution {
+        let mut probabilities = BTreeMap::new();
+        for (a, pa) in &self.probabilities {
+            for (b, pb) in &other.probabilities {
+                *probabilities.entry(a + b).or_insert(0.0) += pa * pb;
+            }
+        }
+        return Distribution { probabilities };
+    }
+
+    /// The probability of exactly this result.
+    pub fn probability(&self, value: i64) -> f64 {
+        return self.probabilities.get(&value).copied().unwrap_or(0.0);
+    }
+
+    /// The probability of a result of at least `value`.
+    pub fn at_least(&self, value: i64) -> f64 {
+        return self.probabilities.range(value..).map(|(_, p)| p).sum();
+    }
+
+    /// The probability of a result of at most `value`.
+    pub fn at_most(&self, value: i64) -> f64 {
+        return self.probabilities.range(..=value).map(|(_, p)| p).sum();
+    }
+
+    /// All possible results with their probability, ordered by the result.
+    pub fn iter(&self) -> impl Iterator<Item = (i64, f64)> + '_ {
+        return self.probabilities.iter().map(|(v, p)| (*v, *p));
+    }
+
+    pub fn min(&self) -> i64 {
+        return *self.probabilities.keys().next().unwrap_or(&0);
+    }
+
+    pub fn max(&self) -> i64 {
+        return *self.probabilities.keys().next_back().unwrap_or(&0);
+    }
+
+    pub fn mean(&self) -> f64 {
+        return self.iter().map(|(v, p)| v as f64 * p).sum();
+    }
+
+    pub fn variance(&self) -> f64 {
+        let mean = self.mean();
+        return self
+            .iter()
+            .map(|(v, p)| (v as f64 - mean).powi(2) * p)
+            .sum();
+    }
+
+    pub fn standard_deviation(&self) -> f64 {
+        return self.variance().sqrt();
+    }
+
+    /// The smallest result that is at least as high as the given share of all results.
+    /// E.g. `percentile(0.5)` is the median.
+    pub fn percentile(&self, share: f64) -> i64 {
+        let mut cumulative = 0.0;
+        for (value, probability) in self.iter() {
+            cumulative += probability;
+            // tolerate rounding errors of the summed up probabilities
+            if cumulative >= share - 1e-12 {
+                return value;
+            }
+        }
+        return self.max();
+    }
+}
+
+/// The state of a single die before it is kept, dropped or counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Die {
+    value: u32,
+    /// The last roll of the die, which decides whether it explodes.
+    last_face: u32,
+}
+
+fn group_distribution(
+    group: &Dice,
+    options: &AnalysisOptions,
+) -> Result<Distribution, AnalysisError> {
+    if group.sides == 0 {
+        return Err(AnalysisError::NoSides);
+    }
+    if group.amount.unsigned_abs() > MAX_DICE {
+        return Err(AnalysisError::TooManyDice(group.amount));
+    }
+    if group.sides > MAX_SIDES {
+        return Err(AnalysisError::TooManySides(group.sides));
+    }
+
+    // rerolls and explosions only depend on the die itself
+    let single_roll: BTreeMap<Die, f64> = (1..=group.sides)
+        .map(|v| {
+            (
+                Die {
+                    value: v,
+                    last_face: v,
+                },
+                1.0 / group.sides as f64,
+            )
+        })
+        .collect();
+    let mut die = single_roll.clone();
+    let mut modifiers = group.modifiers.iter().peekable();
+    while let Some(modifier) =
+        modifiers.next_if(|m| matches!(m, DiceModifier::Reroll(_) | DiceModifier::Explode(_)))
+    {
+        match modifier {
+            DiceModifier::Reroll(selector) => {
+                reject_positional(selector, "Reroll")?;
+                let mut rerolled = BTreeMap::new();
+                let mut reroll_probability = 0.0;
+                for (state, p) in die {
+                    if matches(selector, state.value) {
+                        reroll_probability += p;
+                    } else {
+                        *rerolled.entry(state).or_insert(0.0) += p;
+                    }
+                }
+                for (state, p) in &single_roll {
+                    *rerolled.entry(*state).or_insert(0.0) += p * reroll_probability;
+                }
+                die = rerolled;
+            }
+            DiceModifier::Explode(selector) => {
+                reject_positional(selector, "Explode")?;
+                // the first explosion is decided by the current last face, too
+                for _ in 0..options.explode_limit {
+                    let exploding = die
+                        .keys()
+                        .filter(|s| matches(selector, s.last_face))
+                        .count();
+                    let work = exploding as u64 * single_roll.len() as u64;
+                    if work > options.max_combinations {
+                        return Err(AnalysisError::TooComplex(work));
+                    }
+                    let mut exploded = BTreeMap::new();
+                    for (state, p) in die {
+                        if !matches(selector, state.last_face) {
+                            *exploded.entry(state).or_insert(0.0) += p;
+                            continue;
+                        }
+                        for (roll, q) in &single_roll {
+                            let next = Die {
+                                value: state.value.saturating_add(roll.value),
+                                last_face: roll.value,
+                            };
+                            *exploded.entry(next).or_insert(0.0) += p * q;
+                        }
+                    }
+                    die = exploded;
+                }
+            }
+            _ => unreachable!("only rerolls and explosions are taken"),
+        }
+    }
+
+    let selections: Vec<&DiceModifier> = modifiers.collect();
+    if selections
+        .iter()
+        .any(|m| matches!(m, DiceModifier::Reroll(_) | DiceModifier::Explode(_)))
+    {
+        return Err(AnalysisError::Unsupported(
+            "Reroll and Explode must come before Keep, Drop and Count".to_string(),
+        ));
+    }
+
+    let mut values: BTreeMap<u32, f64> = BTreeMap::new();
+    for (state, p) in die {
+        *values.entry(state.value).or_insert(0.0) += p;
+    }
+    let amount = group.amount.unsigned_abs();
+    let distribution = if selections.iter().any(|m| is_positional(selector_of(m))) {
+        combinations_distribution(amount, &values, &selections, options)?
+    } else {
+        independent_distribution(amount, &values, &selections, options)?
+    };
+
+    if group.amount < 0 {
+        return Ok(Distribution {
+            probabilities: distribution
+                .probabilities
+                .into_iter()
+                .map(|(v, p)| (-v, p))
+                .collect(),
+        });
+    }
+    return Ok(distribution);
+}
+
+fn reject_positional(selector: &DiceSelector, modifier: &str) -> Result<(), AnalysisError> {
+    if is_positional(selector) {
+        return Err(AnalysisError::Unsupported(format!(
+            "{} can not select the highest or lowest dice",
+            modifier
+        )));
+    }
+    return Ok(());
+}
+
+/// If no die depends on the others, each die contributes independently to the total.
+fn independent_distribution(
+    amount: u32,
+    values: &BTreeMap<u32, f64>,
+    selections: &[&DiceModifier],
+    options: &AnalysisOptions,
+) -> Result<Distribution, AnalysisError> {
+    let counted = selections
+        .iter()
+        .any(|m| matches!(m, DiceModifier::Count(_)));
+    let mut contribution: BTreeMap<i64, f64> = BTreeMap::new();
+    for (value, p) in values {
+        let kept = selections.iter().all(|m| match m {
+            DiceModifier::Drop(s) => !matches(s, *value),
+            other => matches(selector_of(other), *value),
+        });
+        let contributes = match (kept, counted) {
+            (false, _) => 0,
+            (true, true) => 1,
+            (true, false) => *value as i64,
+        };
+        *contribution.entry(contributes).or_insert(0.0) += p;
+    }
+
+    // every die is added to a distribution with up to `amount * span + 1` results, one result
+    // of the die at a time
+    let span =
+        (contribution.keys().max().unwrap_or(&0) - contribution.keys().min().unwrap_or(&0)) as u64;
+    let amount = amount as u64;
+    let work = amount
+        .saturating_mul(amount.saturating_mul(span).saturating_add(1))
+        .saturating_mul(contribution.len() as u64);
+    if work > options.max_combinations {
+        return Err(AnalysisError::TooComplex(work));
+    }
+
+    let single = Distribution {
+        probabilities: contribution,
+    };
+    let mut distribution = Distribution::constant(0);
+    for _ in 0..amount {
+        distribution = distribution.add(&single);
+    }
+    return Ok(distribution);
+}
+
+/// Evaluates every distinct combination of dice values (ignoring their order).
+fn combinations_distribution(
+    amount: u32,
+    values: &BTreeMap<u32, f64>,
+    selections: &[&DiceModifier],
+    options: &AnalysisOptions,
+) -> Result<Distribution, AnalysisError> {
+    let values: Vec<(u32, f64)> = values.iter().map(|(v, p)| (*v, *p)).collect();
+    let combinations =
+        count_combinations(amount as u64, values.len() as u64, options.max_combinations);
+    if combinations > options.max_combinations {
+        return Err(AnalysisError::TooComplex(combinations));
+    }
+
+    let ln_factorials: Vec<f64> = std::iter::once(0.0)
+        .chain((1..=amount).scan(0.0, |acc, n| {
+            *acc += (n as f64).ln();
+            Some(*acc)
+        }))
+        .collect();
+
+    let mut probabilities = BTreeMap::new();
+    let mut counts = vec![0u32; values.len()];
+    enumerate(amount, 0, &mut counts, &mut |counts| {
+        // multinomial probability of exactly these counts
+        let mut ln_probability = ln_factorials[amount as usize];
+        let mut dice = Vec::with_capacity(amount as usize);
+        for ((value, p), count) in values.iter().zip(counts) {
+            ln_probability += *count as f64 * p.ln() - ln_factorials[*count as usize];
+            dice.extend(std::iter::repeat_n(*value, *count as usize));
+        }
+        let total = evaluate_selections(dice, selections);
+        *probabilities.entry(total).or_insert(0.0) += ln_probability.exp();
+    });
+
+    return Ok(Distribution { probabilities });
+}
+
+/// Calls `f` with every way to distribute `remaining` dice onto the values starting at `index`.
+fn enumerate(remaining: u32, index: usize, counts: &mut Vec<u32>, f: &mut impl FnMut(&[u32])) {
+    if index == counts.len() - 1 {
+        counts[index] = remaining;
+        f(counts);
+        return;
+    }
+    for count in 0..=remaining {
+        counts[index] = count;
+        enumerate(remaining - count, index + 1, counts, f);
+    }
+}
+
+/// The amount of multisets of size `amount` with `kinds` kinds, capped slightly above `cap`.
+fn count_combinations(amount: u64, kinds: u64, cap: u64) -> u64 {
+    // binomial(amount + kinds - 1, amount), calculated incrementally to stay exact
+    let mut result: u64 = 1;
+    for i in 1..kinds {
+        result = match result.checked_mul(amount + i) {
+            Some(r) => r / i,
+            None => return cap.saturating_add(1),
+        };
+        if result > cap {
+            return result;
+        }
+    }
+    return result;
+}
+
+/// Applies keep, drop and count to the dice values of a single roll, like rolling does.
+fn evaluate_selections(mut dice: Vec<u32>, selections: &[&DiceModifier]) -> i64 {
+    dice.sort_unstable();
+    let mut kept = vec![true; dice.len()];
+    let mut counted = false;
+    for modifier in selections {
+        let selector = selector_of(modifier);
+        let candidates: Vec<usize> = (0..dice.len()).filter(|i| kept[*i]).collect();
+        let selected: Vec<usize> = match selector {
+            DiceSelector::Highest(n) => {
+                candidates.iter().rev().take(*n as usize).copied().collect()
+            }
+            DiceSelector::Lowest(n) => candidates.iter().take(*n as usize).copied().collect(),
+            _ => candidates
+                .iter()
+                .filter(|i| matches(selector, dice[**i]))
+                .copied()
+                .collect(),
+        };
+        match modifier {
+            DiceModifier::Drop(_) => {
+                for index in selected {
+                    kept[index] = false;
+                }
+            }
+            _ => {
+                for index in candidates {
+                    if !selected.contains(&index) {
+                        kept[index] = false;
+                    }
+                }
+                counted |= matches!(modifier, DiceModifier::Count(_));
+            }
+        }
+    }
+
+    let kept = dice.iter().zip(kept).filter(|(_, k)| *k);
+    if counted {
+        return kept.count() as i64;
+    }
+    return kept.map(|(v, _)| *v as i64).sum();
+}
+
+#[cfg(test)]
+mod tests {
+    use types::character_sheet_collection::{Dice, DiceModifier, DiceSelector, DiceValue};
+
+    use super::{AnalysisError, AnalysisOptions, Distribution};
+
+    fn dice(amount: i32, sides: u32, modifiers: Vec<DiceModifier>) -> DiceValue {
+        DiceValue {
+            dice: vec![Dice {
+                amount,
+                sides,
+                modifiers,
+            }],
+            bonus: 0,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn plain_dice() {
+        let distribution = Distribution::of(&dice(2, 6, vec![])).unwrap();
+        assert_close(distribution.probability(7), 6.0 / 36.0);
+        assert_close(distribution.probability(2), 1.0 / 36.0);
+        assert_close(distribution.mean(), 7.0);
+        assert_close(distribution.variance(), 35.0 / 6.0);
+        assert_eq!(distribution.min(), 2);
+        assert_eq!(distribution.max(), 12);
+        assert_eq!(distribution.percentile(0.5), 7);
+        assert_eq!(distribution.percentile(0.0), 2);
+        assert_eq!(distribution.percentile(1.0), 12);
+        assert_close(distribution.at_least(11), 3.0 / 36.0);
+        assert_close(distribution.at_most(3), 3.0 / 36.0);
+        assert_close(distribution.iter().map(|(_, p)| p).sum(), 1.0);
+
+        let mixed = DiceValue {
+            dice: vec![
+                Dice {
+                    amount: 1,
+                    sides: 8,
+                    modifiers: vec![],
+                },
+                Dice {
+                    amount: -1,
+                    sides: 4,
+                    modifiers: vec![],
+                },
+            ],
+            bonus: 3,
+        };
+        let distribution = Distribution::of(&mixed).unwrap();
+        assert_eq!(distribution.min(), 0);
+        assert_eq!(distribution.max(), 10);
+        assert_close(distribution.mean(), 4.5 - 2.5 + 3.0);
+    }
+
+    #[test]
+    fn keep_and_drop() {
+        let keep = dice(4, 6, vec![DiceModifier::Keep(DiceSelector::Highest(3))]);
+        let distribution = Distribution::of(&keep).unwrap();
+        assert_close(distribution.mean(), 15869.0 / 1296.0);
+        assert_close(distribution.probability(18), 21.0 / 1296.0);
+        assert_close(distribution.probability(3), 1.0 / 1296.0);
+
+        let drop = dice(4, 6, vec![DiceModifier::Drop(DiceSelector::Lowest(1))]);
+        assert_eq!(Distribution::of(&drop).unwrap(), distribution);
+
+        let advantage = dice(2, 20, vec![DiceModifier::Keep(DiceSelector::Highest(1))]);
+        let distribution = Distribution::of(&advantage).unwrap();
+        assert_close(
+            distribution.at_least(15),
+            1.0 - (14.0 / 20.0) * (14.0 / 20.0),
+        );
+
+        let no_ones = dice(2, 6, vec![DiceModifier::Drop(DiceSelector::Exactly(1))]);
+        let distribution = Distribution::of(&no_ones).unwrap();
+        assert_close(distribution.probability(0), 1.0 / 36.0);
+        assert_close(distribution.probability(6), 5.0 / 36.0);
+    }
+
+    #[test]
+    fn count() {
+        let successes = dice(
+            5,
+            10,
+            vec![DiceModifier::Count(DiceSelector::HigherThan(7))],
+        );
+        let distribution = Distribution::of(&successes).unwrap();
+        assert_close(distribution.probability(0), 0.7f64.powi(5));
+        assert_close(distribution.probability(5), 0.3f64.powi(5));
+        assert_close(distribution.mean(), 1.5);
+
+        let highest_two = dice(3, 6, vec![DiceModifier::Count(DiceSelector::Highest(2))]);
+        let distribution = Distribution::of(&highest_two).unwrap();
+        assert_eq!(distribution.min(), 2);
+        assert_eq!(distribution.max(), 2);
+        assert_close(distribution.probability(2), 1.0);
+    }
+
+    #[test]
+    fn reroll_and_explode() {
+        let reroll = dice(1, 6, vec![DiceModifier::Reroll(DiceSelector::Exactly(1))]);
+        let distribution = Distribution::of(&reroll).unwrap();
+        assert_close(distribution.probability(1), 1.0 / 36.0);
+        assert_close(distribution.probability(6), 7.0 / 36.0);
+
+        let explode = dice(1, 6, vec![DiceModifier::Explode(DiceSelector::Exactly(6))]);
+        let distribution = Distribution::of(&explode).unwrap();
+        assert_close(distribution.probability(6), 0.0);
+        assert_close(distribution.probability(7), 1.0 / 36.0);
+        assert!((distribution.mean() - 4.2).abs() < 1e-6);
+        assert_close(distribution.iter().map(|(_, p)| p).sum(), 1.0);
+
+        let options = AnalysisOptions {
+            explode_limit: 1,
+            ..Default::default()
+        };
+        let distribution = Distribution::of_with(&explode, &options).unwrap();
+        assert_eq!(distribution.max(), 12);
+        assert_close(distribution.probability(12), 1.0 / 36.0);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Distribution::of(&dice(1, 0, vec![])),
+            Err(AnalysisError::NoSides)
+        );
+        assert!(matches!(
+            Distribution::of(&dice(
+                1,
+                6,
+                vec![DiceModifier::Reroll(DiceSelector::Lowest(1))]
+            )),
+            Err(AnalysisError::Unsupported(_))
+        ));
+        assert!(matches!(
+            Distribution::of(&dice(
+                2,
+                6,
+                vec![
+                    DiceModifier::Keep(DiceSelector::All),
+                    DiceModifier::Explode(DiceSelector::Exactly(6))
+                ]
+            )),
+            Err(AnalysisError::Unsupported(_))
+        ));
+        assert!(matches!(
+            Distribution::of(&dice(
+                200,
+                100,
+                vec![DiceModifier::Keep(DiceSelector::Highest(3))]
+            )),
+            Err(AnalysisError::TooComplex(_))
+        ));
+        assert_eq!(
+            Distribution::of(&dice(1, 100_000_000, vec![])),
+            Err(AnalysisError::TooManySides(100_000_000))
+        );
+        assert!(matches!(
+            Distribution::of(&dice(10_000, 100, vec![])),
+            Err(AnalysisError::TooComplex(_))
+        ));
+        assert!(matches!(
+            Distribution::of(&dice(
+                1,
+                10_000,
+                vec![DiceModifier::Explode(DiceSelector::HigherThan(1))]
+            )),
+            Err(AnalysisError::TooComplex(_))
+        ));
+        let many_groups = DiceValue {
+            dice: vec![dice(10, 100, vec![]).dice[0].clone(); 100],
+            bonus: 0,
+        };
+        assert!(matches!(
+            Distribution::of(&many_groups),
+            Err(AnalysisError::TooComplex(_))
+        ));
+    }
+}
@@ -0,0 +1,631 @@
+//! Rolling of [`DiceValue`]s.
+//!
+//! Every group of [`Dice`] is rolled on its own and its modifiers are applied in order:
+//!
+//! * `Reroll` rolls the selected dice once more and keeps the new result.
+//! * `Explode` rolls the selected dice once more and adds the new result to the die. As long as
+//!   the new result matches the selector, this repeats up to [`RollOptions::explode_limit`] times.
+//! * `Keep` and `Drop` mark all other / the selected dice as dropped.
+//! * `Count` keeps the selected dice and counts them instead of adding up their values.
+//!
+//! `Highest(n)` and `Lowest(n)` select the `n` highest / lowest dice that have not been dropped
+//! yet, all other selectors compare the value of each die (or for explosions the last roll).
+//! Dropped dice are never rerolled or exploded.
+//!
+//! Rolls take any [`DiceRng`], so that tests can use a [`SeededRng`] or a fixed list of rolls.
+
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use types::character_sheet_collection::{Dice, DiceModifier, DiceSelector, DiceValue};
+
+/// The most dice a single group may roll.
+pub const MAX_DICE: u32 = 10_000;
+
+/// A source of random die rolls.
+pub trait DiceRng {
+    /// Returns a uniformly distributed number between 1 and `sides` (inclusive).
+    /// `sides` is never 0.
+    fn roll_die(&mut self, sides: u32) -> u32;
+}
+
+/// Any closure returning die results can be used as rng, e.g. to replay fixed rolls.
+impl<F: FnMut(u32) -> u32> DiceRng for F {
+    fn roll_die(&mut self, sides: u32) -> u32 {
+        return self(sides);
+    }
+}
+
+/// A small, seedable pseudo random number generator (SplitMix64).
+/// Not cryptographically secure, but the same seed always produces the same rolls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> SeededRng {
+        return SeededRng { state: seed };
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        return z ^ (z >> 31);
+    }
+}
+
+impl DiceRng for SeededRng {
+    fn roll_die(&mut self, sides: u32) -> u32 {
+        // rejection sampling to avoid a bias towards low results
+        let sides = sides as u64;
+        let zone = u64::MAX - (u64::MAX % sides);
+        loop {
+            let value = self.next_u64();
+            if value < zone {
+                return (value % sides) as u32 + 1;
+            }
+        }
+    }
+}
+
+/// Configures how dice are rolled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RollOptions {
+    /// The maximum amount of times a single die may explode.
+    pub explode_limit: u32,
+}
+
+impl Default for RollOptions {
+    fn default() -> Self {
+        return RollOptions { explode_limit: 20 };
+    }
+}
+
+/// Errors that prevent dice from being rolled.
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RollError {
+    /// Dice need at least one side.
+    NoSides,
+    /// A group has more than [`MAX_DICE`] dice.
+    TooManyDice(i32),
+}
+
+impl fmt::Display for RollError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RollError::NoSides => write!(f, "Dice need at least one side"),
+            RollError::TooManyDice(amount) => write!(
+                f,
+                "Can not roll {} dice at once, the maximum is {}",
+                amount, MAX_DICE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RollError {}
+
+/// How a single roll of a die came to be.
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaceOrigin {
+    /// The first roll of the die.
+    Roll,
+    /// The die was rerolled.
+    Reroll,
+    /// The die exploded.
+    Explosion,
+}
+
+/// A single roll of a die.
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Face {
+    pub value: u32,
+    pub origin: FaceOrigin,
+    /// Whether the face was replaced by a reroll.
+    pub discarded: bool,
+}
+
+/// All rolls of a single die.
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DieRoll {
+    /// Every roll of this die in order.
+    pub faces: Vec<Face>,
+    /// The sum of all faces that have not been discarded.
+    pub value: u32,
+    /// Whether the die was dropped and does not contribute to the total.
+    pub dropped: bool,
+}
+
+impl DieRoll {
+    fn new(value: u32) -> DieRoll {
+        return DieRoll {
+            faces: vec![Face {
+                value,
+                origin: FaceOrigin::Roll,
+                discarded: false,
+            }],
+            value,
+            dropped: false,
+        };
+    }
+
+    /// The value of the last roll that has not been discarded.
+    fn last_face(&self) -> u32 {
+        return self
+            .faces
+            .iter()
+            .rev()
+            .find(|f| !f.discarded)
+            .map(|f| f.value)
+            .unwrap_or(0);
+    }
+}
+
+/// The roll of one group of [`Dice`].
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupRoll {
+    /// The amount of dice, negative if the group is subtracted.
+    pub amount: i32,
+    pub sides: u32,
+    pub dice: Vec<DieRoll>,
+    /// Whether the dice were counted instead of summed up.
+    pub counted: bool,
+    /// The result of this group, already negated for negative amounts.
+    pub total: i64,
+}
+
+/// The result of rolling a [`DiceValue`] with all individual rolls.
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RollResult {
+    pub groups: Vec<GroupRoll>,
+    pub bonus: i32,
+    pub total: i64,
+}
+
+/// Rolls the dice with the default [`RollOptions`].
+pub fn roll(dice: &DiceValue, rng: &mut impl DiceRng) -> Result<RollResult, RollError> {
+    return roll_with(dice, rng, &RollOptions::default());
+}
+
+/// Rolls the dice.
+pub fn roll_with(
+    dice: &DiceValue,
+    rng: &mut impl DiceRng,
+    options: &RollOptions,
+) -> Result<RollResult, RollError> {
+    let mut groups = Vec::with_capacity(dice.dice.len());
+    for group in &dice.dice {
+        groups.push(roll_group(group, rng, options)?);
+    }
+    let total = groups.iter().map(|g| g.total).sum::<i64>() + dice.bonus as i64;
+    return Ok(RollResult {
+        groups,
+        bonus: dice.bonus,
+        total,
+    });
+}
+
+fn roll_group(
+    group: &Dice,
+    rng: &mut impl DiceRng,
+    options: &RollOptions,
+) -> Result<GroupRoll, RollError> {
+    if group.sides == 0 {
+        return Err(RollError::NoSides);
+    }
+    if group.amount.unsigned_abs() > MAX_DICE {
+        return Err(RollError::TooManyDice(group.amount));
+    }
+
+    let sides = group.sides;
+    let mut dice: Vec<DieRoll> = (0..group.amount.unsigned_abs())
+        .map(|_| DieRoll::new(rng.roll_die(sides)))
+        .collect();
+    let mut counted = false;
+
+    for modifier in &group.modifiers {
+        match modifier {
+            DiceModifier::Reroll(selector) => {
+                for index in select(&dice, selector, |d| d.value) {
+                    let die = &mut dice[index];
+                    for face in die.faces.iter_mut() {
+                        face.discarded = true;
+                    }
+                    let value = rng.roll_die(sides);
+                    die.faces.push(Face {
+                        value,
+                        origin: FaceOrigin::Reroll,
+                        discarded: false,
+                    });
+                    die.value = value;
+                }
+            }
+            DiceModifier::Explode(selector) => {
+                let positional = is_positional(selector);
+                for index in select(&dice, selector, |d| d.last_face()) {
+                    let die = &mut dice[index];
+                    for explosion in 0..options.explode_limit {
+                        if explosion > 0 && (positional || !matches(selector, die.last_face())) {
+                            break;
+                        }
+                        let value = rng.roll_die(sides);
+                        die.faces.push(Face {
+                            value,
+                            origin: FaceOrigin::Explosion,
+                            discarded: false,
+                        });
+                        die.value = die.value.saturating_add(value);
+                    }
+                }
+            }
+            DiceModifier::Keep(selector) | DiceModifier::Count(selector) => {
+                let kept = select(&dice, selector, |d| d.value);
+                for (index, die) in dice.iter_mut().enumerate() {
+                    if !kept.contains(&index) {
+                        die.dropped = true;
+                    }
+                }
+                counted |= matches!(modifier, DiceModifier::Count(_));
+            }
+            DiceModifier::Drop(selector) => {
+                for index in select(&dice, selector, |d| d.value) {
+                    dice[index].dropped = true;
+                }
+            }
+        }
+    }
+
+    let kept = dice.iter().filter(|d| !d.dropped);
+    let total = if counted {
+        kept.count() as i64
+    } else {
+        kept.map(|d| d.value as i64).sum()
+    };
+    return Ok(GroupRoll {
+        amount: group.amount,
+        sides,
+        dice,
+        counted,
+        total: if group.amount < 0 { -total } else { total },
+    });
+}
+
+/// Whether the selector depends on the other dice instead of only the value of a single die.
+pub(crate) fn is_positional(selector: &DiceSelector) -> bool {
+    return matches!(selector, DiceSelector::Highest(_) | DiceSelector::Lowest(_));
+}
+
+/// The selector of any modifier.
+pub(crate) fn selector_of(modifier: &DiceModifier) -> &DiceSelector {
+    return match modifier {
+        DiceModifier::Keep(s)
+        | DiceModifier::Drop(s)
+        | DiceModifier::Reroll(s)
+        | DiceModifier::Explode(s)
+        | DiceModifier::Count(s) => s,
+    };
+}
+
+/// Whether a value is selected by a non-positional selector.
+pub(crate) fn matches(selector: &DiceSelector, value: u32) -> bool {
+    return match selector {
+        DiceSelector::HigherThan(x) => value > *x as u32,
+        DiceSelector::LowerThan(x) => value < *x as u32,
+        DiceSelector::Exactly(x) => value == *x as u32,
+        DiceSelector::All => true,
+        DiceSelector::Highest(_) | DiceSelector::Lowest(_) => false,
+    };
+}
+
+/// Returns the indices of all dice that have not been dropped and are selected.
+fn select(
+    dice: &[DieRoll],
+    selector: &DiceSelector,
+    value: impl Fn(&DieRoll) -> u32,
+) -> Vec<usize> {
+    let mut candidates: Vec<usize> = (0..dice.len()).filter(|i| !dice[*i].dropped).collect();
+    return match selector {
+        DiceSelector::Highest(n) => {
+            // stable sort, so that of equal dice the first ones are selected
+            candidates.sort_by_key(|i| std::cmp::Reverse(value(&dice[*i])));
+            candidates.truncate(*n as usize);
+            candidates
+        }
+        DiceSelector::Lowest(n) => {
+            candidates.sort_by_key(|i| value(&dice[*i]));
+            candidates.truncate(*n as usize);
+            candidates
+        }
+        _ => candidates
+            .into_iter()
+            .filter(|i| matches(selector, value(&dice[*i])))
+            .collect(),
+    };
+}
+
+impl fmt::Display for RollResult {
+    /// Writes the rolls like `4d6: [6, 4, 3, (1)] + 2 = 15`.
+    /// Dropped dice are in parentheses, rolls replaced by a reroll are prefixed with `~` and
+    /// explosions are joined with `!`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, group) in self.groups.iter().enumerate() {
+            if index > 0 || group.amount < 0 {
+                write!(f, "{}", if group.amount < 0 { " - " } else { " + " })?;
+            }
+            write!(f, "{}d{}: [", group.amount.unsigned_abs(), group.sides)?;
+            for (index, die) in group.dice.iter().enumerate() {
+                if index > 0 {
+                    write!(f, ", ")?;
+                }
+                let mut faces = String::new();
+                for (index, face) in die.faces.iter().enumerate() {
+                    if index > 0 {
+                        faces.push(if face.origin == FaceOrigin::Explosion {
+                            '!'
+                        } else {
+                            ' '
+                        });
+                    }
+                    if face.discarded {
+                        faces.push('~');
+                    }
+                    faces.push_str(&face.value.to_string());
+                }
+                if die.dropped {
+                    write!(f, "({})", faces)?;
+                } else {
+                    write!(f, "{}", faces)?;
+                }
+            }
+            write!(f, "]")?;
+            if group.counted {
+                write!(f, " counted")?;
+            }
+        }
+        if self.bonus != 0 || self.groups.is_empty() {
+            if self.groups.is_empty() {
+                write!(f, "{}", self.bonus)?;
+            } else if self.bonus < 0 {
+                write!(f, " - {}", self.bonus.unsigned_abs())?;
+            } else {
+                write!(f, " + {}", self.bonus)?;
+            }
+        }
+        write!(f, " = {}", self.total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::character_sheet_collection::{Dice, DiceModifier, DiceSelector, DiceValue};
+
+    use super::{roll, roll_with, DiceRng, FaceOrigin, RollError, RollOptions, SeededRng};
+
+    fn dice(amount: i32, sides: u32, modifiers: Vec<DiceModifier>, bonus: i32) -> DiceValue {
+        DiceValue {
+            dice: vec![Dice {
+                amount,
+                sides,
+                modifiers,
+            }],
+            bonus,
+        }
+    }
+
+    /// Returns the given rolls in order.
+    fn fixed(rolls: &[u32]) -> impl DiceRng + '_ {
+        let mut rolls = rolls.iter();
+        move |_sides: u32| *rolls.next().expect("not enough rolls")
+    }
+
+    #[test]
+    fn seeded_rolls_are_reproducible() {
+        let value = dice(10, 20, vec![], 0);
+        let first = roll(&value, &mut SeededRng::new(42)).unwrap();
+        let second = roll(&value, &mut SeededRng::new(42)).unwrap();
+        assert_eq!(first, second);
+        assert!(first.groups[0]
+            .dice
+            .iter()
+            .all(|d| (1..=20).contains(&d.value)));
+
+        let mut rng = SeededRng::new(7);
+        let mut seen = [false; 6];
+        for _ in 0..200 {
+            seen[rng.roll_die(6) as usize - 1] = true;
+        }
+        assert!(seen.iter().all(|s| *s), "Every side is rolled eventually.");
+    }
+
+    #[test]
+    fn sum_and_bonus() {
+        let value = DiceValue {
+            dice: vec![
+                Dice {
+                    amount: 2,
+                    sides: 6,
+                    modifiers: vec![],
+                },
+                Dice {
+                    amount: -1,
+                    sides: 4,
+                    modifiers: vec![],
+                },
+            ],
+            bonus: 3,
+        };
+        let result = roll(&value, &mut fixed(&[6, 2, 3])).unwrap();
+        assert_eq!(result.groups[0].total, 8);
+        assert_eq!(result.groups[1].total, -3);
+        assert_eq!(result.total, 8);
+        assert_eq!(result.to_string(), "2d6: [6, 2] - 1d4: [3] + 3 = 8");
+    }
+
+    #[test]
+    fn keep_and_drop() {
+        let keep_highest = dice(4, 6, vec![DiceModifier::Keep(DiceSelector::Highest(3))], 0);
+        let result = roll(&keep_highest, &mut fixed(&[3, 1, 6, 3])).unwrap();
+        assert_eq!(result.total, 12);
+        let dropped: Vec<bool> = result.groups[0].dice.iter().map(|d| d.dropped).collect();
+        assert_eq!(dropped, vec![false, true, false, false]);
+        assert_eq!(result.to_string(), "4d6: [3, (1), 6, 3] = 12");
+
+        let drop_lowest = dice(4, 6, vec![DiceModifier::Drop(DiceSelector::Lowest(1))], 0);
+        assert_eq!(
+            roll(&drop_lowest, &mut fixed(&[3, 1, 6, 3])).unwrap().total,
+            12
+        );
+
+        let drop_ones = dice(
+            4,
+            6,
+            vec![DiceModifier::Drop(DiceSelector::LowerThan(2))],
+            0,
+        );
+        assert_eq!(
+            roll(&drop_ones, &mut fixed(&[1, 1, 6, 3])).unwrap().total,
+            9
+        );
+
+        let advantage_then_drop = dice(
+            3,
+            20,
+            vec![
+                DiceModifier::Drop(DiceSelector::Exactly(20)),
+                DiceModifier::Keep(DiceSelector::Highest(1)),
+            ],
+            0,
+        );
+        assert_eq!(
+            roll(&advantage_then_drop, &mut fixed(&[20, 5, 12]))
+                .unwrap()
+                .total,
+            12
+        );
+    }
+
+    #[test]
+    fn reroll_once() {
+        let value = dice(
+            3,
+            6,
+            vec![DiceModifier::Reroll(DiceSelector::LowerThan(3))],
+            0,
+        );
+        let result = roll(&value, &mut fixed(&[1, 5, 2, 1, 4])).unwrap();
+        let values: Vec<u32> = result.groups[0].dice.iter().map(|d| d.value).collect();
+        assert_eq!(
+            values,
+            vec![1, 5, 4],
+            "The reroll is kept, even if it is low again."
+        );
+        assert_eq!(result.total, 10);
+
+        let first_die = &result.groups[0].dice[0];
+        assert_eq!(first_die.faces.len(), 2);
+        assert!(first_die.faces[0].discarded);
+        assert_eq!(first_die.faces[1].origin, FaceOrigin::Reroll);
+        assert_eq!(result.to_string(), "3d6: [~1 1, 5, ~2 4] = 10");
+    }
+
+    #[test]
+    fn explode() {
+        let value = dice(
+            2,
+            6,
+            vec![DiceModifier::Explode(DiceSelector::Exactly(6))],
+            0,
+        );
+        let result = roll(&value, &mut fixed(&[6, 3, 6, 6, 2])).unwrap();
+        let values: Vec<u32> = result.groups[0].dice.iter().map(|d| d.value).collect();
+        assert_eq!(values, vec![6 + 6 + 6 + 2, 3]);
+        assert_eq!(result.total, 23);
+        assert_eq!(result.to_string(), "2d6: [6!6!6!2, 3] = 23");
+
+        let always = dice(1, 6, vec![DiceModifier::Explode(DiceSelector::All)], 0);
+        let options = RollOptions { explode_limit: 3 };
+        let result = roll_with(&always, &mut fixed(&[1, 2, 3, 4]), &options).unwrap();
+        assert_eq!(
+            result.groups[0].dice[0].faces.len(),
+            4,
+            "Explosions are capped."
+        );
+        assert_eq!(result.total, 10);
+
+        let highest = dice(
+            2,
+            6,
+            vec![DiceModifier::Explode(DiceSelector::Highest(1))],
+            0,
+        );
+        let result = roll(&highest, &mut fixed(&[2, 6, 6])).unwrap();
+        assert_eq!(
+            result.total, 14,
+            "Positional explosions only happen once per die."
+        );
+    }
+
+    #[test]
+    fn count_successes() {
+        let value = dice(
+            5,
+            10,
+            vec![DiceModifier::Count(DiceSelector::HigherThan(7))],
+            1,
+        );
+        let result = roll(&value, &mut fixed(&[8, 10, 3, 7, 9])).unwrap();
+        assert!(result.groups[0].counted);
+        assert_eq!(result.groups[0].total, 3);
+        assert_eq!(result.total, 4);
+        assert_eq!(
+            result.to_string(),
+            "5d10: [8, 10, (3), (7), 9] counted + 1 = 4"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            roll(&dice(1, 0, vec![], 0), &mut SeededRng::new(0)),
+            Err(RollError::NoSides)
+        );
+        assert_eq!(
+            roll(&dice(-20_000, 6, vec![], 0), &mut SeededRng::new(0)),
+            Err(RollError::TooManyDice(-20_000))
+        );
+    }
+}
@@ -0,0 +1,1025 @@
+//! The expression language used by [`Script`](types::character_sheet_collection::Script)s.
+//!
+//! # Syntax
+//!
+//! * Numbers: `12`, `1.5`
+//! * Dice in [dice notation](types::dice_notation): `2d6`, `4d6kh3`. The amount is required.
+//! * References to dependencies: `strength` or `$strength`, properties of a feature are qualified
+//!   with its name: `Rage.uses`
+//! * The current value of the modified property: `@` or `$@`.
+//!   Scripts using it are bonuses that are applied on top of the other values of the property.
+//! * Arithmetic: `+`, `-`, `*`, `/` and `%` with the usual precedence and parentheses.
+//!   Divisions are exact, use `floor`, `ceil` or `round` to get back to a whole number or let the
+//!   [rounding mode](types::character_sheet_collection::RoundingMode) of the property round it.
+//! * Functions: `floor(x)`, `ceil(x)`, `round(x)`, `abs(x)`, `min(a, b, ...)`, `max(a, b, ...)`
+//! * Comparisons: `<`, `<=`, `>`, `>=`, `==`, `!=`
+//! * Logic: `and`, `or`, `not` (or `&&`, `||`, `!`) and the literals `true` and `false`
+//! * Conditionals: `condition ? value_if_true : value_if_false`
+//!
+//! Example: `level >= 5 ? floor((strength - 10) / 2) + 2 : 1 + strength`
+//!
+//! # Dice
+//!
+//! Dice values support a subset of the arithmetic:
+//!
+//! * Adding or subtracting whole numbers changes the bonus: `1d8 + 3` is `1d8+3`.
+//! * Adding or subtracting dice combines them: `1d8 + 2d6 + 1d8` is `2d8+2d6`.
+//!   Groups are only merged if they have the same sides and modifiers, are added with the same
+//!   sign and do not use the positional selectors `h` or `l`, so that the roll stays the same.
+//! * Multiplying with a whole number multiplies the amount of dice, but not the bonus:
+//!   `(1d8 + 3) * 2` is `2d8+3`.
+//!
+//! Everything else, e.g. multiplying dice with dice, is an error.
+
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use types::character_sheet_collection::DiceValue;
+use types::fraction::Fraction;
+
+use crate::roll::{is_positional, selector_of};
+
+/// An error that occured while parsing or evaluating a script.
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+    /// Byte offset into the script at which the error occured.
+    pub position: usize,
+    pub message: String,
+}
+
+impl ScriptError {
+    fn new(position: usize, message: impl Into<String>) -> ScriptError {
+        return ScriptError {
+            position,
+            message: message.into(),
+        };
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at position {})", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// A value a script expression may evaluate to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Number(Fraction),
+    Bool(bool),
+    Dice(DiceValue),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        return match self {
+            Value::Number(_) => "number",
+            Value::Bool(_) => "boolean",
+            Value::Dice(_) => "dice value",
+        };
+    }
+}
+
+/// The name under which the current value of the modified property is resolved.
+pub const CURRENT_VALUE: &str = "@";
+
+/// Parses and evaluates the given script.
+///
+/// `resolve` is called for every referenced property and returns its value, or `None` if the
+/// property may not be referenced from this script.
+pub fn evaluate<F>(script: &str, resolve: F) -> Result<Value, ScriptError>
+where
+    F: Fn(&str) -> Option<Result<Value, String>>,
+{
+    return evaluate_with_position(script, resolve).map(|(value, _)| value);
+}
+
+/// Like [`evaluate`], but also returns the position of the outermost expression, e.g. the `<` of
+/// `1 < strength`. Useful to report errors about the result of the script.
+pub fn evaluate_with_position<F>(script: &str, resolve: F) -> Result<(Value, usize), ScriptError>
+where
+    F: Fn(&str) -> Option<Result<Value, String>>,
+{
+    let expression = parse(script)?;
+    let value = expression.evaluate(&resolve)?;
+    return Ok((value, expression.position));
+}
+
+/// Parses the script without evaluating it.
+/// Useful to check a script for syntax errors before any of its dependencies are known.
+pub fn check_syntax(script: &str) -> Result<(), ScriptError> {
+    return parse(script).map(|_| ());
+}
+
+/// Whether the script references the current value of the property it modifies (`@`).
+/// Scripts with syntax errors never do.
+pub fn references_current_value(script: &str) -> bool {
+    return match lex(script) {
+        Ok(tokens) => tokens
+            .iter()
+            .any(|t| t.kind == TokenKind::Identifier(CURRENT_VALUE.to_string())),
+        Err(_) => false,
+    };
+}
+
+fn parse(script: &str) -> Result<Expression, ScriptError> {
+    let tokens = lex(script)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        position: 0,
+        depth: 0,
+    };
+    let expression = parser.expression()?;
+    let rest = parser.curr();
+    if rest.kind != TokenKind::End {
+        return Err(ScriptError::new(
+            rest.position,
+            format!("Unexpected {} after the end of the expression", rest.kind),
+        ));
+    }
+    return Ok(expression);
+}
+
+// ---------------------------------------------------------------------------------------------
+// lexing
+// ---------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TokenKind {
+    Number(Fraction),
+    Dice(DiceValue),
+    Identifier(String),
+    Operator(&'static str),
+    OpeningParenthesis,
+    ClosingParenthesis,
+    Comma,
+    Question,
+    Colon,
+    End,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::Number(n) => write!(f, "number `{}`", n),
+            TokenKind::Dice(d) => write!(f, "dice `{}`", d),
+            TokenKind::Identifier(i) => write!(f, "`{}`", i),
+            TokenKind::Operator(o) => write!(f, "`{}`", o),
+            TokenKind::OpeningParenthesis => write!(f, "`(`"),
+            TokenKind::ClosingParenthesis => write!(f, "`)`"),
+            TokenKind::Comma => write!(f, "`,`"),
+            TokenKind::Question => write!(f, "`?`"),
+            TokenKind::Colon => write!(f, "`:`"),
+            TokenKind::End => write!(f, "end of script"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    kind: TokenKind,
+    position: usize,
+}
+
+const OPERATORS: [&str; 16] = [
+    "<=", ">=", "==", "!=", "&&", "||", "<", ">", "+", "-", "*", "/", "%", "!", "=", "&",
+];
+
+fn lex(script: &str) -> Result<Vec<Token>, ScriptError> {
+    let mut tokens = Vec::new();
+    let mut chars = script.char_indices().peekable();
+
+    while let Some(&(position, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() {
+            let mut end = position;
+            let mut seen_dot = false;
+            while let Some(&(i, c)) = chars.peek() {
+                if c.is_ascii_digit() || (c == '.' && !seen_dot) {
+                    seen_dot |= c == '.';
+                    end = i + c.len_utf8();
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            if script[end..].starts_with('d')
+                && script[end + 1..].starts_with(|c: char| c.is_ascii_digit())
+            {
+                let end = dice_end(script, end);
+                while chars.peek().is_some_and(|(i, _)| *i < end) {
+                    chars.next();
+                }
+                let dice = script[position..end].parse::<DiceValue>().map_err(|e| {
+                    ScriptError::new(position + e.position, format!("Invalid dice: {}", e.kind))
+                })?;
+                tokens.push(Token {
+                    kind: TokenKind::Dice(dice),
+                    position,
+                });
+                continue;
+            }
+            let number = script[position..end]
+                .parse::<Fraction>()
+                .map_err(|_| ScriptError::new(position, "Invalid number"))?;
+            tokens.push(Token {
+                kind: TokenKind::Number(number),
+                position,
+            });
+        } else if c == '@' || (c == '$' && script[position + 1..].starts_with('@')) {
+            chars.next();
+            if c == '$' {
+                chars.next();
+            }
+            tokens.push(Token {
+                kind: TokenKind::Identifier(CURRENT_VALUE.to_string()),
+                position,
+            });
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            chars.next();
+            let mut end = position + c.len_utf8();
+            while let Some(&(i, c)) = chars.peek() {
+                // a `.` separates the feature from its property, e.g. `Rage.uses`
+                let qualified = c == '.'
+                    && script[i + 1..]
+                        .starts_with(|next: char| next.is_alphabetic() || next == '_');
+                if c.is_alphanumeric() || c == '_' || qualified {
+                    end = i + c.len_utf8();
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            let name = script[position..end].trim_start_matches('$');
+            if name.is_empty() {
+                return Err(ScriptError::new(position, "Expected a name after `$`"));
+            }
+            tokens.push(Token {
+                kind: TokenKind::Identifier(name.to_string()),
+                position,
+            });
+        } else {
+            let kind = match c {
+                '(' => TokenKind::OpeningParenthesis,
+                ')' => TokenKind::ClosingParenthesis,
+                ',' => TokenKind::Comma,
+                '?' => TokenKind::Question,
+                ':' => TokenKind::Colon,
+                _ => {
+                    let operator = OPERATORS
+                        .iter()
+                        .find(|o| script[position..].starts_with(**o))
+                        .ok_or_else(|| {
+                            ScriptError::new(position, format!("Unexpected character `{}`", c))
+                        })?;
+                    if *operator == "=" || *operator == "&" {
+                        return Err(ScriptError::new(
+                            position,
+                            format!("Unexpected character `{}`, did you mean `{}{}`?", c, c, c),
+                        ));
+                    }
+                    // all operators are ascii
+                    for _ in 1..operator.len() {
+                        chars.next();
+                    }
+                    TokenKind::Operator(operator)
+                }
+            };
+            chars.next();
+            tokens.push(Token { kind, position });
+        }
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::End,
+        position: script.len(),
+    });
+    return Ok(tokens);
+}
+
+/// Finds the end of the dice notation whose sides start after the `d` at `start`.
+/// Stops at whitespace and operators, so that e.g. `2d6+1` is lexed as dice plus a number.
+fn dice_end(script: &str, start: usize) -> usize {
+    let mut end = start + 1;
+    let mut after_modifier = false;
+    for c in script[end..].chars() {
+        let allowed = c.is_ascii_digit()
+            || matches!(c, 'k' | 'd' | 'r' | 'x' | 'c')
+            || (after_modifier && matches!(c, 'h' | 'l' | 'a' | '<' | '>' | '='));
+        if !allowed {
+            break;
+        }
+        after_modifier = matches!(c, 'k' | 'd' | 'r' | 'x' | 'c');
+        end += c.len_utf8();
+    }
+    return end;
+}
+
+// ---------------------------------------------------------------------------------------------
+// parsing
+// ---------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Expression {
+    kind: ExpressionKind,
+    position: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ExpressionKind {
+    Literal(Value),
+    Reference(String),
+    Unary(&'static str, Box<Expression>),
+    Binary(&'static str, Box<Expression>, Box<Expression>),
+    Conditional(Box<Expression>, Box<Expression>, Box<Expression>),
+    Call(String, Vec<Expression>),
+}
+
+/// How deep parentheses, calls, signs and conditionals can be nested, so that parsing and
+/// evaluating them can not overflow the stack.
+pub const MAX_NESTING: usize = 64;
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    /// How many expressions the current expression is nested in.
+    depth: usize,
+}
+
+/// Binary operators by precedence, lowest first.
+const BINARY_OPERATORS: [&[&str]; 6] = [
+    &["or", "||"],
+    &["and", "&&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+impl Parser<'_> {
+    fn curr(&self) -> &Token {
+        &self.tokens[self.position]
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.position].clone();
+        if token.kind != TokenKind::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<Token, ScriptError> {
+        if self.curr().kind != kind {
+            return Err(self.unexpected(&kind.to_string()));
+        }
+        return Ok(self.advance());
+    }
+
+    fn unexpected(&self, expected: &str) -> ScriptError {
+        let curr = self.curr();
+        return ScriptError::new(
+            curr.position,
+            format!("Unexpected {}, expected {}", curr.kind, expected),
+        );
+    }
+
+    /// Parses an expression inside of another one, unless they are already nested too deep.
+    fn nested(
+        &mut self,
+        parse: fn(&mut Self) -> Result<Expression, ScriptError>,
+    ) -> Result<Expression, ScriptError> {
+        if self.depth == MAX_NESTING {
+            return Err(ScriptError::new(
+                self.curr().position,
+                format!(
+                    "Expressions can not be nested more than {} levels deep",
+                    MAX_NESTING
+                ),
+            ));
+        }
+        self.depth += 1;
+        let expression = parse(self);
+        self.depth -= 1;
+        return expression;
+    }
+
+    /// Returns the binary operator of the current token if it has the given precedence.
+    fn binary_operator(&self, precedence: usize) -> Option<&'static str> {
+        let operator = match &self.curr().kind {
+            TokenKind::Operator(o) => *o,
+            TokenKind::Identifier(i) if i == "and" => "and",
+            TokenKind::Identifier(i) if i == "or" => "or",
+            _ => return None,
+        };
+        return BINARY_OPERATORS[precedence]
+            .contains(&operator)
+            .then_some(operator);
+    }
+
+    fn expression(&mut self) -> Result<Expression, ScriptError> {
+        let condition = self.binary(0)?;
+        if self.curr().kind != TokenKind::Question {
+            return Ok(condition);
+        }
+        self.advance();
+        let if_true = self.nested(Self::expression)?;
+        self.expect(TokenKind::Colon)?;
+        let if_false = self.nested(Self::expression)?;
+        return Ok(Expression {
+            position: condition.position,
+            kind: ExpressionKind::Conditional(
+                Box::new(condition),
+                Box::new(if_true),
+                Box::new(if_false),
+            ),
+        });
+    }
+
+    fn binary(&mut self, precedence: usize) -> Result<Expression, ScriptError> {
+        if precedence == BINARY_OPERATORS.len() {
+            return self.unary();
+        }
+
+        let mut left = self.binary(precedence + 1)?;
+        while let Some(operator) = self.binary_operator(precedence) {
+            let position = self.advance().position;
+            let right = self.binary(precedence + 1)?;
+            let operator = match operator {
+                "||" => "or",
+                "&&" => "and",
+                o => o,
+            };
+            left = Expression {
+                kind: ExpressionKind::Binary(operator, Box::new(left), Box::new(right)),
+                position,
+            };
+        }
+        return Ok(left);
+    }
+
+    fn unary(&mut self) -> Result<Expression, ScriptError> {
+        let operator = match &self.curr().kind {
+            TokenKind::Operator("-") => "-",
+            TokenKind::Operator("+") => "+",
+            TokenKind::Operator("!") => "not",
+            TokenKind::Identifier(i) if i == "not" => "not",
+            _ => return self.primary(),
+        };
+        let position = self.advance().position;
+        let operand = self.nested(Self::unary)?;
+        return Ok(Expression {
+            kind: ExpressionKind::Unary(operator, Box::new(operand)),
+            position,
+        });
+    }
+
+    fn primary(&mut self) -> Result<Expression, ScriptError> {
+        let token = self.curr().clone();
+        let kind = match token.kind {
+            TokenKind::Number(n) => {
+                self.advance();
+                ExpressionKind::Literal(Value::Number(n))
+            }
+            TokenKind::Dice(d) => {
+                self.advance();
+                ExpressionKind::Literal(Value::Dice(d))
+            }
+            TokenKind::Identifier(name) => {
+                self.advance();
+                match name.as_str() {
+                    "true" => ExpressionKind::Literal(Value::Bool(true)),
+                    "false" => ExpressionKind::Literal(Value::Bool(false)),
+                    "and" | "or" | "not" => {
+                        return Err(ScriptError::new(
+                            token.position,
+                            format!("Unexpected `{}`, expected a value", name),
+                        ))
+                    }
+                    _ if self.curr().kind == TokenKind::OpeningParenthesis => {
+                        ExpressionKind::Call(name, self.arguments()?)
+                    }
+                    _ => ExpressionKind::Reference(name),
+                }
+            }
+            TokenKind::OpeningParenthesis => {
+                self.advance();
+                let inner = self.nested(Self::expression)?;
+                self.expect(TokenKind::ClosingParenthesis)?;
+                return Ok(inner);
+            }
+            _ => return Err(self.unexpected("a value")),
+        };
+        return Ok(Expression {
+            kind,
+            position: token.position,
+        });
+    }
+
+    fn arguments(&mut self) -> Result<Vec<Expression>, ScriptError> {
+        self.expect(TokenKind::OpeningParenthesis)?;
+        let mut arguments = vec![];
+        if self.curr().kind != TokenKind::ClosingParenthesis {
+            loop {
+                arguments.push(self.nested(Self::expression)?);
+                if self.curr().kind != TokenKind::Comma {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.expect(TokenKind::ClosingParenthesis)?;
+        return Ok(arguments);
+    }
+}
+
+// ---------------------------------------------------------------------------------------------
+// evaluation
+// ---------------------------------------------------------------------------------------------
+
+impl Expression {
+    fn evaluate<F>(&self, resolve: &F) -> Result<Value, ScriptError>
+    where
+        F: Fn(&str) -> Option<Result<Value, String>>,
+    {
+        return match &self.kind {
+            ExpressionKind::Literal(value) => Ok(value.clone()),
+            ExpressionKind::Reference(name) => match resolve(name) {
+                Some(Ok(value)) => Ok(value),
+                Some(Err(message)) => Err(self.error(message)),
+                None => Err(self.error(format!("`{}` is not a dependency of this script", name))),
+            },
+            ExpressionKind::Unary(operator, operand) => {
+                let value = operand.evaluate(resolve)?;
+                match (*operator, value) {
+                    ("-", Value::Number(n)) => Ok(Value::Number(self.checked(n.checked_neg())?)),
+                    ("-", Value::Dice(d)) => Ok(Value::Dice(self.checked_dice(negate(d))?)),
+                    ("+", value @ (Value::Number(_) | Value::Dice(_))) => Ok(value),
+                    ("not", Value::Bool(b)) => Ok(Value::Bool(!b)),
+                    (operator, value) => Err(self.error(format!(
+                        "`{}` can not be applied to a {}",
+                        operator,
+                        value.type_name()
+                    ))),
+                }
+            }
+            ExpressionKind::Binary(operator, left, right) => {
+                let left = left.evaluate(resolve)?;
+                // short circuit, so that e.g. `x != 0 and 10 / x > 2` works
+                match (*operator, &left) {
+                    ("and", Value::Bool(false)) => return Ok(Value::Bool(false)),
+                    ("or", Value::Bool(true)) => return Ok(Value::Bool(true)),
+                    _ => {}
+                }
+                let right = right.evaluate(resolve)?;
+                self.binary(operator, left, right)
+            }
+            ExpressionKind::Conditional(condition, if_true, if_false) => {
+                match condition.evaluate(resolve)? {
+                    Value::Bool(true) => if_true.evaluate(resolve),
+                    Value::Bool(false) => if_false.evaluate(resolve),
+                    other => Err(condition.error(format!(
+                        "The condition must be a boolean, but is a {}",
+                        other.type_name()
+                    ))),
+                }
+            }
+            ExpressionKind::Call(name, arguments) => {
+                let mut values = Vec::with_capacity(arguments.len());
+                for argument in arguments {
+                    values.push(argument.evaluate(resolve)?);
+                }
+                self.call(name, values)
+            }
+        };
+    }
+
+    fn binary(&self, operator: &str, left: Value, right: Value) -> Result<Value, ScriptError> {
+        return match (operator, left, right) {
+            ("+", Value::Number(l), Value::Number(r)) => {
+                Ok(Value::Number(self.checked(l.checked_add(r))?))
+            }
+            ("-", Value::Number(l), Value::Number(r)) => {
+                Ok(Value::Number(self.checked(l.checked_sub(r))?))
+            }
+            ("*", Value::Number(l), Value::Number(r)) => {
+                Ok(Value::Number(self.checked(l.checked_mul(r))?))
+            }
+            ("/" | "%", Value::Number(_), Value::Number(r)) if r == Fraction::from_integer(0) => {
+                Err(self.error("Division by zero"))
+            }
+            ("/", Value::Number(l), Value::Number(r)) => {
+                Ok(Value::Number(self.checked(l.checked_div(r))?))
+            }
+            ("%", Value::Number(l), Value::Number(r)) => {
+                Ok(Value::Number(self.checked(l.checked_rem(r))?))
+            }
+            ("+", Value::Dice(l), Value::Dice(r)) => {
+                Ok(Value::Dice(self.checked_dice(merge(l, r))?))
+            }
+            ("-", Value::Dice(l), Value::Dice(r)) => {
+                let r = self.checked_dice(negate(r))?;
+                Ok(Value::Dice(self.checked_dice(merge(l, r))?))
+            }
+            ("+", Value::Dice(d), Value::Number(n)) | ("+", Value::Number(n), Value::Dice(d)) => {
+                Ok(Value::Dice(
+                    self.checked_dice(add_bonus(d, self.whole(n)?))?,
+                ))
+            }
+            ("-", Value::Dice(d), Value::Number(n)) => {
+                let n = self.whole(n)?.checked_neg();
+                Ok(Value::Dice(
+                    self.checked_dice(n.and_then(|n| add_bonus(d, n)))?,
+                ))
+            }
+            ("-", Value::Number(n), Value::Dice(d)) => {
+                let d = self.checked_dice(negate(d))?;
+                Ok(Value::Dice(
+                    self.checked_dice(add_bonus(d, self.whole(n)?))?,
+                ))
+            }
+            ("*", Value::Dice(d), Value::Number(n)) | ("*", Value::Number(n), Value::Dice(d)) => {
+                Ok(Value::Dice(self.checked_dice(multiply(d, self.whole(n)?))?))
+            }
+            ("*", Value::Dice(_), Value::Dice(_)) => {
+                Err(self.error("Dice values can not be multiplied with each other"))
+            }
+            ("/" | "%", Value::Dice(_), _) | ("/" | "%", _, Value::Dice(_)) => {
+                Err(self.error(format!(
+                    "`{}` can not be applied to dice values, only `+`, `-` and `*`",
+                    operator
+                )))
+            }
+            ("<", Value::Number(l), Value::Number(r)) => Ok(Value::Bool(l < r)),
+            ("<=", Value::Number(l), Value::Number(r)) => Ok(Value::Bool(l <= r)),
+            (">", Value::Number(l), Value::Number(r)) => Ok(Value::Bool(l > r)),
+            (">=", Value::Number(l), Value::Number(r)) => Ok(Value::Bool(l >= r)),
+            ("==", l, r) if l.type_name() == r.type_name() => Ok(Value::Bool(l == r)),
+            ("!=", l, r) if l.type_name() == r.type_name() => Ok(Value::Bool(l != r)),
+            ("and", Value::Bool(l), Value::Bool(r)) => Ok(Value::Bool(l && r)),
+            ("or", Value::Bool(l), Value::Bool(r)) => Ok(Value::Bool(l || r)),
+            (operator, l, r) => Err(self.error(format!(
+                "`{}` can not be applied to a {} and a {}",
+                operator,
+                l.type_name(),
+                r.type_name()
+            ))),
+        };
+    }
+
+    fn call(&self, name: &str, arguments: Vec<Value>) -> Result<Value, ScriptError> {
+        let mut numbers = Vec::with_capacity(arguments.len());
+        for argument in arguments {
+            match argument {
+                Value::Number(n) => numbers.push(n),
+                other => {
+                    return Err(self.error(format!(
+                        "`{}` expects numbers, but got a {}",
+                        name,
+                        other.type_name()
+                    )))
+                }
+            }
+        }
+
+        let single = |numbers: &[Fraction]| -> Result<Fraction, ScriptError> {
+            match numbers {
+                [n] => Ok(*n),
+                _ => Err(self.error(format!(
+                    "`{}` expects exactly 1 argument, but got {}",
+                    name,
+                    numbers.len()
+                ))),
+            }
+        };
+
+        let result = match name {
+            "floor" => single(&numbers)?.floor(),
+            "ceil" => single(&numbers)?.ceil(),
+            "round" => single(&numbers)?.round(),
+            "abs" => {
+                let n = single(&numbers)?;
+                if n < Fraction::from_integer(0) {
+                    self.checked(n.checked_neg())?
+                } else {
+                    n
+                }
+            }
+            "min" | "max" => {
+                let found = if name == "min" {
+                    numbers.iter().min()
+                } else {
+                    numbers.iter().max()
+                };
+                *found
+                    .ok_or_else(|| self.error(format!("`{}` expects at least 1 argument", name)))?
+            }
+            _ => return Err(self.error(format!("Unknown function `{}`", name))),
+        };
+        return Ok(Value::Number(result));
+    }
+
+    /// Converts a number that is combined with dice to an integer.
+    fn whole(&self, n: Fraction) -> Result<i32, ScriptError> {
+        if !n.is_integer() {
+            return Err(self.error(format!(
+                "Dice can only be combined with whole numbers, but got {}",
+                n
+            )));
+        }
+        return i32::try_from(n.numerator()).map_err(|_| self.error("The calculation overflowed"));
+    }
+
+    fn checked_dice(&self, value: Option<DiceValue>) -> Result<DiceValue, ScriptError> {
+        return value.ok_or_else(|| self.error("The calculation overflowed"));
+    }
+
+    fn checked(&self, value: Option<Fraction>) -> Result<Fraction, ScriptError> {
+        return value.ok_or_else(|| self.error("The calculation overflowed"));
+    }
+
+    fn error(&self, message: impl Into<String>) -> ScriptError {
+        return ScriptError::new(self.position, message);
+    }
+}
+
+fn add_bonus(mut dice: DiceValue, bonus: i32) -> Option<DiceValue> {
+    dice.bonus = dice.bonus.checked_add(bonus)?;
+    return Some(dice);
+}
+
+fn negate(mut dice: DiceValue) -> Option<DiceValue> {
+    for group in &mut dice.dice {
+        group.amount = group.amount.checked_neg()?;
+    }
+    dice.bonus = dice.bonus.checked_neg()?;
+    return Some(dice);
+}
+
+/// Multiplies the amount of dice in every group, the bonus stays the same.
+fn multiply(mut dice: DiceValue, factor: i32) -> Option<DiceValue> {
+    for group in &mut dice.dice {
+        group.amount = group.amount.checked_mul(factor)?;
+    }
+    dice.dice.retain(|group| group.amount != 0);
+    return Some(dice);
+}
+
+/// Adds the dice of `right` to `left`, merging groups if that does not change the roll.
+fn merge(mut left: DiceValue, right: DiceValue) -> Option<DiceValue> {
+    left.bonus = left.bonus.checked_add(right.bonus)?;
+    for group in right.dice {
+        let mergeable = left.dice.iter_mut().find(|existing| {
+            existing.sides == group.sides
+                && existing.modifiers == group.modifiers
+                && existing.amount.signum() == group.amount.signum()
+                && !group
+                    .modifiers
+                    .iter()
+                    .any(|m| is_positional(selector_of(m)))
+        });
+        match mergeable {
+            Some(existing) => existing.amount = existing.amount.checked_add(group.amount)?,
+            None => left.dice.push(group),
+        }
+    }
+    return Some(left);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{evaluate, references_current_value, Fraction, ScriptError, Value, MAX_NESTING};
+
+    fn dice(notation: &str) -> Result<Value, ScriptError> {
+        Ok(Value::Dice(notation.parse().unwrap()))
+    }
+
+    fn eval(script: &str) -> Result<Value, ScriptError> {
+        evaluate(script, |name| match name {
+            "strength" => Some(Ok(Value::Number(Fraction::from_integer(14)))),
+            "level" => Some(Ok(Value::Number(Fraction::from_integer(3)))),
+            "@" => Some(Ok(Value::Number(Fraction::from_integer(2)))),
+            "broken" => Some(Err("`broken` has no value".to_string())),
+            "weapon" => Some(Ok(Value::Dice("1d8".parse().unwrap()))),
+            "Rage.uses" => Some(Ok(Value::Number(Fraction::from_integer(2)))),
+            _ => None,
+        })
+    }
+
+    fn number(n: i64) -> Result<Value, ScriptError> {
+        Ok(Value::Number(Fraction::from_integer(n)))
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(eval("1 + 2 * 3"), number(7));
+        assert_eq!(eval("(1 + 2) * 3"), number(9));
+        assert_eq!(eval("10 - 4 - 3"), number(3));
+        assert_eq!(eval("-2 * -3"), number(6));
+        assert_eq!(eval("7 % 3"), number(1));
+        assert_eq!(eval("7 / 2 * 2"), number(7));
+        assert_eq!(eval("1.5 * 4"), number(6));
+    }
+
+    #[test]
+    fn references() {
+        assert_eq!(eval("1 + strength"), number(15));
+        assert_eq!(eval("$strength + $level"), number(17));
+        assert_eq!(eval("@ + $level"), number(5));
+        assert_eq!(eval("$@+$level"), number(5));
+        assert_eq!(eval("Rage.uses * 2"), number(4));
+        assert_eq!(
+            eval("1 + dexterity"),
+            Err(ScriptError {
+                position: 4,
+                message: "`dexterity` is not a dependency of this script".to_string()
+            })
+        );
+        assert_eq!(
+            eval("broken * 2"),
+            Err(ScriptError {
+                position: 0,
+                message: "`broken` has no value".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn current_value() {
+        assert!(references_current_value("@ + 1"));
+        assert!(references_current_value("max($@, strength)"));
+        assert!(!references_current_value("strength + 1"));
+        assert!(!references_current_value("1 +"));
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(eval("floor((strength - 11) / 2)"), number(1));
+        assert_eq!(eval("floor(-1 / 2)"), number(-1));
+        assert_eq!(eval("ceil(level / 2)"), number(2));
+        assert_eq!(eval("round(5 / 2)"), number(3));
+        assert_eq!(eval("round(-5 / 2)"), number(-3));
+        assert_eq!(
+            eval("round(9223372036854775806 / 9223372036854775807)"),
+            number(1)
+        );
+        assert_eq!(eval("abs(2 - strength)"), number(12));
+        assert_eq!(eval("min(strength, 10, level)"), number(3));
+        assert_eq!(eval("max(strength, 10, level)"), number(14));
+        assert_eq!(
+            eval("min()").unwrap_err().message,
+            "`min` expects at least 1 argument"
+        );
+        assert_eq!(
+            eval("sqrt(4)").unwrap_err().message,
+            "Unknown function `sqrt`"
+        );
+    }
+
+    #[test]
+    fn conditionals() {
+        assert_eq!(eval("level >= 3 ? 1 : 2"), number(1));
+        assert_eq!(eval("level > 3 ? 1 : 2"), number(2));
+        assert_eq!(
+            eval("level == 3 and not (strength < 10) ? 1 : 2"),
+            number(1)
+        );
+        assert_eq!(eval("level != 3 || false ? 1 : 2"), number(2));
+        assert_eq!(eval("level > 1 ? level > 2 ? 3 : 2 : 1"), number(3));
+        assert_eq!(eval("level > 5 and 1 / 0 > 1 ? 1 : 2"), number(2));
+        assert_eq!(
+            eval("level ? 1 : 2").unwrap_err().message,
+            "The condition must be a boolean, but is a number"
+        );
+    }
+
+    #[test]
+    fn dice_arithmetic() {
+        assert_eq!(eval("2d6"), dice("2d6"));
+        assert_eq!(eval("4d6kh3+2"), dice("4d6kh3+2"));
+        assert_eq!(eval("weapon + floor((strength - 10) / 2)"), dice("1d8+2"));
+        assert_eq!(eval("level - weapon"), dice("-1d8+3"));
+        assert_eq!(eval("weapon + 2d6 + 1d8 - 1"), dice("2d8+2d6-1"));
+        assert_eq!(eval("weapon - 1d8"), dice("1d8-1d8"));
+        assert_eq!(eval("2d20kh1 + 1d20kh1"), dice("2d20kh1+1d20kh1"));
+        assert_eq!(eval("2d10c>7 + 3d10c>7"), dice("5d10c>7"));
+        assert_eq!(eval("(weapon + 3) * 2"), dice("2d8+3"));
+        assert_eq!(eval("level * (2d6 - 1d4)"), dice("6d6-3d4"));
+        assert_eq!(eval("-(weapon + 1)"), dice("-1d8-1"));
+        assert_eq!(eval("weapon == 1d8"), Ok(Value::Bool(true)));
+        assert_eq!(
+            eval("1d6 > 2 ? 1 : 2").unwrap_err().message,
+            "`>` can not be applied to a dice value and a number"
+        );
+
+        assert_eq!(
+            eval("weapon * 1d6"),
+            Err(ScriptError {
+                position: 7,
+                message: "Dice values can not be multiplied with each other".to_string()
+            })
+        );
+        assert_eq!(
+            eval("weapon / 2").unwrap_err().message,
+            "`/` can not be applied to dice values, only `+`, `-` and `*`"
+        );
+        assert_eq!(
+            eval("weapon + 1 / 2").unwrap_err().message,
+            "Dice can only be combined with whole numbers, but got 0.5"
+        );
+        assert_eq!(
+            eval("max(weapon, 2)").unwrap_err().message,
+            "`max` expects numbers, but got a dice value"
+        );
+        assert_eq!(
+            eval("1 + 2d6k"),
+            Err(ScriptError {
+                position: 8,
+                message: "Invalid dice: Unexpected end of input, expected a selector".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            eval("1 +"),
+            Err(ScriptError {
+                position: 3,
+                message: "Unexpected end of script, expected a value".to_string()
+            })
+        );
+        assert_eq!(
+            eval("(1 + 2"),
+            Err(ScriptError {
+                position: 6,
+                message: "Unexpected end of script, expected `)`".to_string()
+            })
+        );
+        assert_eq!(
+            eval("1 2"),
+            Err(ScriptError {
+                position: 2,
+                message: "Unexpected number `2` after the end of the expression".to_string()
+            })
+        );
+        assert_eq!(
+            eval("level = 3"),
+            Err(ScriptError {
+                position: 6,
+                message: "Unexpected character `=`, did you mean `==`?".to_string()
+            })
+        );
+        assert_eq!(
+            eval("4 / (level - 3)"),
+            Err(ScriptError {
+                position: 2,
+                message: "Division by zero".to_string()
+            })
+        );
+        assert_eq!(
+            eval("true + 1").unwrap_err().message,
+            "`+` can not be applied to a boolean and a number"
+        );
+        assert_eq!(
+            eval("9223372036854775807 + 1").unwrap_err().message,
+            "The calculation overflowed"
+        );
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let nested = |open: &str, depth: usize, close: &str| {
+            format!("{}1{}", open.repeat(depth), close.repeat(depth))
+        };
+        assert_eq!(eval(&nested("(", MAX_NESTING, ")")), number(1));
+        assert_eq!(eval(&nested("abs(", MAX_NESTING, ")")), number(1));
+        assert_eq!(
+            eval(&nested("(", MAX_NESTING + 1, ")")),
+            Err(ScriptError {
+                position: MAX_NESTING + 1,
+                message: "Expressions can not be nested more than 64 levels deep".to_string()
+            })
+        );
+        for script in [
+            nested("(", 100_000, ")"),
+            nested("max(", 100_000, ")"),
+            nested("-", 100_000, ""),
+            nested("true ? ", 100_000, " : 2"),
+        ] {
+            assert_eq!(
+                eval(&script).unwrap_err().message,
+                "Expressions can not be nested more than 64 levels deep"
+            );
+        }
+    }
+}
@@ -0,0 +1,458 @@
+//! Selectors combine the values of all modifiers of a property into a single value.
+//!
+//! The selector of a property is specified by its
+//! [`PropertyDefinition`](types::character_sheet_collection::PropertyDefinition).
+//! Properties without a definition (or with an empty selector identifier) use `last`.
+//!
+//! # Built-in selectors
+//!
+//! * `highest [n]` - sum of the `n` highest values (default: 1)
+//! * `lowest [n]` - sum of the `n` lowest values (default: 1)
+//! * `first [n]` - sum of the first `n` values (default: 1)
+//! * `last [n]` - sum of the last `n` values (default: 1)
+//! * `sum` - sum of all values
+//! * `average [down|up|nearest|towardZero]` - average of all values, rounded with the
+//!   [`RoundingMode`](types::character_sheet_collection::RoundingMode) (default: down)
+//! * `count` - the amount of values
+//!
+//! All built-in selectors except for `average` keep fractions as they are.
+//!
+//! Additional selectors can be added by implementing [`ValueSelector`] and registering them in a
+//! [`SelectorRegistry`].
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use types::character_sheet_collection::{RoundingMode, StaticValueType};
+use types::fraction::Fraction;
+
+/// The selector used if a property has none specified.
+pub const DEFAULT_SELECTOR: &str = "last";
+
+/// Selects the value of a property out of the values of all of its modifiers.
+pub trait ValueSelector {
+    /// Combines the values into one.
+    /// `values` is never empty and ordered like the features that provide them.
+    ///
+    /// Returns a message describing the problem if the values or arguments are not supported.
+    fn select(
+        &self,
+        values: Vec<StaticValueType>,
+        arguments: &[String],
+    ) -> Result<StaticValueType, String>;
+}
+
+/// Errors during the selection of a value.
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectorError {
+    /// No selector with the given identifier is registered.
+    Unknown(String),
+    /// The selector could not combine the values.
+    Failed { selector: String, message: String },
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectorError::Unknown(identifier) => write!(f, "Unknown selector `{}`", identifier),
+            SelectorError::Failed { selector, message } => {
+                write!(f, "Selector `{}` failed: {}", selector, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SelectorError {}
+
+/// All selectors that are available during a calculation, by their identifier.
+pub struct SelectorRegistry {
+    selectors: HashMap<String, Box<dyn ValueSelector>>,
+}
+
+impl SelectorRegistry {
+    /// Creates a registry without any selectors.
+    pub fn empty() -> SelectorRegistry {
+        return SelectorRegistry {
+            selectors: HashMap::new(),
+        };
+    }
+
+    /// Creates a registry with all built-in selectors.
+    pub fn new() -> SelectorRegistry {
+        let mut registry = SelectorRegistry::empty();
+        registry.register("highest", Highest);
+        registry.register("lowest", Lowest);
+        registry.register("first", First);
+        registry.register("last", Last);
+        registry.register("sum", Sum);
+        registry.register("average", Average);
+        registry.register("count", Count);
+        return registry;
+    }
+
+    /// Registers a selector under the given identifier.
+    /// Replaces any selector that was previously registered with the same identifier.
+    pub fn register(&mut self, identifier: &str, selector: impl ValueSelector + 'static) {
+        self.selectors
+            .insert(identifier.to_string(), Box::new(selector));
+    }
+
+    pub fn get(&self, identifier: &str) -> Option<&dyn ValueSelector> {
+        return self.selectors.get(identifier).map(|s| s.as_ref());
+    }
+
+    /// Selects a value with the selector of the given identifier.
+    /// An empty identifier uses the [`DEFAULT_SELECTOR`].
+    pub fn select(
+        &self,
+        identifier: &str,
+        arguments: &[String],
+        values: Vec<StaticValueType>,
+    ) -> Result<StaticValueType, SelectorError> {
+        let identifier = if identifier.is_empty() {
+            DEFAULT_SELECTOR
+        } else {
+            identifier
+        };
+        let selector = self
+            .get(identifier)
+            .ok_or_else(|| SelectorError::Unknown(identifier.to_string()))?;
+        return selector
+            .select(values, arguments)
+            .map_err(|message| SelectorError::Failed {
+                selector: identifier.to_string(),
+                message,
+            });
+    }
+}
+
+impl Default for SelectorRegistry {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl fmt::Debug for SelectorRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut identifiers: Vec<&String> = self.selectors.keys().collect();
+        identifiers.sort();
+        f.debug_struct("SelectorRegistry")
+            .field("selectors", &identifiers)
+            .finish()
+    }
+}
+
+/// Returns the values as numbers or an error if any of them is not a number.
+/// Whole numbers are returned as fractions, too.
+pub fn numbers(values: Vec<StaticValueType>) -> Result<Vec<Fraction>, String> {
+    return values
+        .into_iter()
+        .map(|value| match value {
+            StaticValueType::Number(n) => Ok(Fraction::from(n)),
+            StaticValueType::Fraction(f) => Ok(f),
+            StaticValueType::Dice(_) => Err("only numbers can be combined".to_string()),
+        })
+        .collect();
+}
+
+/// Converts a number back into a value, whole numbers must fit into a
+/// [`StaticValueType::Number`].
+pub fn number_value(n: Fraction) -> Result<StaticValueType, String> {
+    if n.is_integer() && i32::try_from(n.numerator()).is_err() {
+        return Err(format!("the value {} is too large", n));
+    }
+    return Ok(n.into());
+}
+
+/// Parses the optional count argument of the built-in selectors.
+fn count_argument(arguments: &[String]) -> Result<usize, String> {
+    return match arguments {
+        [] => Ok(1),
+        [count] => match count.parse::<usize>() {
+            Ok(count) if count > 0 => Ok(count),
+            _ => Err(format!("`{}` is not a positive number", count)),
+        },
+        _ => Err(format!(
+            "expected at most 1 argument, got {}",
+            arguments.len()
+        )),
+    };
+}
+
+fn no_arguments(arguments: &[String]) -> Result<(), String> {
+    if arguments.is_empty() {
+        return Ok(());
+    }
+    return Err(format!("expected no arguments, got {}", arguments.len()));
+}
+
+fn sum(numbers: impl IntoIterator<Item = Fraction>) -> Result<StaticValueType, String> {
+    let sum = numbers
+        .into_iter()
+        .try_fold(Fraction::from_integer(0), |acc, n| acc.checked_add(n))
+        .ok_or_else(|| "the sum is too large".to_string())?;
+    return number_value(sum).map_err(|_| "the sum is too large".to_string());
+}
+
+/// Sums up the `n` values at the end of the vector.
+/// Without a count, the value is kept as it is, so that e.g. dice values can be selected, too.
+fn take_from_end(
+    mut values: Vec<StaticValueType>,
+    arguments: &[String],
+) -> Result<StaticValueType, String> {
+    let count = count_argument(arguments)?;
+    if count == 1 {
+        return Ok(values.pop().unwrap_or_default());
+    }
+    let numbers = numbers(values)?;
+    return sum(numbers.into_iter().rev().take(count));
+}
+
+struct Highest;
+
+impl ValueSelector for Highest {
+    fn select(
+        &self,
+        values: Vec<StaticValueType>,
+        arguments: &[String],
+    ) -> Result<StaticValueType, String> {
+        let count = count_argument(arguments)?;
+        let mut numbers = numbers(values)?;
+        numbers.sort_unstable_by(|a, b| b.cmp(a));
+        return sum(numbers.into_iter().take(count));
+    }
+}
+
+struct Lowest;
+
+impl ValueSelector for Lowest {
+    fn select(
+        &self,
+        values: Vec<StaticValueType>,
+        arguments: &[String],
+    ) -> Result<StaticValueType, String> {
+        let count = count_argument(arguments)?;
+        let mut numbers = numbers(values)?;
+        numbers.sort_unstable();
+        return sum(numbers.into_iter().take(count));
+    }
+}
+
+struct First;
+
+impl ValueSelector for First {
+    fn select(
+        &self,
+        mut values: Vec<StaticValueType>,
+        arguments: &[String],
+    ) -> Result<StaticValueType, String> {
+        values.reverse();
+        return take_from_end(values, arguments);
+    }
+}
+
+struct Last;
+
+impl ValueSelector for Last {
+    fn select(
+        &self,
+        values: Vec<StaticValueType>,
+        arguments: &[String],
+    ) -> Result<StaticValueType, String> {
+        return take_from_end(values, arguments);
+    }
+}
+
+struct Sum;
+
+impl ValueSelector for Sum {
+    fn select(
+        &self,
+        values: Vec<StaticValueType>,
+        arguments: &[String],
+    ) -> Result<StaticValueType, String> {
+        no_arguments(arguments)?;
+        return sum(numbers(values)?);
+    }
+}
+
+struct Average;
+
+impl ValueSelector for Average {
+    fn select(
+        &self,
+        values: Vec<StaticValueType>,
+        arguments: &[String],
+    ) -> Result<StaticValueType, String> {
+        let rounding = match arguments {
+            [] => RoundingMode::Down,
+            [rounding] => rounding.parse()?,
+            _ => {
+                return Err(format!(
+                    "expected at most 1 argument, got {}",
+                    arguments.len()
+                ))
+            }
+        };
+        let numbers = numbers(values)?;
+        let count = Fraction::from_integer(numbers.len().max(1) as i64);
+        let average = numbers
+            .into_iter()
+            .try_fold(Fraction::from_integer(0), |acc, n| acc.checked_add(n))
+            .and_then(|total| total.checked_div(count))
+            .ok_or_else(|| "the sum is too large".to_string())?;
+        return number_value(average.round_with(rounding));
+    }
+}
+
+struct Count;
+
+impl ValueSelector for Count {
+    fn select(
+        &self,
+        values: Vec<StaticValueType>,
+        arguments: &[String],
+    ) -> Result<StaticValueType, String> {
+        no_arguments(arguments)?;
+        return i32::try_from(values.len())
+            .map(StaticValueType::Number)
+            .map_err(|_| "too many values".to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::character_sheet_collection::{DiceValue, StaticValueType};
+    use types::fraction::Fraction;
+
+    use super::{SelectorError, SelectorRegistry, ValueSelector};
+
+    fn select(identifier: &str, arguments: &[&str], values: &[i32]) -> Result<i32, SelectorError> {
+        let arguments: Vec<String> = arguments.iter().map(|a| a.to_string()).collect();
+        let values = values.iter().map(|v| StaticValueType::Number(*v)).collect();
+        match SelectorRegistry::new().select(identifier, &arguments, values)? {
+            StaticValueType::Number(n) => Ok(n),
+            other => panic!("Expected a number, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn builtins() {
+        let values = [3, 7, 1, 5];
+        assert_eq!(select("highest", &[], &values), Ok(7));
+        assert_eq!(select("highest", &["2"], &values), Ok(12));
+        assert_eq!(select("lowest", &[], &values), Ok(1));
+        assert_eq!(select("lowest", &["3"], &values), Ok(9));
+        assert_eq!(select("first", &[], &values), Ok(3));
+        assert_eq!(select("first", &["2"], &values), Ok(10));
+        assert_eq!(select("last", &[], &values), Ok(5));
+        assert_eq!(select("last", &["2"], &values), Ok(6));
+        assert_eq!(select("", &[], &values), Ok(5));
+        assert_eq!(select("sum", &[], &values), Ok(16));
+        assert_eq!(select("average", &[], &[1, 2]), Ok(1));
+        assert_eq!(select("average", &["up"], &[1, 2]), Ok(2));
+        assert_eq!(select("average", &["nearest"], &[1, 2, 2]), Ok(2));
+        assert_eq!(select("average", &["down"], &[-1, -2]), Ok(-2));
+        assert_eq!(select("count", &[], &values), Ok(4));
+        assert_eq!(select("average", &["towardZero"], &[-1, -2]), Ok(-1));
+    }
+
+    #[test]
+    fn fractions() {
+        let values: Vec<StaticValueType> = ["1.5", "2", "0.25"]
+            .iter()
+            .map(|v| StaticValueType::from(v.parse::<Fraction>().unwrap()))
+            .collect();
+        let registry = SelectorRegistry::new();
+        let select = |identifier: &str, arguments: &[&str]| {
+            let arguments: Vec<String> = arguments.iter().map(|a| a.to_string()).collect();
+            registry.select(identifier, &arguments, values.clone())
+        };
+        let fraction = |value: &str| Ok(StaticValueType::Fraction(value.parse().unwrap()));
+        assert_eq!(select("sum", &[]), fraction("3.75"));
+        assert_eq!(select("lowest", &["2"]), fraction("1.75"));
+        assert_eq!(select("highest", &["2"]), fraction("3.5"));
+        assert_eq!(
+            select("average", &["nearest"]),
+            Ok(StaticValueType::Number(1))
+        );
+        assert_eq!(select("average", &["up"]), Ok(StaticValueType::Number(2)));
+        assert_eq!(
+            select("average", &["half"]),
+            Err(SelectorError::Failed {
+                selector: "average".to_string(),
+                message:
+                    "unknown rounding `half`, expected `down`, `up`, `nearest` or `towardZero`"
+                        .to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            select("median", &[], &[1]),
+            Err(SelectorError::Unknown("median".to_string()))
+        );
+        assert_eq!(
+            select("highest", &["0"], &[1]),
+            Err(SelectorError::Failed {
+                selector: "highest".to_string(),
+                message: "`0` is not a positive number".to_string()
+            })
+        );
+        assert_eq!(
+            select("sum", &["1"], &[1]),
+            Err(SelectorError::Failed {
+                selector: "sum".to_string(),
+                message: "expected no arguments, got 1".to_string()
+            })
+        );
+
+        let dice = StaticValueType::Dice(DiceValue::default());
+        let registry = SelectorRegistry::new();
+        assert_eq!(
+            registry.select("last", &[], vec![StaticValueType::Number(1), dice.clone()]),
+            Ok(dice.clone()),
+            "Dice values can be selected as they are."
+        );
+        assert!(registry
+            .select("highest", &[], vec![StaticValueType::Number(1), dice])
+            .is_err());
+    }
+
+    #[test]
+    fn custom_selector() {
+        struct Second;
+        impl ValueSelector for Second {
+            fn select(
+                &self,
+                values: Vec<StaticValueType>,
+                _arguments: &[String],
+            ) -> Result<StaticValueType, String> {
+                values
+                    .into_iter()
+                    .nth(1)
+                    .ok_or_else(|| "needs two values".to_string())
+            }
+        }
+
+        let mut registry = SelectorRegistry::new();
+        registry.register("second", Second);
+        assert_eq!(
+            registry.select(
+                "second",
+                &[],
+                vec![StaticValueType::Number(1), StaticValueType::Number(2)]
+            ),
+            Ok(StaticValueType::Number(2))
+        );
+    }
+}
@@ -1,5 +1,8 @@
+#![allow(clippy::needless_return)]
+
 mod utils;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
 
 use engine::CharacterSheet;
@@ -14,23 +17,21 @@ extern "C" {
     fn logS(s: String);
 }
 
-// global hashmap of identifiers -> CharacterSheet instances
-static mut CHARSHEETS: Option<HashMap<String, CharacterSheet>> = Option::None;
+thread_local! {
+    // global hashmap of identifiers -> CharacterSheet instances
+    static CHARSHEETS: RefCell<HashMap<String, CharacterSheet>> = RefCell::new(HashMap::new());
+}
 
-fn get_charsheet(name: &str) -> Option<&mut CharacterSheet> {
-    let hashmap: &mut HashMap<String, CharacterSheet>;
-    unsafe {
-        hashmap = CHARSHEETS.get_or_insert_with(|| HashMap::new());
-    }
-    return hashmap.get_mut(name);
+fn with_charsheet<T>(name: &str, f: impl FnOnce(Option<&mut CharacterSheet>) -> T) -> T {
+    return CHARSHEETS.with(|charsheets| f(charsheets.borrow_mut().get_mut(name)));
 }
 
 fn set_charsheet(name: &str, new_charsheet: CharacterSheet) {
-    let hashmap: &mut HashMap<String, CharacterSheet>;
-    unsafe {
-        hashmap = CHARSHEETS.get_or_insert_with(|| HashMap::new());
-    }
-    hashmap.insert(name.to_string(), new_charsheet);
+    CHARSHEETS.with(|charsheets| {
+        charsheets
+            .borrow_mut()
+            .insert(name.to_string(), new_charsheet)
+    });
 }
 
 #[wasm_bindgen(start)]
@@ -58,15 +59,15 @@ pub fn create_from_json(name: &str, json: &str) -> JsValue {
 
 #[wasm_bindgen(js_name = "getAsJson")]
 pub fn get_as_json(name: &str) -> String {
-    match get_charsheet(name) {
+    with_charsheet(name, |charsheet| match charsheet {
         None => return "null".to_string(),
         Some(charsheet) => return as_string(charsheet),
-    }
+    })
 }
 
 #[wasm_bindgen(js_name = "findMinimumRequiredUserValues")]
 pub fn find_minimum_required_user_values(name: &str) -> Vec<String> {
-    match get_charsheet(name) {
+    with_charsheet(name, |charsheet| match charsheet {
         None => return vec![],
         Some(charsheet) => {
             let mut uvals: Vec<String> = charsheet
@@ -76,20 +77,20 @@ pub fn find_minimum_required_user_values(name: &str) -> Vec<String> {
             uvals.sort();
             return uvals;
         }
-    }
+    })
 }
 
 #[wasm_bindgen(js_name = "calculateAllValuesAsJson")]
 pub fn calculate_all_values_as_json(name: &str) -> String {
-    match get_charsheet(name) {
+    with_charsheet(name, |charsheet| match charsheet {
         None => return as_string(&Result::<&str, &str>::Ok("{}")),
         Some(charsheet) => return as_string(&charsheet.calculate_all_values()),
-    }
+    })
 }
 
 #[wasm_bindgen(js_name = "setUserValueFromJson")]
 pub fn set_user_value_from_json(cs_name: &str, value_name: &str, value_value_as_json: &str) -> JsValue {
-    match get_charsheet(cs_name) {
+    with_charsheet(cs_name, |charsheet| match charsheet {
         None => return JsValue::FALSE,
         Some(charsheet) => match serde_json::from_str(value_value_as_json) {
             Ok(user_values) => {
@@ -98,5 +99,5 @@ pub fn set_user_value_from_json(cs_name: &str, value_name: &str, value_value_as_
             }
             Err(err) => return JsValue::from_str(&("serde_json: ".to_string() + &err.to_string())),
         },
-    }
+    })
 }
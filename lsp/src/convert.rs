@@ -0,0 +1,88 @@
+//! Conversions between the offsets and paths of the parser and the positions and URIs of LSP.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use lsp_types::{Location, Position, Range, Uri};
+use url::Url;
+
+use character_sheet_parser::span::Span;
+
+/// The position of a byte offset. Characters are counted in UTF-16 code units, like LSP does by
+/// default.
+pub fn position(text: &str, offset: usize) -> Position {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    Position {
+        line: text[..line_start].matches('\n').count() as u32,
+        character: text[line_start..offset].encode_utf16().count() as u32,
+    }
+}
+
+/// The byte offset of a position. Positions past the end of their line are moved to its end.
+pub fn offset(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let line = text[line_start..].split('\n').next().unwrap_or_default();
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= position.character as usize {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    line_start + line.len()
+}
+
+pub fn range(text: &str, span: Span) -> Range {
+    Range::new(position(text, span.start), position(text, span.end))
+}
+
+pub fn location(path: &Path, text: &str, span: Span) -> Option<Location> {
+    Some(Location::new(uri(path)?, range(text, span)))
+}
+
+/// The path of a `file://` URI.
+pub fn path(uri: &Uri) -> Option<PathBuf> {
+    Url::parse(uri.as_str()).ok()?.to_file_path().ok()
+}
+
+/// The `file://` URI of an absolute path.
+pub fn uri(path: &Path) -> Option<Uri> {
+    Uri::from_str(Url::from_file_path(path).ok()?.as_str()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::Position;
+
+    use super::{offset, position};
+
+    #[test]
+    fn positions() {
+        let text = "Name: \"Zwölf 🎲\";\nModifiers:\n  +2 strength;";
+        let strength = text.find("strength").unwrap();
+        assert_eq!(position(text, strength), Position::new(2, 5));
+        assert_eq!(offset(text, Position::new(2, 5)), strength);
+
+        // the dice is a single character, but two UTF-16 code units
+        let quote = text.rfind('"').unwrap();
+        assert_eq!(position(text, quote), Position::new(0, 15));
+        assert_eq!(offset(text, Position::new(0, 15)), quote);
+
+        assert_eq!(
+            offset(text, Position::new(1, 99)),
+            text.find("\n  +2").unwrap()
+        );
+        assert_eq!(offset(text, Position::new(9, 0)), text.len());
+        assert_eq!(position(text, text.len() + 5), Position::new(2, 14));
+    }
+}
@@ -0,0 +1,20 @@
+//! A language server for the DSL, talking to the editor over stdin and stdout.
+
+use std::error::Error;
+
+use lsp_server::Connection;
+use lsp_types::InitializeParams;
+
+mod convert;
+mod server;
+mod workspace;
+
+fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let (connection, io_threads) = Connection::stdio();
+    let params = connection.initialize(serde_json::to_value(server::capabilities())?)?;
+    let params: InitializeParams = serde_json::from_value(params)?;
+    server::Server::new(&params).run(&connection)?;
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
@@ -0,0 +1,671 @@
+//! Answers the requests of the editor and publishes the diagnostics of the open files.
+
+use std::error::Error;
+use std::path::Path;
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as LspNotification, PublishDiagnostics,
+};
+use lsp_types::request::{
+    Completion, DocumentSymbolRequest, Formatting, GotoDefinition, HoverRequest, References,
+    Request as LspRequest,
+};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    Diagnostic, DiagnosticSeverity, DocumentFormattingParams, DocumentSymbol, DocumentSymbolParams,
+    DocumentSymbolResponse, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents,
+    HoverParams, HoverProviderCapability, InitializeParams, Location, MarkupContent, MarkupKind,
+    NumberOrString, OneOf, PublishDiagnosticsParams, ReferenceParams, ServerCapabilities,
+    SymbolKind, TextDocumentPositionParams, TextDocumentSyncCapability, TextDocumentSyncKind,
+    TextEdit, Uri,
+};
+
+use character_sheet_parser::compiler::{compile_with, CompileError};
+use character_sheet_parser::lint::Level;
+use character_sheet_parser::parser::ast::{Reference, AST};
+use character_sheet_parser::parser::parse;
+use character_sheet_parser::resolver::resolve;
+use character_sheet_parser::serializer::{format, FormatOptions};
+use character_sheet_parser::span::Span;
+use character_sheet_parser::tokenizer::lex;
+
+use crate::convert;
+use crate::workspace::{File, Workspace};
+
+/// The words of the DSL that are offered as completions, besides the properties and features.
+const KEYWORDS: [&str; 13] = [
+    "Import",
+    "FeatureSet",
+    "Source",
+    "Name",
+    "Type",
+    "Description",
+    "Definitions",
+    "Modifiers",
+    "bonus",
+    "to",
+    "of",
+    "set",
+    "round",
+];
+
+pub fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        document_formatting_provider: Some(OneOf::Left(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![".".to_string()]),
+            ..CompletionOptions::default()
+        }),
+        ..ServerCapabilities::default()
+    }
+}
+
+pub struct Server {
+    workspace: Workspace,
+}
+
+impl Server {
+    /// Loads the DSL files of all workspace folders.
+    pub fn new(params: &InitializeParams) -> Self {
+        let mut workspace = Workspace::default();
+        #[allow(deprecated)] // older clients only send the root
+        let root = params.root_uri.iter();
+        let folders = params.workspace_folders.iter().flatten().map(|f| &f.uri);
+        for folder in folders.chain(root).filter_map(convert::path) {
+            workspace.scan(&folder);
+        }
+        Server { workspace }
+    }
+
+    /// Handles the messages until the editor shuts the server down.
+    pub fn run(&mut self, connection: &Connection) -> Result<(), Box<dyn Error + Sync + Send>> {
+        for message in &connection.receiver {
+            match message {
+                Message::Request(request) => {
+                    if connection.handle_shutdown(&request)? {
+                        return Ok(());
+                    }
+                    connection
+                        .sender
+                        .send(Message::Response(self.request(request)))?;
+                }
+                Message::Notification(notification) => {
+                    for notification in self.notification(notification) {
+                        connection
+                            .sender
+                            .send(Message::Notification(notification))?;
+                    }
+                }
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&self, request: Request) -> Response {
+        match request.method.as_str() {
+            Formatting::METHOD => self.handle::<Formatting>(request, Self::formatting),
+            DocumentSymbolRequest::METHOD => {
+                self.handle::<DocumentSymbolRequest>(request, Self::document_symbols)
+            }
+            GotoDefinition::METHOD => self.handle::<GotoDefinition>(request, Self::definition),
+            References::METHOD => self.handle::<References>(request, Self::references),
+            HoverRequest::METHOD => self.handle::<HoverRequest>(request, Self::hover),
+            Completion::METHOD => self.handle::<Completion>(request, Self::completion),
+            method => Response::new_err(
+                request.id,
+                ErrorCode::MethodNotFound as i32,
+                format!("Unsupported request {}", method),
+            ),
+        }
+    }
+
+    fn handle<R: LspRequest>(
+        &self,
+        request: Request,
+        handler: fn(&Self, R::Params) -> R::Result,
+    ) -> Response {
+        match serde_json::from_value(request.params) {
+            Ok(params) => Response::new_ok(request.id, handler(self, params)),
+            Err(error) => Response::new_err(
+                request.id,
+                ErrorCode::InvalidParams as i32,
+                error.to_string(),
+            ),
+        }
+    }
+
+    /// Updates the workspace and returns the diagnostics to publish.
+    fn notification(&mut self, notification: Notification) -> Vec<Notification> {
+        let mut closed = None;
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let Some(params) = params::<DidOpenTextDocument>(notification) else {
+                    return vec![];
+                };
+                let document = params.text_document;
+                let Some(path) = convert::path(&document.uri) else {
+                    return vec![];
+                };
+                self.workspace.open(path, document.text);
+            }
+            DidChangeTextDocument::METHOD => {
+                let Some(params) = params::<DidChangeTextDocument>(notification) else {
+                    return vec![];
+                };
+                // the whole text is synchronized, so the last change contains all of it
+                let (Some(path), Some(change)) = (
+                    convert::path(&params.text_document.uri),
+                    params.content_changes.into_iter().last(),
+                ) else {
+                    return vec![];
+                };
+                self.workspace.open(path, change.text);
+            }
+            DidCloseTextDocument::METHOD => {
+                let Some(params) = params::<DidCloseTextDocument>(notification) else {
+                    return vec![];
+                };
+                let uri = params.text_document.uri;
+                let Some(path) = convert::path(&uri) else {
+                    return vec![];
+                };
+                self.workspace.close(&path);
+                closed = Some(uri);
+            }
+            _ => return vec![],
+        }
+
+        // a change can also fix or break the files that import the changed one
+        let mut notifications: Vec<_> = self
+            .workspace
+            .open_files()
+            .filter_map(|path| Some(publish(convert::uri(path)?, self.diagnostics(path))))
+            .collect();
+        if let Some(uri) = closed {
+            notifications.push(publish(uri, vec![]));
+        }
+        notifications
+    }
+
+    /// The errors of the file and its imports, the lints and the errors of the compiler.
+    fn diagnostics(&self, path: &Path) -> Vec<Diagnostic> {
+        let Some(file) = self.workspace.file(path) else {
+            return vec![];
+        };
+        let text = &file.text;
+        let resolution = resolve(path, &self.workspace);
+        let mut diagnostics = vec![];
+        for error in resolution.errors.iter().filter(|e| e.path == path) {
+            let message = error.error.to_string();
+            let span = error.error.span();
+            diagnostics.push(diagnostic(
+                text,
+                span,
+                DiagnosticSeverity::ERROR,
+                None,
+                message,
+            ));
+        }
+        let Some(module) = resolution.modules.iter().find(|m| m.path == path) else {
+            return diagnostics;
+        };
+        for lint in module.warnings.iter().chain(&module.infos) {
+            let severity = match lint.level {
+                Level::Deny => DiagnosticSeverity::ERROR,
+                Level::Warning => DiagnosticSeverity::WARNING,
+                Level::Info | Level::Allow => DiagnosticSeverity::INFORMATION,
+            };
+            let code = Some(lint.code.to_string());
+            diagnostics.push(diagnostic(
+                text,
+                lint.span,
+                severity,
+                code,
+                lint.message.clone(),
+            ));
+        }
+        for error in compile_with(&module.ast, &module.imported_features).errors {
+            let span = compile_error_span(&module.ast, &error);
+            let message = error.to_string();
+            diagnostics.push(diagnostic(
+                text,
+                span,
+                DiagnosticSeverity::ERROR,
+                None,
+                message,
+            ));
+        }
+        diagnostics
+    }
+
+    fn formatting(&self, params: DocumentFormattingParams) -> Option<Vec<TextEdit>> {
+        let file = self.file(&params.text_document.uri)?;
+        let ast = parse(&lex(&file.text)).ok()?.ast;
+        let options = FormatOptions {
+            indent_width: params.options.tab_size as usize,
+            use_tabs: !params.options.insert_spaces,
+            ..FormatOptions::default()
+        };
+        let formatted = format(&ast, &file.text, &options);
+        if formatted == file.text {
+            return Some(vec![]);
+        }
+        let whole = Span::new(0, file.text.len());
+        Some(vec![TextEdit::new(
+            convert::range(&file.text, whole),
+            formatted,
+        )])
+    }
+
+    /// One symbol per feature, with its definitions and modifiers as children.
+    fn document_symbols(&self, params: DocumentSymbolParams) -> Option<DocumentSymbolResponse> {
+        let file = self.file(&params.text_document.uri)?;
+        let text = &file.text;
+        let symbols = file.ast.model.features.iter().map(|feature| {
+            let definitions = feature.definitions.iter().map(|d| {
+                let range = convert::range(text, d.span);
+                let selection = convert::range(text, d.property.span);
+                symbol(
+                    d.property.qualified_name(),
+                    None,
+                    SymbolKind::PROPERTY,
+                    range,
+                    selection,
+                )
+            });
+            let modifiers = feature.modifiers.iter().map(|m| {
+                let range = convert::range(text, m.span);
+                let selection = convert::range(text, m.referencing.span);
+                let detail = text.get(m.span.start..m.span.end).map(str::to_string);
+                symbol(
+                    m.referencing.qualified_name(),
+                    detail,
+                    SymbolKind::FIELD,
+                    range,
+                    selection,
+                )
+            });
+            let range = convert::range(text, feature.span);
+            let detail = Some(feature.base_type.clone()).filter(|t| !t.is_empty());
+            DocumentSymbol {
+                children: Some(definitions.chain(modifiers).collect()),
+                ..symbol(
+                    feature.name.clone(),
+                    detail,
+                    SymbolKind::CLASS,
+                    range,
+                    range,
+                )
+            }
+        });
+        Some(DocumentSymbolResponse::Nested(symbols.collect()))
+    }
+
+    /// The definitions of the property, or the modifiers of it if it has no definition.
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let (_, reference) = self.reference_at(&params.text_document_position_params)?;
+        let name = reference.qualified_name();
+        let mut locations: Vec<Location> = self
+            .workspace
+            .definitions(&name)
+            .iter()
+            .filter_map(|d| convert::location(d.path, &d.file.text, d.node.property.span))
+            .collect();
+        if locations.is_empty() {
+            locations = self
+                .workspace
+                .modifiers(&name)
+                .iter()
+                .filter_map(|m| convert::location(m.path, &m.file.text, m.node.referencing.span))
+                .collect();
+        }
+        if locations.is_empty() {
+            return None;
+        }
+        Some(GotoDefinitionResponse::Array(locations))
+    }
+
+    fn references(&self, params: ReferenceParams) -> Option<Vec<Location>> {
+        let (_, reference) = self.reference_at(&params.text_document_position)?;
+        let name = reference.qualified_name();
+        let definitions = self.workspace.definitions(&name);
+        let is_definition = |path: &Path, span: Span| {
+            definitions
+                .iter()
+                .any(|d| d.path == path && d.node.property.span == span)
+        };
+        let locations = self
+            .workspace
+            .references(&name)
+            .into_iter()
+            .filter(|(path, _, span)| {
+                params.context.include_declaration || !is_definition(path, *span)
+            })
+            .filter_map(|(path, file, span)| convert::location(path, &file.text, span))
+            .collect();
+        Some(locations)
+    }
+
+    /// The definitions of the property and the features that modify it.
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let (file, reference) = self.reference_at(&params.text_document_position_params)?;
+        let name = reference.qualified_name();
+        let mut sections = vec![];
+        for definition in self.workspace.definitions(&name) {
+            let span = definition.node.span;
+            let source = definition.file.text.get(span.start..span.end)?;
+            sections.push(format!(
+                "```\n{}\n```\nDefined by `{}` in {}",
+                source,
+                definition.feature.name,
+                file_name(definition.path)
+            ));
+        }
+        let modifiers: Vec<String> = self
+            .workspace
+            .modifiers(&name)
+            .iter()
+            .filter_map(|m| {
+                let source = m.file.text.get(m.node.span.start..m.node.span.end)?;
+                Some(format!("* `{}` by `{}`", source, m.feature.name))
+            })
+            .collect();
+        if !modifiers.is_empty() {
+            sections.push(format!("Modified by:\n{}", modifiers.join("\n")));
+        }
+        if sections.is_empty() {
+            sections.push(format!("`{}` is neither defined nor modified", name));
+        }
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: sections.join("\n\n---\n\n"),
+            }),
+            range: Some(convert::range(&file.text, reference.span)),
+        })
+    }
+
+    /// The keywords, the properties and the features of the workspace.
+    fn completion(&self, _params: CompletionParams) -> Option<CompletionResponse> {
+        let item = |label: &str, kind, detail: &str| CompletionItem {
+            label: label.to_string(),
+            kind: Some(kind),
+            detail: Some(detail.to_string()),
+            ..CompletionItem::default()
+        };
+        let keywords = KEYWORDS
+            .iter()
+            .map(|k| item(k, CompletionItemKind::KEYWORD, "keyword"));
+        let properties = self.workspace.property_names();
+        let properties = properties
+            .iter()
+            .map(|p| item(p, CompletionItemKind::PROPERTY, "property"));
+        let features = self.workspace.feature_names();
+        let features = features
+            .iter()
+            .map(|f| item(f, CompletionItemKind::CLASS, "feature"));
+        Some(CompletionResponse::Array(
+            keywords.chain(properties).chain(features).collect(),
+        ))
+    }
+
+    fn file(&self, uri: &Uri) -> Option<&File> {
+        self.workspace.file(&convert::path(uri)?)
+    }
+
+    /// The property under the cursor.
+    fn reference_at(&self, params: &TextDocumentPositionParams) -> Option<(&File, &Reference)> {
+        let file = self.file(&params.text_document.uri)?;
+        let offset = convert::offset(&file.text, params.position);
+        Some((file, file.reference_at(offset)?))
+    }
+}
+
+fn params<N: LspNotification>(notification: Notification) -> Option<N::Params> {
+    serde_json::from_value(notification.params).ok()
+}
+
+fn publish(uri: Uri, diagnostics: Vec<Diagnostic>) -> Notification {
+    let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+    Notification::new(PublishDiagnostics::METHOD.to_string(), params)
+}
+
+fn diagnostic(
+    text: &str,
+    span: Span,
+    severity: DiagnosticSeverity,
+    code: Option<String>,
+    message: String,
+) -> Diagnostic {
+    Diagnostic {
+        range: convert::range(text, span),
+        severity: Some(severity),
+        code: code.map(NumberOrString::String),
+        source: Some("character-sheet".to_string()),
+        message,
+        ..Diagnostic::default()
+    }
+}
+
+fn symbol(
+    name: String,
+    detail: Option<String>,
+    kind: SymbolKind,
+    range: lsp_types::Range,
+    selection_range: lsp_types::Range,
+) -> DocumentSymbol {
+    #[allow(deprecated)] // `deprecated` has to be set, even though tags replace it
+    DocumentSymbol {
+        name,
+        detail,
+        kind,
+        tags: None,
+        deprecated: None,
+        range,
+        selection_range,
+        children: None,
+    }
+}
+
+/// Compile errors have no span, so they are shown at the property they are about.
+fn compile_error_span(ast: &AST, error: &CompileError) -> Span {
+    let name = match error {
+        CompileError::UnknownFeature {
+            owner, property, ..
+        } => format!("{}.{}", owner, property),
+        CompileError::InvalidDependency { dependency, .. } => dependency.clone(),
+    };
+    ast.references
+        .iter()
+        .find(|r| r.qualified_name() == name)
+        .map_or(Span::default(), |r| r.span)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use lsp_server::{Notification, Request, RequestId};
+    use lsp_types::notification::{DidChangeTextDocument, DidOpenTextDocument, Notification as _};
+    use lsp_types::request::{
+        Completion, DocumentSymbolRequest, Formatting, GotoDefinition, HoverRequest, References,
+        Request as LspRequest,
+    };
+    use lsp_types::{
+        CompletionResponse, DiagnosticSeverity, DocumentSymbolResponse, GotoDefinitionResponse,
+        HoverContents, Position, PublishDiagnosticsParams, Uri,
+    };
+    use serde_json::{json, Value};
+
+    use super::Server;
+    use crate::workspace::Workspace;
+
+    const CLASSES: &str = "Name: \"Barbarian\";\nDefinitions:\n  rage_damage;\n";
+    const RACES: &str =
+        "Name: \"Dwarf\";\nModifiers:\n  +2 rage_damage;\n  bonus to strength of 1;\n";
+
+    fn uri(name: &str) -> Uri {
+        Uri::from_str(&format!("file:///project/{}", name)).unwrap()
+    }
+
+    fn server() -> Server {
+        let mut server = Server {
+            workspace: Workspace::default(),
+        };
+        open(&mut server, "classes.cs", CLASSES);
+        open(&mut server, "races.cs", RACES);
+        server
+    }
+
+    fn open(server: &mut Server, name: &str, text: &str) -> Vec<PublishDiagnosticsParams> {
+        let params = json!({
+            "textDocument": { "uri": uri(name), "languageId": "cs", "version": 1, "text": text }
+        });
+        notify(server, DidOpenTextDocument::METHOD, params)
+    }
+
+    fn notify(server: &mut Server, method: &str, params: Value) -> Vec<PublishDiagnosticsParams> {
+        server
+            .notification(Notification::new(method.to_string(), params))
+            .into_iter()
+            .map(|n| serde_json::from_value(n.params).unwrap())
+            .collect()
+    }
+
+    fn request<R: LspRequest>(server: &Server, params: Value) -> R::Result {
+        let request = Request::new(RequestId::from(1), R::METHOD.to_string(), params);
+        let response = server.request(request);
+        assert!(response.error.is_none(), "{:?}", response.error);
+        serde_json::from_value(response.result.unwrap()).unwrap()
+    }
+
+    /// The parameters of a request at the position of the first `needle` in the file.
+    fn at(name: &str, text: &str, needle: &str) -> Value {
+        let offset = text.find(needle).unwrap();
+        let position = crate::convert::position(text, offset);
+        json!({ "textDocument": { "uri": uri(name) }, "position": position })
+    }
+
+    #[test]
+    fn diagnostics_on_change() {
+        let mut server = server();
+        let published = open(
+            &mut server,
+            "broken.cs",
+            "Name: \"Broken\";\nModifiers:\n  +2;\n",
+        );
+        let broken = published
+            .iter()
+            .find(|p| p.uri == uri("broken.cs"))
+            .unwrap();
+        assert_eq!(broken.diagnostics.len(), 1);
+        assert_eq!(
+            broken.diagnostics[0].severity,
+            Some(DiagnosticSeverity::ERROR)
+        );
+        assert_eq!(broken.diagnostics[0].range.start.line, 2);
+
+        let params = json!({
+            "textDocument": { "uri": uri("broken.cs"), "version": 2 },
+            "contentChanges": [{ "text": "Name: \"Fixed\";\nModifiers:\n  +2 strength;\n" }]
+        });
+        let published = notify(&mut server, DidChangeTextDocument::METHOD, params);
+        assert_eq!(published.len(), 3);
+        // only the lint about the missing descriptions is left
+        let mut diagnostics = published.iter().flat_map(|p| &p.diagnostics);
+        assert!(diagnostics.all(|d| d.severity == Some(DiagnosticSeverity::INFORMATION)));
+    }
+
+    #[test]
+    fn definitions_and_references_across_files() {
+        let server = server();
+        let params = at("races.cs", RACES, "rage_damage");
+        let definition = request::<GotoDefinition>(&server, params.clone()).unwrap();
+        let GotoDefinitionResponse::Array(locations) = definition else {
+            panic!("expected an array of locations");
+        };
+        assert_eq!(locations.len(), 1);
+        assert_eq!(locations[0].uri, uri("classes.cs"));
+        assert_eq!(locations[0].range.start, Position::new(2, 2));
+
+        let mut with_declaration = params.clone();
+        with_declaration["context"] = json!({ "includeDeclaration": true });
+        let references = request::<References>(&server, with_declaration).unwrap();
+        assert_eq!(references.len(), 2);
+
+        let mut without_declaration = params;
+        without_declaration["context"] = json!({ "includeDeclaration": false });
+        let references = request::<References>(&server, without_declaration).unwrap();
+        assert_eq!(references.len(), 1);
+        assert_eq!(references[0].uri, uri("races.cs"));
+    }
+
+    #[test]
+    fn hover_shows_the_definition_and_modifiers() {
+        let server = server();
+        let hover = request::<HoverRequest>(&server, at("races.cs", RACES, "rage_damage")).unwrap();
+        let HoverContents::Markup(content) = hover.contents else {
+            panic!("expected markdown");
+        };
+        assert!(content
+            .value
+            .contains("Defined by `Barbarian` in classes.cs"));
+        assert!(content.value.contains("* `+2 rage_damage;` by `Dwarf`"));
+    }
+
+    #[test]
+    fn formatting_symbols_and_completion() {
+        let mut server = server();
+        let text = "Name: \"Elf\";\nModifiers:\n    bonus to dexterity of 2;\n";
+        open(&mut server, "elf.cs", text);
+        let params = json!({
+            "textDocument": { "uri": uri("elf.cs") },
+            "options": { "tabSize": 2, "insertSpaces": true }
+        });
+        let edits = request::<Formatting>(&server, params).unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(
+            edits[0].new_text,
+            "Name: \"Elf\";\nModifiers:\n  bonus to dexterity of 2;\n"
+        );
+
+        let params = json!({ "textDocument": { "uri": uri("races.cs") } });
+        let symbols = request::<DocumentSymbolRequest>(&server, params).unwrap();
+        let DocumentSymbolResponse::Nested(symbols) = symbols else {
+            panic!("expected nested symbols");
+        };
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0].name, "Dwarf");
+        let children: Vec<_> = symbols[0]
+            .children
+            .iter()
+            .flatten()
+            .map(|c| &c.name)
+            .collect();
+        assert_eq!(children, ["rage_damage", "strength"]);
+
+        let completion = request::<Completion>(&server, at("races.cs", RACES, "strength")).unwrap();
+        let CompletionResponse::Array(items) = completion else {
+            panic!("expected an array of completions");
+        };
+        let labels: Vec<_> = items.iter().map(|i| i.label.as_str()).collect();
+        for label in ["Modifiers", "bonus", "rage_damage", "strength", "Barbarian"] {
+            assert!(labels.contains(&label), "{} is missing", label);
+        }
+    }
+}
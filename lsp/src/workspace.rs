@@ -0,0 +1,191 @@
+//! The DSL files of the workspace and the properties used in them.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use character_sheet_parser::parser::ast::{Definition, Feature, Modifier, Reference, AST};
+use character_sheet_parser::parser::parse;
+use character_sheet_parser::resolver::FileLoader;
+use character_sheet_parser::span::Span;
+use character_sheet_parser::tokenizer::lex;
+
+/// The extension of DSL files, e.g. `classes/fighter.cs`.
+pub const EXTENSION: &str = "cs";
+
+/// A parsed DSL file.
+pub struct File {
+    pub text: String,
+    /// If the file has errors, this only contains the features without errors.
+    pub ast: AST,
+    /// Whether the text comes from the editor instead of the file system.
+    pub open: bool,
+}
+
+impl File {
+    fn new(text: String, open: bool) -> Self {
+        let ast = match parse(&lex(&text)) {
+            Ok(success) => success.ast,
+            Err(failure) => failure.ast,
+        };
+        File { text, ast, open }
+    }
+
+    /// The property at the byte offset, e.g. the one under the cursor.
+    pub fn reference_at(&self, offset: usize) -> Option<&Reference> {
+        self.ast
+            .references
+            .iter()
+            .find(|r| r.span.start <= offset && offset <= r.span.end)
+    }
+}
+
+/// A definition or modifier of a property, together with where it was found.
+pub struct Found<'a, T> {
+    pub path: &'a Path,
+    pub file: &'a File,
+    pub feature: &'a Feature,
+    pub node: &'a T,
+}
+
+/// All DSL files of the workspace. Open files have the text of the editor, all others the one
+/// on disk.
+#[derive(Default)]
+pub struct Workspace {
+    files: BTreeMap<PathBuf, File>,
+}
+
+impl Workspace {
+    /// Loads all DSL files in the directory and its subdirectories, except for hidden ones.
+    pub fn scan(&mut self, directory: &Path) {
+        let Ok(entries) = fs::read_dir(directory) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                self.scan(&path);
+            } else if path.extension().is_some_and(|e| e == EXTENSION)
+                && !self.files.contains_key(&path)
+            {
+                if let Ok(text) = fs::read_to_string(&path) {
+                    self.files.insert(path, File::new(text, false));
+                }
+            }
+        }
+    }
+
+    /// Opens the file or updates its text.
+    pub fn open(&mut self, path: PathBuf, text: String) {
+        self.files.insert(path, File::new(text, true));
+    }
+
+    /// Goes back to the text on disk, or forgets the file if it does not exist.
+    pub fn close(&mut self, path: &Path) {
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                self.files
+                    .insert(path.to_path_buf(), File::new(text, false));
+            }
+            Err(_) => {
+                self.files.remove(path);
+            }
+        }
+    }
+
+    pub fn file(&self, path: &Path) -> Option<&File> {
+        self.files.get(path)
+    }
+
+    pub fn open_files(&self) -> impl Iterator<Item = &Path> {
+        self.files
+            .iter()
+            .filter(|(_, file)| file.open)
+            .map(|(path, _)| path.as_path())
+    }
+
+    /// The definitions of the property with the qualified name, e.g. `Rage.uses`.
+    pub fn definitions(&self, name: &str) -> Vec<Found<'_, Definition>> {
+        self.features()
+            .flat_map(|(path, file, feature)| {
+                feature
+                    .definitions
+                    .iter()
+                    .filter(|d| d.property.qualified_name() == name)
+                    .map(move |node| Found {
+                        path,
+                        file,
+                        feature,
+                        node,
+                    })
+            })
+            .collect()
+    }
+
+    /// The modifiers of the property with the qualified name.
+    pub fn modifiers(&self, name: &str) -> Vec<Found<'_, Modifier>> {
+        self.features()
+            .flat_map(|(path, file, feature)| {
+                feature
+                    .modifiers
+                    .iter()
+                    .filter(|m| m.referencing.qualified_name() == name)
+                    .map(move |node| Found {
+                        path,
+                        file,
+                        feature,
+                        node,
+                    })
+            })
+            .collect()
+    }
+
+    /// Everywhere the property with the qualified name is used, including its definitions.
+    pub fn references(&self, name: &str) -> Vec<(&Path, &File, Span)> {
+        self.files
+            .iter()
+            .flat_map(|(path, file)| {
+                file.ast
+                    .references
+                    .iter()
+                    .filter(|r| r.qualified_name() == name)
+                    .map(move |r| (path.as_path(), file, r.span))
+            })
+            .collect()
+    }
+
+    /// The qualified names of all properties used in the workspace.
+    pub fn property_names(&self) -> BTreeSet<String> {
+        self.files
+            .values()
+            .flat_map(|file| file.ast.references.iter().map(|r| r.qualified_name()))
+            .collect()
+    }
+
+    pub fn feature_names(&self) -> BTreeSet<&str> {
+        self.features().map(|(_, _, f)| f.name.as_str()).collect()
+    }
+
+    fn features(&self) -> impl Iterator<Item = (&Path, &File, &Feature)> {
+        self.files.iter().flat_map(|(path, file)| {
+            file.ast
+                .model
+                .features
+                .iter()
+                .map(move |feature| (path.as_path(), file, feature))
+        })
+    }
+}
+
+/// Imports of open files use the text of the editor.
+impl FileLoader for Workspace {
+    fn load(&self, path: &Path) -> Result<String, String> {
+        match self.files.get(path) {
+            Some(file) => Ok(file.text.clone()),
+            None => fs::read_to_string(path).map_err(|e| e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,19 @@
+//! Lexing, parsing and serializing must never panic, whatever the input is.
+//!
+//! Run with `cargo fuzz run lex_parse` in the `parser` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use character_sheet_parser::parser::parse;
+use character_sheet_parser::serializer::serialize;
+use character_sheet_parser::tokenizer::{lex, validate};
+
+fuzz_target!(|input: &str| {
+    let tokens = lex(input);
+    validate(&tokens);
+    if let Ok(success) = parse(&tokens) {
+        serialize(&success.ast);
+    }
+});
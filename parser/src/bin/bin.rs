@@ -1,92 +1,650 @@
-use text_io::read;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Editor, Helper};
+use serde_json::{json, Value};
+
+use engine::{CharacterSheet, ResultValue};
+use types::character_sheet_collection::{DiceValue, FeatureSet, StaticValueType};
+use types::fraction::Fraction;
+
+use character_sheet_parser::compiler;
 use character_sheet_parser::parser;
-use character_sheet_parser::tokenizer;
+use character_sheet_parser::resolver;
 use character_sheet_parser::serializer;
+use character_sheet_parser::span::{LineIndex, Span};
+use character_sheet_parser::tokenizer;
 
+use compiler::compile_with;
 use parser::parse;
+use resolver::{resolve, FileSystemLoader, Resolution};
 use tokenizer::lex;
 use tokenizer::validate;
 
 use parser::ast::AST;
 
-use serializer::serialize;
+use serializer::{format, serialize, FormatOptions, ModifierStyle};
+
+/// Checks, formats and compiles character sheet DSL files.
+///
+/// Without a command the interactive mode is started.
+#[derive(Debug, Parser)]
+#[command(name = "character_sheet_parser_cli")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Checks the files and everything they import, exits with 1 on errors
+    Check {
+        #[arg(long, value_enum, default_value_t = Format::Human)]
+        format: Format,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Formats the files in place
+    Fmt {
+        /// Only reports files that are not formatted, exits with 1 if there are any
+        #[arg(long)]
+        check: bool,
+        #[command(flatten)]
+        options: FormatArgs,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Compiles the files and everything they import into a JSON list of feature sets
+    Compile {
+        /// The file the feature sets are written to, stdout if there is none
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = Format::Human)]
+        format: Format,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Reads the DSL interactively and parses, compiles or evaluates it on command
+    Repl,
+}
+
+/// The [`FormatOptions`] of the `fmt` command, the defaults are used for the missing ones.
+#[derive(Debug, Args)]
+struct FormatArgs {
+    /// The amount of spaces per indentation level
+    #[arg(long)]
+    indent_width: Option<usize>,
+    /// Indent with tabs instead of spaces
+    #[arg(long)]
+    use_tabs: bool,
+    /// The blank lines in front of the `---` between two features
+    #[arg(long)]
+    blank_lines_between_features: Option<usize>,
+    /// The most blank lines that are kept between modifiers, definitions, imports and comments
+    #[arg(long)]
+    max_blank_lines: Option<usize>,
+    /// How bonuses are written
+    #[arg(long, value_enum, default_value_t = ModifierStyleArg::Keep)]
+    modifier_style: ModifierStyleArg,
+    /// Long modifier values are split up to stay within this width
+    #[arg(long)]
+    line_width: Option<usize>,
+}
+
+impl FormatArgs {
+    fn options(&self) -> FormatOptions {
+        let default = FormatOptions::default();
+        FormatOptions {
+            indent_width: self.indent_width.unwrap_or(default.indent_width),
+            use_tabs: self.use_tabs,
+            blank_lines_between_features: self
+                .blank_lines_between_features
+                .unwrap_or(default.blank_lines_between_features),
+            max_blank_lines: self.max_blank_lines.unwrap_or(default.max_blank_lines),
+            modifier_style: match self.modifier_style {
+                ModifierStyleArg::Keep => ModifierStyle::Keep,
+                ModifierStyleArg::Short => ModifierStyle::Short,
+                ModifierStyleArg::Long => ModifierStyle::Long,
+            },
+            line_width: self.line_width.unwrap_or(default.line_width),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ModifierStyleArg {
+    /// As they were written
+    Keep,
+    /// `+2 strength;` wherever possible
+    Short,
+    /// `bonus to strength of 2;` for all bonuses
+    Long,
+}
+
+/// How diagnostics are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Rendered with the source line on stderr
+    Human,
+    /// A JSON list of diagnostics on stdout
+    Json,
+}
+
+/// Collects the diagnostics of all files, so that each one is only reported once, even if the
+/// file is imported by multiple checked files.
+struct Report {
+    format: Format,
+    diagnostics: Vec<Value>,
+    seen: HashSet<String>,
+    has_errors: bool,
+}
+
+impl Report {
+    fn new(format: Format) -> Self {
+        Report {
+            format,
+            diagnostics: vec![],
+            seen: HashSet::new(),
+            has_errors: false,
+        }
+    }
+
+    fn resolution(&mut self, resolution: &Resolution) {
+        for error in &resolution.errors {
+            let source = resolution
+                .modules
+                .iter()
+                .find(|m| m.path == error.path)
+                .map(|m| m.source.as_str());
+            let rendered = resolution.render(error);
+            let message = error.error.to_string();
+            self.add(&rendered, &error.path, source.map(|s| (s, error.error.span())), "error", None, &message);
+        }
+        for module in &resolution.modules {
+            let index = LineIndex::new(&module.source);
+            let file = module.path.display().to_string();
+            for diagnostic in module.warnings.iter().chain(&module.infos) {
+                let rendered = diagnostic.render_in_file(&file, &index);
+                let code = diagnostic.code.to_string();
+                let label = diagnostic.level.label();
+                let position = Some((module.source.as_str(), diagnostic.span));
+                self.add(&rendered, &module.path, position, label, Some(&code), &diagnostic.message);
+            }
+        }
+    }
+
+    fn error(&mut self, file: &Path, message: &str) {
+        let rendered = format!("error: {}\n --> {}", message, file.display());
+        self.add(&rendered, file, None, "error", None, message);
+    }
+
+    fn add(
+        &mut self,
+        rendered: &str,
+        file: &Path,
+        position: Option<(&str, Span)>,
+        severity: &str,
+        code: Option<&str>,
+        message: &str,
+    ) {
+        if !self.seen.insert(rendered.to_string()) {
+            return;
+        }
+        self.has_errors |= severity == "error";
+        match self.format {
+            Format::Human => eprintln!("{}\n", rendered),
+            Format::Json => {
+                let mut diagnostic = json!({
+                    "file": file.display().to_string(),
+                    "severity": severity,
+                    "code": code,
+                    "message": message,
+                });
+                if let Some((source, span)) = position {
+                    let index = LineIndex::new(source);
+                    let (start, end) = (index.line_column(span.start), index.line_column(span.end));
+                    // lines and columns start at 1, like in the rendered diagnostics
+                    diagnostic["line"] = json!(start.line + 1);
+                    diagnostic["column"] = json!(start.column + 1);
+                    diagnostic["endLine"] = json!(end.line + 1);
+                    diagnostic["endColumn"] = json!(end.column + 1);
+                }
+                self.diagnostics.push(diagnostic);
+            }
+        }
+    }
+
+    fn finish(self) -> ExitCode {
+        if self.format == Format::Json {
+            println!("{}", Value::Array(self.diagnostics));
+        }
+        if self.has_errors {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+fn check(files: &[PathBuf], format: Format) -> ExitCode {
+    let mut report = Report::new(format);
+    for file in files {
+        let resolution = resolve(file, &FileSystemLoader);
+        report.resolution(&resolution);
+        for module in &resolution.modules {
+            for error in compile_with(&module.ast, &module.imported_features).errors {
+                report.error(&module.path, &error.to_string());
+            }
+        }
+    }
+    report.finish()
+}
+
+fn fmt(files: &[PathBuf], check: bool, options: &FormatOptions) -> ExitCode {
+    let mut success = true;
+    for file in files {
+        let source = match fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("error: {} can not be read: {}", file.display(), error);
+                success = false;
+                continue;
+            }
+        };
+        let ast = match parse(&lex(&source)) {
+            Ok(success) => success.ast,
+            Err(failure) => {
+                let index = LineIndex::new(&source);
+                let name = file.display().to_string();
+                for error in &failure.errors {
+                    let rendered = index.render_in_file(&name, error.span(), "error", &error.to_string());
+                    eprintln!("{}\n", rendered);
+                }
+                success = false;
+                continue;
+            }
+        };
+
+        let formatted = format(&ast, &source, options);
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", file.display());
+            success = false;
+        } else if let Err(error) = fs::write(file, formatted) {
+            eprintln!("error: {} can not be written: {}", file.display(), error);
+            success = false;
+        }
+    }
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn compile(files: &[PathBuf], output: Option<&Path>, format: Format) -> ExitCode {
+    let mut report = Report::new(format);
+    let mut compiled = HashSet::new();
+    let mut feature_sets = vec![];
+    for file in files {
+        let resolution = resolve(file, &FileSystemLoader);
+        report.resolution(&resolution);
+        // files imported by multiple files are only compiled once
+        for module in resolution.modules.iter().filter(|m| compiled.insert(m.path.clone())) {
+            let mut compilation = compile_with(&module.ast, &module.imported_features);
+            for error in &compilation.errors {
+                report.error(&module.path, &error.to_string());
+            }
+            feature_sets.append(&mut compilation.feature_sets);
+        }
+    }
+    if report.has_errors {
+        return report.finish();
+    }
+
+    let json = serde_json::to_string_pretty(&feature_sets).expect("feature sets can always be serialized");
+    match output {
+        Some(output) => {
+            if let Err(error) = fs::write(output, json + "\n") {
+                report.error(output, &format!("the output can not be written: {}", error));
+            }
+        }
+        None => println!("{}", json),
+    }
+    report.finish()
+}
 
+/// The REPL keeps the last parsed or loaded file, so that it can be serialized, compiled and
+/// evaluated by later commands.
 struct State {
     last_ast: Option<AST>,
+    feature_sets: Vec<FeatureSet>,
+    user_values: HashMap<String, StaticValueType>,
 }
 
 impl State {
     fn new() -> State {
-        State { last_ast: None }
+        State {
+            last_ast: None,
+            feature_sets: vec![],
+            user_values: HashMap::new(),
+        }
+    }
+}
+
+/// Lets the editor continue the input on the next line while a string, comment or parenthesis
+/// is still open.
+struct ReplHelper;
+
+impl Helper for ReplHelper {}
+impl Completer for ReplHelper {
+    type Candidate = String;
+}
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if !ctx.input().starts_with(':') && is_incomplete(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
     }
 }
 
+fn is_incomplete(input: &str) -> bool {
+    let mut chars = input.chars().peekable();
+    let mut parentheses = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            // a string is skipped up to its closing quote
+            '"' if !chars.by_ref().any(|c| c == '"') => return true,
+            '#' => {
+                chars.by_ref().find(|c| *c == '\n');
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                chars.by_ref().find(|c| *c == '\n');
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                if !chars.by_ref().any(|c| std::mem::replace(&mut previous, c) == '*' && c == '/') {
+                    return true;
+                }
+            }
+            '(' => parentheses += 1,
+            ')' => parentheses -= 1,
+            _ => {}
+        }
+    }
+    parentheses > 0
+}
+
 fn print_tokenize(text: &str) {
     let tokens = lex(text);
     println!("Tokenized: {:#?}", tokens);
     let errors = validate(&tokens[..]);
-    match errors {
-        Some(errors) => println!("Errors: {:#?}", errors),
-        None => (),
+    if let Some(errors) = errors {
+        println!("Errors: {:#?}", errors);
     }
 }
 
 fn print_parse(state: &mut State, text: &str) {
     let tokens = lex(text);
-    let parse_result = parse(&tokens[..]);
-    println!("Parse result: {:#?}", &parse_result);
-
-    if let Ok(success) = parse_result {
-        state.last_ast = Some(success.ast);
+    let index = LineIndex::new(text);
+    match parse(&tokens[..]) {
+        Ok(success) => {
+            for diagnostic in success.warnings.iter().chain(&success.infos) {
+                println!("{}\n", diagnostic.render(&index));
+            }
+            println!("Parsed {} features", success.ast.model.features.len());
+            let compilation = compile_with(&success.ast, &[]);
+            for error in &compilation.errors {
+                println!("error: {}", error);
+            }
+            state.feature_sets = compilation.feature_sets;
+            state.last_ast = Some(success.ast);
+        }
+        Err(failure) => {
+            for error in &failure.errors {
+                println!("{}\n", error.render(&index));
+            }
+        }
     }
 }
 
 fn print_serialized(state: &State) {
-    if let Some(ast) = &state.last_ast {
-        let serialized = serialize(&ast);
-        println!("{}", serialized);
+    match &state.last_ast {
+        Some(ast) => println!("{}", serialize(ast)),
+        None => println!("Nothing parsed yet, use :parse or :load first"),
+    }
+}
+
+fn print_ast(state: &State) {
+    match &state.last_ast {
+        Some(ast) => println!("{:#?}", ast),
+        None => println!("Nothing parsed yet, use :parse or :load first"),
+    }
+}
+
+fn print_json(state: &State) {
+    let json = serde_json::to_string_pretty(&state.feature_sets).expect("feature sets can always be serialized");
+    println!("{}", json);
+}
+
+fn load(state: &mut State, file: &str) {
+    let mut resolution = resolve(Path::new(file), &FileSystemLoader);
+    for error in &resolution.errors {
+        println!("{}\n", resolution.render(error));
+    }
+    for module in &resolution.modules {
+        let index = LineIndex::new(&module.source);
+        let file = module.path.display().to_string();
+        for diagnostic in module.warnings.iter().chain(&module.infos) {
+            println!("{}\n", diagnostic.render_in_file(&file, &index));
+        }
+    }
+    if !resolution.errors.is_empty() {
+        return;
+    }
+
+    let compilation = resolution.compile();
+    for error in &compilation.errors {
+        println!("error: {}", error);
+    }
+    println!(
+        "Loaded {} files with {} features",
+        resolution.modules.len(),
+        compilation.feature_sets.iter().map(|f| f.features.len()).sum::<usize>()
+    );
+    state.feature_sets = compilation.feature_sets;
+    // the entry file is always the last module
+    state.last_ast = resolution.modules.pop().map(|m| m.ast);
+}
+
+fn save(state: &State, file: &str) {
+    let Some(ast) = &state.last_ast else {
+        println!("Nothing parsed yet, use :parse or :load first");
+        return;
+    };
+    match fs::write(file, serialize(ast)) {
+        Ok(()) => println!("Saved to {}", file),
+        Err(error) => println!("error: {} can not be written: {}", file, error),
+    }
+}
+
+fn set(state: &mut State, arguments: &str) {
+    let Some((property, value)) = arguments.split_once(char::is_whitespace) else {
+        println!("Usage: :set <property> <value>, e.g. :set strength 14");
+        return;
+    };
+    let value = value.trim();
+    let parsed = match value.parse::<Fraction>() {
+        Ok(number) => number.into(),
+        Err(_) => match value.parse::<DiceValue>() {
+            Ok(dice) => StaticValueType::Dice(dice),
+            Err(_) => {
+                println!("error: {} is neither a number nor dice", value);
+                return;
+            }
+        },
+    };
+    state.user_values.insert(property.to_string(), parsed);
+    evaluate(state);
+}
+
+fn unset(state: &mut State, property: &str) {
+    if state.user_values.remove(property).is_none() {
+        println!("{} has no user value", property);
+        return;
     }
+    evaluate(state);
 }
 
-fn start_interactive_mode() {
+/// Calculates all values of a character sheet with the current feature sets and user values and
+/// prints them as a table.
+fn evaluate(state: &State) {
+    let mut sheet = CharacterSheet::new();
+    sheet.active_features = state.feature_sets.clone();
+    sheet.user_values = state.user_values.clone();
+
+    let mut missing: Vec<String> = sheet
+        .find_minimum_required_user_values()
+        .into_iter()
+        .filter(|property| !state.user_values.contains_key(property))
+        .collect();
+    missing.sort();
+    if !missing.is_empty() {
+        println!("Missing user values (set them with :set <property> <value>): {}", missing.join(", "));
+    }
+
+    let values = match sheet.calculate_all_values() {
+        Ok(values) => values,
+        Err(error) => match error {},
+    };
+    let mut rows: Vec<(String, String)> = values
+        .iter()
+        .map(|(property, value)| (property.clone(), format_value(value)))
+        .collect();
+    rows.sort();
+
+    let width = rows.iter().map(|(property, _)| property.len()).max().unwrap_or(0).max("Property".len());
+    println!("{:width$} | Value", "Property");
+    println!("{}-+-{}", "-".repeat(width), "-".repeat(5));
+    for (property, value) in rows {
+        println!("{:width$} | {}", property, value);
+    }
+}
+
+fn format_value(value: &ResultValue) -> String {
+    match value {
+        Ok(StaticValueType::Number(n)) => n.to_string(),
+        Ok(StaticValueType::Fraction(f)) => f.to_string(),
+        Ok(StaticValueType::Dice(d)) => d.to_string(),
+        Err(error) => format!("error: {}", error),
+    }
+}
+
+fn start_interactive_mode() -> ExitCode {
+    let mut editor: Editor<ReplHelper, DefaultHistory> = match Editor::new() {
+        Ok(editor) => editor,
+        Err(error) => {
+            eprintln!("error: the interactive mode can not be started: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+    editor.set_helper(Some(ReplHelper));
+
     let mut text: String = "".to_string();
     let mut state: State = State::new();
     loop {
-        let line: String = read!("{}\n");
+        let prompt = if text.is_empty() { "> " } else { ".. " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C discards the text that was not parsed yet
+            Err(ReadlineError::Interrupted) => {
+                text.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                eprintln!("error: {}", error);
+                return ExitCode::FAILURE;
+            }
+        };
+        let _ = editor.add_history_entry(line.as_str());
 
-        if line.starts_with(":") {
-            let command = line[1..].trim();
-            match command {
-                "t" | "tokenize" => {
+        if let Some(command) = line.strip_prefix(':') {
+            let (command, argument) = match command.trim().split_once(char::is_whitespace) {
+                Some((command, argument)) => (command, argument.trim()),
+                None => (command.trim(), ""),
+            };
+            match (command, argument) {
+                ("t" | "tokenize", _) => {
                     print_tokenize(&text);
-                    text = "".to_string();
+                    text.clear();
                 },
-                "p" | "parse" => {
+                ("p" | "parse", _) => {
                     print_parse(&mut state, &text);
-                    text = "".to_string();
-                },
-                "s" | "serialize" => {
-                    print_serialized(&state);
+                    text.clear();
                 },
-                "e" | "exit" => break,
+                ("s" | "serialize", _) => print_serialized(&state),
+                ("ast", _) => print_ast(&state),
+                ("json", _) => print_json(&state),
+                ("l" | "load", file) if !file.is_empty() => load(&mut state, file),
+                ("save", file) if !file.is_empty() => save(&state, file),
+                ("eval", _) => evaluate(&state),
+                ("set", arguments) => set(&mut state, arguments),
+                ("unset", property) if !property.is_empty() => unset(&mut state, property),
+                ("c" | "clear", _) => text.clear(),
+                ("e" | "exit", _) => break,
                 _ => print_help()
             }
         }
         else {
             text.push_str(&line);
-            text.push_str("\n");
+            text.push('\n');
         }
     }
+    ExitCode::SUCCESS
 }
 
 fn print_help() {
+    println!("Enter DSL text line by line, then use one of the commands on it.");
     println!("Available commands:");
     println!("  :t, :tokenize - Tokenize the text");
-    println!("  :p, :parse - Parse the text");
+    println!("  :p, :parse - Parse and compile the text");
+    println!("  :c, :clear - Discard the text");
+    println!("  :l, :load <file> - Parse and compile the file and everything it imports");
+    println!("  :save <file> - Write the serialized last parsed or loaded file");
+    println!("  :s, :serialize - Print the serialized last parsed or loaded file");
+    println!("  :ast - Print the AST of the last parsed or loaded file");
+    println!("  :json - Print the compiled feature sets as JSON");
+    println!("  :eval - Calculate all values of a character with the compiled feature sets");
+    println!("  :set <property> <value> - Set a user value, e.g. :set strength 14, and calculate again");
+    println!("  :unset <property> - Remove a user value and calculate again");
     println!("  :e, :exit - Exit the program");
 }
 
-fn main() {
-    start_interactive_mode()
+fn main() -> ExitCode {
+    match Cli::parse().command {
+        Some(Command::Check { format, files }) => check(&files, format),
+        Some(Command::Fmt { check, options, files }) => fmt(&files, check, &options.options()),
+        Some(Command::Compile { output, format, files }) => compile(&files, output.as_deref(), format),
+        Some(Command::Repl) | None => start_interactive_mode(),
+    }
 }
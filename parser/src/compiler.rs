@@ -0,0 +1,579 @@
+use thiserror::Error;
+
+use types::character_sheet_collection::{
+    CalculatedValue, Feature, FeatureModifier, FeatureSet, Limiter, PropertyDefinition, Script,
+    Selector, StaticValueType,
+};
+
+use crate::parser::ast;
+use crate::parser::ast::{
+    negate_dice, Expression, ExpressionKind, Invocation, ModifierValue, Reference, Scope, AST,
+};
+
+/// The result of compiling an `AST`.
+/// Contains everything that could be represented, even if there were errors.
+#[derive(Debug)]
+pub struct Compilation {
+    /// The compiled feature sets. A file currently always compiles to exactly one feature set.
+    pub feature_sets: Vec<FeatureSet>,
+    pub errors: Vec<CompileError>,
+}
+
+/// Constructs of the AST that can not be represented in a `FeatureSet`.
+/// The affected constructs are left out of the result.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CompileError {
+    #[error("Feature {feature}: the feature of {owner}.{property} does not exist")]
+    UnknownFeature {
+        feature: String,
+        owner: String,
+        property: String,
+    },
+    #[error("Feature {feature}: the value of {property} can not use `{dependency}`, because scripts read the `-` as a subtraction")]
+    InvalidDependency {
+        feature: String,
+        property: String,
+        dependency: String,
+    },
+}
+
+/// Lowers the AST into the feature sets used by the engine.
+///
+/// * `set x to n` sets the value of `x` to `n`
+/// * `+n x` and `bonus to x of n` add `n` to the value of `x` (as the script `@ + n`)
+/// * `set x to 1d8 + 2` sets a dice value if the expression only adds and subtracts dice and
+///   whole numbers, all other expressions become scripts that depend on the properties they use
+/// * properties of a feature, e.g. `Rage.uses`, keep their qualified name, so that they are
+///   separate from the properties of the character and other features
+/// * definitions without a selector get an empty selector, which the engine replaces with its
+///   default selector
+pub fn compile(ast: &AST) -> Compilation {
+    compile_with(ast, &[])
+}
+
+/// Like [`compile`], but properties of the imported features may be referenced as well.
+pub fn compile_with(ast: &AST, imported_features: &[String]) -> Compilation {
+    let mut errors = vec![];
+    let names: Vec<&str> = ast
+        .model
+        .features
+        .iter()
+        .map(|f| f.name.as_str())
+        .chain(imported_features.iter().map(String::as_str))
+        .collect();
+    let features = ast
+        .model
+        .features
+        .iter()
+        .map(|feature| compile_feature(feature, &names, &mut errors))
+        .collect();
+
+    let (name, description, source) = match &ast.model.header {
+        Some(header) => (
+            header.name.clone(),
+            header.description.clone(),
+            header.source.clone(),
+        ),
+        None => Default::default(),
+    };
+    Compilation {
+        feature_sets: vec![FeatureSet {
+            name,
+            description,
+            source,
+            features,
+        }],
+        errors,
+    }
+}
+
+fn compile_feature(
+    feature: &ast::Feature,
+    names: &[&str],
+    errors: &mut Vec<CompileError>,
+) -> Feature {
+    // `property` is the modified property if the reference is a dependency of its value
+    let mut resolve = |reference: &Reference, property: Option<&str>| match &reference.scope {
+        Scope::Feature(owner) if !names.contains(&owner.as_str()) => {
+            errors.push(CompileError::UnknownFeature {
+                feature: feature.name.clone(),
+                owner: owner.clone(),
+                property: reference.name.clone(),
+            });
+            None
+        }
+        _ => match property {
+            Some(property) if reference.qualified_name().contains('-') => {
+                errors.push(CompileError::InvalidDependency {
+                    feature: feature.name.clone(),
+                    property: property.to_string(),
+                    dependency: reference.qualified_name(),
+                });
+                None
+            }
+            _ => Some(reference.qualified_name()),
+        },
+    };
+
+    let definitions = feature
+        .definitions
+        .iter()
+        .filter_map(|definition| {
+            Some(PropertyDefinition {
+                name: resolve(&definition.property, None)?,
+                selector: definition
+                    .selector
+                    .as_ref()
+                    .map(|selector| Selector {
+                        identifier: selector.identifier.clone(),
+                        arguments: selector.arguments.clone(),
+                    })
+                    .unwrap_or_default(),
+                limiters: definition.limiters.iter().map(compile_limiter).collect(),
+                rounding: definition.rounding,
+            })
+        })
+        .collect();
+    let modifiers = feature
+        .modifiers
+        .iter()
+        .filter_map(|modifier| {
+            let property = resolve(&modifier.referencing, None)?;
+            let mut resolve_dependency =
+                |reference: &Reference| resolve(reference, Some(&property));
+            let value = compile_value(&modifier.value, &mut resolve_dependency)?;
+            Some(FeatureModifier { property, value })
+        })
+        .collect();
+
+    Feature {
+        name: feature.name.clone(),
+        description: feature.description.clone(),
+        base_type: feature.base_type.clone(),
+        definitions,
+        modifiers,
+    }
+}
+
+fn compile_limiter(limiter: &Invocation) -> Limiter {
+    Limiter {
+        identifier: limiter.identifier.clone(),
+        arguments: limiter.arguments.clone(),
+    }
+}
+
+fn compile_value(
+    value: &ModifierValue,
+    resolve: &mut impl FnMut(&Reference) -> Option<String>,
+) -> Option<CalculatedValue> {
+    let value = match value {
+        ModifierValue::Set(n) => CalculatedValue::StaticValue((*n).into()),
+        ModifierValue::SimpleBonus(n) | ModifierValue::Bonus(n) => {
+            // the script language supports the same numbers, so they can be written as they are
+            let script = match n.checked_neg() {
+                Some(negated) if negated > *n => format!("@ - {}", negated),
+                _ => format!("@ + {}", n),
+            };
+            script_value(script, vec![])
+        }
+        ModifierValue::SimpleDiceBonus(d) => {
+            let script = match negate_dice(d) {
+                Some(negated) if d.to_string().starts_with('-') => format!("@ - {}", negated),
+                _ => format!("@ + {}", d),
+            };
+            script_value(script, vec![])
+        }
+        ModifierValue::BonusExpression(e) => {
+            let script = match &e.kind {
+                // `-(a - b)` is `@ - (a - b)`, built ASTs do not need to contain the parentheses
+                ExpressionKind::Unary(op, inner)
+                    if op == "-" && inner.binds_weaker_than(op, true) =>
+                {
+                    format!("@ - ({})", inner)
+                }
+                ExpressionKind::Unary(op, inner) if op == "-" => format!("@ - {}", inner),
+                _ => format!("@ + {}", e),
+            };
+            script_value(script, dependencies(e, resolve)?)
+        }
+        ModifierValue::SetExpression(e) => match e.dice() {
+            Some(dice) => CalculatedValue::StaticValue(StaticValueType::Dice(dice)),
+            None => script_value(e.to_string(), dependencies(e, resolve)?),
+        },
+    };
+    Some(value)
+}
+
+fn script_value(script: String, dependencies: Vec<String>) -> CalculatedValue {
+    CalculatedValue::Script(Script {
+        script,
+        dependencies,
+    })
+}
+
+/// Resolves all dependencies of the expression, so that every invalid one is reported.
+fn dependencies(
+    expression: &Expression,
+    resolve: &mut impl FnMut(&Reference) -> Option<String>,
+) -> Option<Vec<String>> {
+    let resolved: Vec<Option<String>> = expression
+        .dependencies()
+        .into_iter()
+        .map(&mut *resolve)
+        .collect();
+    resolved.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use types::character_sheet_collection::{
+        CalculatedValue, DiceValue, FeatureModifier, Limiter, PropertyDefinition, RoundingMode,
+        Script, Selector, StaticValueType,
+    };
+
+    use types::fraction::Fraction;
+
+    use super::{compile, CompileError};
+    use crate::parser::ast::{
+        Expression, ExpressionKind, Feature, Model, Modifier, ModifierValue, Reference, Scope, AST,
+    };
+    use crate::parser::parse;
+    use crate::span::Span;
+    use crate::tokenizer::lex;
+
+    fn bonus(property: &str, script: &str) -> FeatureModifier {
+        FeatureModifier {
+            property: property.to_string(),
+            value: CalculatedValue::Script(Script {
+                script: script.to_string(),
+                dependencies: vec![],
+            }),
+        }
+    }
+
+    #[test]
+    fn compile_features() {
+        let input = r#"Name: "Dwarf";
+Description: "Short and sturdy";
+Modifiers:
+    +2 constitution;
+    bonus to speed of -5;
+    set darkvision to 60;
+---
+Name: "Toughness";
+"#;
+        let ast = parse(&lex(input)).unwrap().ast;
+        let compilation = compile(&ast);
+
+        assert_eq!(compilation.errors, vec![]);
+        assert_eq!(compilation.feature_sets.len(), 1);
+        let features = &compilation.feature_sets[0].features;
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0].name, "Dwarf");
+        assert_eq!(features[0].description, "Short and sturdy");
+        assert_eq!(
+            features[0].modifiers,
+            vec![
+                bonus("constitution", "@ + 2"),
+                bonus("speed", "@ - 5"),
+                FeatureModifier {
+                    property: "darkvision".to_string(),
+                    value: CalculatedValue::StaticValue(StaticValueType::Number(60)),
+                },
+            ]
+        );
+        assert_eq!(features[1].name, "Toughness");
+        assert_eq!(features[1].modifiers, vec![]);
+    }
+
+    #[test]
+    fn header() {
+        let input = r#"FeatureSet: "Basic rules";
+Description: "The free rules";
+Source: "Basic rules, p. 18";
+---
+Name: "Dwarf";
+Type: "race";
+"#;
+        let compilation = compile(&parse(&lex(input)).unwrap().ast);
+        let feature_set = &compilation.feature_sets[0];
+        assert_eq!(feature_set.name, "Basic rules");
+        assert_eq!(feature_set.description, "The free rules");
+        assert_eq!(feature_set.source, "Basic rules, p. 18");
+        assert_eq!(feature_set.features[0].name, "Dwarf");
+        assert_eq!(feature_set.features[0].base_type, "race");
+    }
+
+    #[test]
+    fn decimals() {
+        let input = r#"Name: "Giant";
+Description: "";
+Modifiers:
+    set weight to 2,5;
+    -0.5 speed;
+    bonus to damage of 1.25;
+    set size to 2.0;
+"#;
+        let ast = parse(&lex(input)).unwrap().ast;
+        let compilation = compile(&ast);
+
+        assert_eq!(
+            compilation.feature_sets[0].features[0].modifiers,
+            vec![
+                FeatureModifier {
+                    property: "weight".to_string(),
+                    value: CalculatedValue::StaticValue(StaticValueType::Fraction(
+                        Fraction::new(5, 2).unwrap()
+                    )),
+                },
+                bonus("speed", "@ - 0.5"),
+                bonus("damage", "@ + 1.25"),
+                FeatureModifier {
+                    property: "size".to_string(),
+                    value: CalculatedValue::StaticValue(StaticValueType::Number(2)),
+                },
+            ]
+        );
+
+        let errors = parse(&lex(
+            "Name: \"Giant\";\nModifiers:\n    +99999999999999999999 size;\n",
+        ))
+        .unwrap_err()
+        .errors;
+        assert_eq!(
+            errors[0].to_string(),
+            "The number 99999999999999999999 is too large"
+        );
+    }
+
+    #[test]
+    fn feature_references() {
+        let input = r#"Name: "Longsword";
+Modifiers:
+    set damage to 8;
+---
+Name: "Rage";
+Modifiers:
+    set Rage.uses to 3;
+    +2 Longsword.damage;
+    +2 damage;
+"#;
+        let compilation = compile(&parse(&lex(input)).unwrap().ast);
+        assert_eq!(compilation.errors, vec![]);
+        assert_eq!(
+            compilation.feature_sets[0].features[1].modifiers,
+            vec![
+                FeatureModifier {
+                    property: "Rage.uses".to_string(),
+                    value: CalculatedValue::StaticValue(StaticValueType::Number(3)),
+                },
+                bonus("Longsword.damage", "@ + 2"),
+                bonus("damage", "@ + 2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn expressions() {
+        let input = r#"Name: "Rogue";
+Modifiers:
+    bonus to damage of 1d6;
+    set hp to 8 + constitution;
+    +2d4x sneak_attack;
+    -1d4 speed;
+    set weapon to 1d8 + (1d6 - 2);
+    bonus to attack of -(Rogue.level / 2);
+    set ac to max(10 + dexterity, 12 + floor(dexterity / 2));
+"#;
+        let compilation = compile(&parse(&lex(input)).unwrap().ast);
+        let script = |property: &str, script: &str, dependencies: &[&str]| FeatureModifier {
+            property: property.to_string(),
+            value: CalculatedValue::Script(Script {
+                script: script.to_string(),
+                dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+            }),
+        };
+        assert_eq!(compilation.errors, vec![]);
+        assert_eq!(
+            compilation.feature_sets[0].features[0].modifiers,
+            vec![
+                bonus("damage", "@ + 1d6"),
+                script("hp", "8 + constitution", &["constitution"]),
+                bonus("sneak_attack", "@ + 2d4x=4"),
+                bonus("speed", "@ - 1d4"),
+                FeatureModifier {
+                    property: "weapon".to_string(),
+                    value: CalculatedValue::StaticValue(StaticValueType::Dice(
+                        "1d8+1d6-2".parse::<DiceValue>().unwrap()
+                    )),
+                },
+                script("attack", "@ - (Rogue.level / 2)", &["Rogue.level"]),
+                script(
+                    "ac",
+                    "max(10 + dexterity, 12 + floor(dexterity / 2))",
+                    &["dexterity"]
+                ),
+            ]
+        );
+
+        let input = "Name: \"Cleric\";\nModifiers:\n    set hp to hit-dice + 1;\n";
+        let compilation = compile(&parse(&lex(input)).unwrap().ast);
+        assert_eq!(
+            compilation.errors,
+            vec![CompileError::InvalidDependency {
+                feature: "Cleric".to_string(),
+                property: "hp".to_string(),
+                dependency: "hit-dice".to_string(),
+            }]
+        );
+        assert_eq!(compilation.feature_sets[0].features[0].modifiers, vec![]);
+    }
+
+    #[test]
+    fn definitions() {
+        let input = r#"Name: "Attributes";
+Definitions:
+    strength: highest, min 0, max 20;
+    Attributes.points: sum, round down;
+    luck;
+"#;
+        let compilation = compile(&parse(&lex(input)).unwrap().ast);
+        let limiter = |identifier: &str, argument: &str| Limiter {
+            identifier: identifier.to_string(),
+            arguments: vec![argument.to_string()],
+        };
+        assert_eq!(
+            compilation.feature_sets[0].features[0].definitions,
+            vec![
+                PropertyDefinition {
+                    name: "strength".to_string(),
+                    selector: Selector {
+                        identifier: "highest".to_string(),
+                        arguments: vec![],
+                    },
+                    limiters: vec![limiter("min", "0"), limiter("max", "20")],
+                    rounding: None,
+                },
+                PropertyDefinition {
+                    name: "Attributes.points".to_string(),
+                    selector: Selector {
+                        identifier: "sum".to_string(),
+                        arguments: vec![],
+                    },
+                    limiters: vec![],
+                    rounding: Some(RoundingMode::Down),
+                },
+                PropertyDefinition {
+                    name: "luck".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn negated_bonus_expressions() {
+        let input = "Name: \"Rage\";\nModifiers:\n    +1 damage;\n    +1 attack;\n";
+        let mut ast = parse(&lex(input)).unwrap().ast;
+        let expression = |kind| Expression {
+            kind,
+            span: Span::default(),
+        };
+        // `-(strength - 2)` and `-(strength * 2)`, without the parentheses the parser would add
+        let negated = |op: &str| {
+            let strength = expression(ExpressionKind::Reference(Reference {
+                scope: Scope::Character,
+                name: "strength".to_string(),
+                span: Span::default(),
+            }));
+            let two = expression(ExpressionKind::Number(Fraction::from_integer(2)));
+            ModifierValue::BonusExpression(expression(ExpressionKind::Unary(
+                "-".to_string(),
+                Box::new(expression(ExpressionKind::Binary(
+                    op.to_string(),
+                    Box::new(strength),
+                    Box::new(two),
+                ))),
+            )))
+        };
+        let modifiers = &mut ast.model.features[0].modifiers;
+        modifiers[0].value = negated("-");
+        modifiers[1].value = negated("*");
+
+        let script = |property: &str, script: &str| FeatureModifier {
+            property: property.to_string(),
+            value: CalculatedValue::Script(Script {
+                script: script.to_string(),
+                dependencies: vec!["strength".to_string()],
+            }),
+        };
+        assert_eq!(
+            compile(&ast).feature_sets[0].features[0].modifiers,
+            vec![
+                script("damage", "@ - (strength - 2)"),
+                script("attack", "@ - strength * 2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_features() {
+        let ast = AST {
+            model: Model {
+                imports: vec![],
+                header: None,
+                features: vec![Feature {
+                    name: "Rage".to_string(),
+                    base_type: "".to_string(),
+                    description: "".to_string(),
+                    definitions: vec![],
+                    modifiers: vec![
+                        Modifier {
+                            referencing: Reference {
+                                scope: Scope::Feature("Longsword".to_string()),
+                                name: "attack".to_string(),
+                                span: Span::default(),
+                            },
+                            value: ModifierValue::Bonus(Fraction::from_integer(2)),
+                            span: Span::default(),
+                            comments: vec![],
+                            trailing_comment: None,
+                        },
+                        Modifier {
+                            referencing: Reference {
+                                scope: Scope::Character,
+                                name: "damage".to_string(),
+                                span: Span::default(),
+                            },
+                            value: ModifierValue::Bonus(Fraction::from_integer(2)),
+                            span: Span::default(),
+                            comments: vec![],
+                            trailing_comment: None,
+                        },
+                    ],
+                    span: Span::default(),
+                    comments: vec![],
+                    name_comment: None,
+                    type_comment: None,
+                    description_comment: None,
+                    trailing_comments: vec![],
+                }],
+            },
+            references: vec![],
+        };
+        let compilation = compile(&ast);
+
+        assert_eq!(
+            compilation.errors,
+            vec![CompileError::UnknownFeature {
+                feature: "Rage".to_string(),
+                owner: "Longsword".to_string(),
+                property: "attack".to_string(),
+            }]
+        );
+        assert_eq!(
+            compilation.feature_sets[0].features[0].modifiers,
+            vec![bonus("damage", "@ + 2")]
+        );
+    }
+}
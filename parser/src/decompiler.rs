@@ -0,0 +1,677 @@
+use regex::Regex;
+use thiserror::Error;
+
+use types::character_sheet_collection::{
+    CalculatedValue, DiceValue, FeatureModifier, FeatureSet, PropertyDefinition, Script,
+    StaticValueType,
+};
+use types::fraction::Fraction;
+
+use crate::parser::ast;
+use crate::parser::ast::{
+    negate_dice, Definition, Expression, ExpressionKind, Header, Invocation, Model, Modifier,
+    ModifierValue, Reference, Scope, AST,
+};
+use crate::parser::parse_expression;
+use crate::span::Span;
+use crate::tokenizer::lex;
+
+/// The result of decompiling a `FeatureSet`.
+/// Contains everything that could be expressed in the DSL, even if there were errors.
+#[derive(Debug)]
+pub struct Decompilation {
+    pub ast: AST,
+    pub errors: Vec<DecompileError>,
+}
+
+/// Parts of a `FeatureSet` that can not be expressed in the DSL.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DecompileError {
+    #[error(
+        "The {field} of the feature set contains a `\"`, which can not be expressed in the DSL"
+    )]
+    InvalidFeatureSetString { field: &'static str },
+    #[error(
+        "Feature {feature}: the {field} contains a `\"`, which can not be expressed in the DSL"
+    )]
+    InvalidString {
+        feature: String,
+        field: &'static str,
+    },
+    #[error("Feature {feature}: the definition of {property} can not be expressed in the DSL yet ({reason})")]
+    Definition {
+        feature: String,
+        property: String,
+        reason: String,
+    },
+    #[error("Feature {feature}: the modifier of {property} can not be expressed in the DSL yet ({reason})")]
+    Modifier {
+        feature: String,
+        property: String,
+        reason: String,
+    },
+}
+
+/// Converts a `FeatureSet` back into an AST, which can be printed with
+/// [`serialize`](crate::serializer::serialize).
+///
+/// Modifiers that can not be expressed are left out, all other parts are kept, so that the
+/// result can be fixed by hand. Everything that is left out or kept but invalid is reported.
+pub fn decompile(feature_set: &FeatureSet) -> Decompilation {
+    let mut decompiler = Decompiler {
+        errors: vec![],
+        identifier_regex: Regex::new(r"^[a-zA-Z_][a-zA-Z_-]*$").unwrap(),
+        bonus_regex: Regex::new(r"^\s*\$?@\s*([+-])\s*(\d+(?:\.\d+)?)\s*$").unwrap(),
+        dice_bonus_regex: Regex::new(r"^\s*\$?@\s*([+-])\s*(\d+d\S+)\s*$").unwrap(),
+        bonus_script_regex: Regex::new(r"(?s)^\s*\$?@\s*([+-])(.*)$").unwrap(),
+    };
+
+    let fields = [
+        ("name", &feature_set.name),
+        ("description", &feature_set.description),
+        ("source", &feature_set.source),
+    ];
+    for (field, value) in fields {
+        if value.contains('"') {
+            decompiler
+                .errors
+                .push(DecompileError::InvalidFeatureSetString { field });
+        }
+    }
+    // the header is optional, so it is only written if there is something to write
+    let header = fields.iter().any(|(_, value)| !value.is_empty()).then(|| {
+        Box::new(Header {
+            name: feature_set.name.clone(),
+            description: feature_set.description.clone(),
+            source: feature_set.source.clone(),
+            span: Span::default(),
+            comments: vec![],
+            name_comment: None,
+            description_comment: None,
+            source_comment: None,
+            trailing_comments: vec![],
+        })
+    });
+
+    let features = feature_set
+        .features
+        .iter()
+        .map(|feature| decompiler.feature(feature))
+        .collect();
+
+    Decompilation {
+        ast: AST {
+            model: Model {
+                imports: vec![],
+                header,
+                features,
+            },
+            references: vec![],
+        },
+        errors: decompiler.errors,
+    }
+}
+
+struct Decompiler {
+    errors: Vec<DecompileError>,
+    identifier_regex: Regex,
+    bonus_regex: Regex,
+    dice_bonus_regex: Regex,
+    bonus_script_regex: Regex,
+}
+
+impl Decompiler {
+    fn feature(&mut self, feature: &types::character_sheet_collection::Feature) -> ast::Feature {
+        for (field, value) in [
+            ("name", &feature.name),
+            ("type", &feature.base_type),
+            ("description", &feature.description),
+        ] {
+            if value.contains('"') {
+                self.errors.push(DecompileError::InvalidString {
+                    feature: feature.name.clone(),
+                    field,
+                });
+            }
+        }
+        let definitions = feature
+            .definitions
+            .iter()
+            .filter_map(|definition| match self.definition(definition) {
+                Ok(definition) => Some(definition),
+                Err(reason) => {
+                    self.errors.push(DecompileError::Definition {
+                        feature: feature.name.clone(),
+                        property: definition.name.clone(),
+                        reason,
+                    });
+                    None
+                }
+            })
+            .collect();
+        let modifiers = feature
+            .modifiers
+            .iter()
+            .filter_map(|modifier| match self.modifier(modifier) {
+                Ok(modifier) => Some(modifier),
+                Err(reason) => {
+                    self.errors.push(DecompileError::Modifier {
+                        feature: feature.name.clone(),
+                        property: modifier.property.clone(),
+                        reason,
+                    });
+                    None
+                }
+            })
+            .collect();
+
+        ast::Feature {
+            name: feature.name.clone(),
+            base_type: feature.base_type.clone(),
+            description: feature.description.clone(),
+            definitions,
+            modifiers,
+            span: Span::default(),
+            comments: vec![],
+            name_comment: None,
+            type_comment: None,
+            description_comment: None,
+            trailing_comments: vec![],
+        }
+    }
+
+    fn reference(&self, property: &str) -> Result<Reference, String> {
+        // properties of features are qualified with the name of the feature, e.g. `Rage.uses`
+        let (scope, name) = match property.split_once('.') {
+            Some((feature, name)) => (Scope::Feature(feature.to_string()), name),
+            None => (Scope::Character, property),
+        };
+        let valid_scope = match &scope {
+            Scope::Character => true,
+            Scope::Feature(feature) => self.identifier_regex.is_match(feature),
+        };
+        if !valid_scope || !self.identifier_regex.is_match(name) {
+            return Err(format!("`{}` is not a valid property name", property));
+        }
+        Ok(Reference {
+            scope,
+            name: name.to_string(),
+            span: Span::default(),
+        })
+    }
+
+    fn definition(&self, definition: &PropertyDefinition) -> Result<Definition, String> {
+        let property = self.reference(&definition.name)?;
+        let selector = &definition.selector;
+        let selector = if selector.identifier.is_empty() && selector.arguments.is_empty() {
+            None
+        } else {
+            Some(self.invocation("selector", &selector.identifier, &selector.arguments)?)
+        };
+        let limiters = definition
+            .limiters
+            .iter()
+            .map(|limiter| self.invocation("limiter", &limiter.identifier, &limiter.arguments))
+            .collect::<Result<Vec<_>, _>>()?;
+        if limiters.iter().any(|limiter| limiter.identifier == "round") {
+            return Err("a limiter called `round` is reserved for the rounding".to_string());
+        }
+        if selector.is_none() && (!limiters.is_empty() || definition.rounding.is_some()) {
+            // limiters and the rounding can only be written after a selector
+            return Err("limiters need a selector".to_string());
+        }
+
+        Ok(Definition {
+            property,
+            selector,
+            limiters,
+            rounding: definition.rounding,
+            span: Span::default(),
+            comments: vec![],
+            trailing_comment: None,
+        })
+    }
+
+    fn invocation(
+        &self,
+        kind: &str,
+        identifier: &str,
+        arguments: &[String],
+    ) -> Result<Invocation, String> {
+        if !self.identifier_regex.is_match(identifier) {
+            return Err(format!("`{}` is not a valid {} name", identifier, kind));
+        }
+        if let Some(argument) = arguments.iter().find(|a| a.contains('"')) {
+            return Err(format!("the argument `{}` contains a `\"`", argument));
+        }
+        Ok(Invocation {
+            identifier: identifier.to_string(),
+            arguments: arguments.to_vec(),
+            span: Span::default(),
+        })
+    }
+
+    fn modifier(&self, modifier: &FeatureModifier) -> Result<Modifier, String> {
+        let referencing = self.reference(&modifier.property)?;
+
+        let value = match &modifier.value {
+            CalculatedValue::StaticValue(StaticValueType::Number(n)) => {
+                ModifierValue::Set((*n).into())
+            }
+            CalculatedValue::StaticValue(StaticValueType::Fraction(f)) => {
+                if f.to_decimal().is_none() {
+                    return Err(format!(
+                        "the fraction {} can not be written as a decimal",
+                        f
+                    ));
+                }
+                ModifierValue::Set(*f)
+            }
+            CalculatedValue::StaticValue(StaticValueType::Dice(d)) => {
+                ModifierValue::SetExpression(self.dice(d)?)
+            }
+            CalculatedValue::Script(script) => match self.simple_bonus(&script.script)? {
+                Some(value) => value,
+                None => self.script(script)?,
+            },
+        };
+
+        Ok(Modifier {
+            referencing,
+            value,
+            span: Span::default(),
+            comments: vec![],
+            trailing_comment: None,
+        })
+    }
+
+    /// `+2 x` for the scripts `@ + 2` and `@ - 1d4`, if the bonus is a single number or group
+    /// of dice.
+    fn simple_bonus(&self, script: &str) -> Result<Option<ModifierValue>, String> {
+        if let Some(captures) = self.dice_bonus_regex.captures(script) {
+            let dice = captures[2]
+                .parse::<DiceValue>()
+                .ok()
+                .filter(|d| d.dice.len() == 1 && d.dice[0].amount > 0 && d.bonus == 0);
+            return Ok(dice.map(|dice| {
+                ModifierValue::SimpleDiceBonus(if &captures[1] == "-" {
+                    // a single positive group can always be negated
+                    negate_dice(&dice).unwrap()
+                } else {
+                    dice
+                })
+            }));
+        }
+        let Some(captures) = self.bonus_regex.captures(script) else {
+            return Ok(None);
+        };
+        let bonus: Fraction = captures[2]
+            .parse()
+            .map_err(|_| format!("the bonus {} is too large", &captures[2]))?;
+        Ok(Some(ModifierValue::SimpleBonus(if &captures[1] == "-" {
+            // a parsed decimal is never negative, so it can always be negated
+            bonus.checked_neg().unwrap()
+        } else {
+            bonus
+        })))
+    }
+
+    /// `bonus to x of ...` for scripts that add to the value (`@ + ...`), `set x to ...` for
+    /// scripts that do not use it. The script must also be a valid expression of the DSL, which
+    /// uses the same properties as the dependencies of the script.
+    fn script(&self, script: &Script) -> Result<ModifierValue, String> {
+        let (subtract, text) = match self.bonus_script_regex.captures(&script.script) {
+            Some(captures) => (Some(&captures[1] == "-"), captures.get(2).unwrap().as_str()),
+            None => (None, script.script.as_str()),
+        };
+        let expression = parse_expression(&lex(text)).map_err(|_| {
+            if script.script.contains('@') {
+                format!("the script `{}` is not a bonus", script.script)
+            } else {
+                format!("the script `{}` is not an expression", script.script)
+            }
+        })?;
+
+        let mut dependencies: Vec<String> = expression
+            .dependencies()
+            .iter()
+            .map(|reference| reference.qualified_name())
+            .collect();
+        if let Some(dependency) = dependencies.iter().find(|d| d.contains('-')) {
+            // scripts read it as a subtraction, the DSL as a single property
+            return Err(format!(
+                "the script `{}` uses `{}`",
+                script.script, dependency
+            ));
+        }
+        let mut expected = script.dependencies.clone();
+        dependencies.sort();
+        expected.sort();
+        expected.dedup();
+        if dependencies != expected {
+            return Err(format!(
+                "the dependencies of the script `{}` are not the properties it uses",
+                script.script
+            ));
+        }
+
+        let expression = without_spans(expression);
+        Ok(match subtract {
+            Some(true) => ModifierValue::BonusExpression(negate_first(expression)),
+            Some(false) => ModifierValue::BonusExpression(expression),
+            None => ModifierValue::SetExpression(expression),
+        })
+    }
+
+    /// Writes the dice as a sum of the groups, e.g. `2d6 - 1d4 + 2`.
+    fn dice(&self, value: &DiceValue) -> Result<Expression, String> {
+        let mut expression: Option<Expression> = None;
+        for dice in &value.dice {
+            let amount = dice
+                .amount
+                .checked_abs()
+                .filter(|amount| *amount > 0)
+                .ok_or_else(|| format!("the amount of dice in `{}` is invalid", dice))?;
+            let mut group = dice.clone();
+            group.amount = amount;
+            let group = expression_of(ExpressionKind::Dice(DiceValue {
+                dice: vec![group],
+                bonus: 0,
+            }));
+            expression = Some(add(expression, dice.amount < 0, group));
+        }
+        let expression = expression
+            .ok_or_else(|| "dice values without any dice are not supported".to_string())?;
+        if value.bonus == 0 {
+            return Ok(expression);
+        }
+        let bonus = Fraction::from_integer(value.bonus.unsigned_abs().into());
+        Ok(add(
+            Some(expression),
+            value.bonus < 0,
+            expression_of(ExpressionKind::Number(bonus)),
+        ))
+    }
+}
+
+/// Negates the first operand of a sum, so that `@ - a + b` becomes the bonus `-a + b`.
+fn negate_first(expression: Expression) -> Expression {
+    match expression.kind {
+        ExpressionKind::Binary(op, left, right) if op == "+" || op == "-" => expression_of(
+            ExpressionKind::Binary(op, Box::new(negate_first(*left)), right),
+        ),
+        kind => add(None, true, expression_of(kind)),
+    }
+}
+
+/// The parsed expression with the spans of the decompiled AST, which has no source.
+fn without_spans(expression: Expression) -> Expression {
+    let without = |expression: Box<Expression>| Box::new(without_spans(*expression));
+    expression_of(match expression.kind {
+        ExpressionKind::Reference(reference) => ExpressionKind::Reference(Reference {
+            span: Span::default(),
+            ..reference
+        }),
+        ExpressionKind::Call(name, arguments) => {
+            ExpressionKind::Call(name, arguments.into_iter().map(without_spans).collect())
+        }
+        ExpressionKind::Unary(op, inner) => ExpressionKind::Unary(op, without(inner)),
+        ExpressionKind::Binary(op, left, right) => {
+            ExpressionKind::Binary(op, without(left), without(right))
+        }
+        ExpressionKind::Parenthesized(inner) => ExpressionKind::Parenthesized(without(inner)),
+        kind => kind,
+    })
+}
+
+fn expression_of(kind: ExpressionKind) -> Expression {
+    Expression {
+        kind,
+        span: Span::default(),
+    }
+}
+
+/// Adds or subtracts `right` from `left`, or negates it if there is no `left`.
+fn add(left: Option<Expression>, subtract: bool, right: Expression) -> Expression {
+    let op = if subtract { "-" } else { "+" }.to_string();
+    match left {
+        Some(left) => expression_of(ExpressionKind::Binary(op, Box::new(left), Box::new(right))),
+        None if subtract => expression_of(ExpressionKind::Unary(op, Box::new(right))),
+        None => right,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::character_sheet_collection::{
+        CalculatedValue, Feature, FeatureModifier, FeatureSet, Script, StaticValueType,
+    };
+
+    use super::{decompile, DecompileError};
+    use crate::compiler::compile;
+    use crate::parser::parse;
+    use crate::serializer::serialize;
+    use crate::tokenizer::lex;
+
+    const FULL_COLLECTION_JSON: &str =
+        include_str!("../../types/resources/tests/full_collection.json");
+
+    fn script(script: &str) -> CalculatedValue {
+        CalculatedValue::Script(Script {
+            script: script.to_string(),
+            dependencies: vec![],
+        })
+    }
+
+    #[test]
+    fn round_trip() {
+        let feature_set = FeatureSet {
+            name: "Basic rules".to_string(),
+            source: "p. 18".to_string(),
+            features: vec![
+                Feature {
+                    name: "Dwarf".to_string(),
+                    base_type: "race".to_string(),
+                    description: "Short and sturdy".to_string(),
+                    modifiers: vec![
+                        FeatureModifier {
+                            property: "constitution".to_string(),
+                            value: script("@ + 2"),
+                        },
+                        FeatureModifier {
+                            property: "speed".to_string(),
+                            value: script("$@-5"),
+                        },
+                        FeatureModifier {
+                            property: "darkvision".to_string(),
+                            value: CalculatedValue::StaticValue(StaticValueType::Number(60)),
+                        },
+                        FeatureModifier {
+                            property: "Toughness.uses".to_string(),
+                            value: script("@ + 1"),
+                        },
+                        FeatureModifier {
+                            property: "axe".to_string(),
+                            value: CalculatedValue::StaticValue(StaticValueType::Dice(
+                                "-1d8+2d6kh1-3".parse().unwrap(),
+                            )),
+                        },
+                        FeatureModifier {
+                            property: "rage".to_string(),
+                            value: script("@ - 1d4"),
+                        },
+                    ],
+                    ..Default::default()
+                },
+                Feature {
+                    name: "Toughness".to_string(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let decompilation = decompile(&feature_set);
+        assert_eq!(decompilation.errors, vec![]);
+
+        let dsl = serialize(&decompilation.ast);
+        assert_eq!(
+            dsl,
+            r#"FeatureSet: "Basic rules";
+Source: "p. 18";
+---
+Name: "Dwarf";
+Type: "race";
+Description: "Short and sturdy";
+Modifiers:
+  +2 constitution;
+  -5 speed;
+  set darkvision to 60;
+  +1 Toughness.uses;
+  set axe to -1d8 + 2d6kh1 - 3;
+  -1d4 rage;
+---
+Name: "Toughness";
+"#
+        );
+
+        let mut expected = feature_set.clone();
+        expected.features[0].modifiers[1].value = script("@ - 5");
+        let compiled = compile(&parse(&lex(&dsl)).unwrap().ast);
+        assert_eq!(compiled.feature_sets, vec![expected]);
+    }
+
+    #[test]
+    fn scripts() {
+        let script = |property: &str, script: &str, dependencies: &[&str]| FeatureModifier {
+            property: property.to_string(),
+            value: CalculatedValue::Script(Script {
+                script: script.to_string(),
+                dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+            }),
+        };
+        let feature_set = FeatureSet {
+            features: vec![Feature {
+                name: "Rogue".to_string(),
+                modifiers: vec![
+                    script("hp", "8 + constitution", &["constitution"]),
+                    script("attack", "@ + strength", &["strength"]),
+                    script("damage", "@ - Rogue.level / 2 + 1", &["Rogue.level"]),
+                    script("ac", "max(10 + dexterity, 12)", &["dexterity"]),
+                    script("speed", "@ + 2d6 + 1", &[]),
+                    script("initiative", "@ + dexterity", &[]),
+                    script("stealth", "dexterity > 10", &["dexterity"]),
+                    script("reach", "@ + hit-dice", &["hit-dice"]),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let decompilation = decompile(&feature_set);
+        assert_eq!(
+            decompilation
+                .errors
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "Feature Rogue: the modifier of initiative can not be expressed in the DSL yet (the dependencies of the script `@ + dexterity` are not the properties it uses)",
+                "Feature Rogue: the modifier of stealth can not be expressed in the DSL yet (the script `dexterity > 10` is not an expression)",
+                "Feature Rogue: the modifier of reach can not be expressed in the DSL yet (the script `@ + hit-dice` uses `hit-dice`)",
+            ]
+        );
+        let dsl = serialize(&decompilation.ast);
+        assert_eq!(
+            dsl,
+            r#"Name: "Rogue";
+Modifiers:
+  set hp to 8 + constitution;
+  bonus to attack of strength;
+  bonus to damage of -(Rogue.level / 2) + 1;
+  set ac to max(10 + dexterity, 12);
+  bonus to speed of 2d6 + 1;
+"#
+        );
+
+        let compiled = compile(&parse(&lex(&dsl)).unwrap().ast);
+        assert_eq!(compiled.errors, vec![]);
+        assert_eq!(
+            compiled.feature_sets[0].features[0].modifiers,
+            vec![
+                script("hp", "8 + constitution", &["constitution"]),
+                script("attack", "@ + strength", &["strength"]),
+                script("damage", "@ + -(Rogue.level / 2) + 1", &["Rogue.level"]),
+                script("ac", "max(10 + dexterity, 12)", &["dexterity"]),
+                script("speed", "@ + 2d6 + 1", &[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn report_unsupported() {
+        let feature_sets: Vec<FeatureSet> = serde_json::from_str(FULL_COLLECTION_JSON).unwrap();
+        let decompilation = decompile(&feature_sets[0]);
+
+        let modifier_error = |property: &str, reason: &str| DecompileError::Modifier {
+            feature: "feature1".to_string(),
+            property: property.to_string(),
+            reason: reason.to_string(),
+        };
+        assert_eq!(
+            decompilation.errors,
+            vec![
+                DecompileError::Definition {
+                    feature: "feature1".to_string(),
+                    property: "property1".to_string(),
+                    reason: "`property1` is not a valid property name".to_string()
+                },
+                modifier_error("property1", "`property1` is not a valid property name"),
+                modifier_error("property2", "`property2` is not a valid property name"),
+                modifier_error("property3", "`property3` is not a valid property name"),
+            ]
+        );
+        assert_eq!(decompilation.ast.model.features[0].name, "feature1");
+        assert!(decompilation.ast.model.features[0].modifiers.is_empty());
+
+        let feature_set = FeatureSet {
+            features: vec![Feature {
+                name: "Rage".to_string(),
+                description: "Roar \"loudly\"".to_string(),
+                modifiers: vec![
+                    FeatureModifier {
+                        property: "damage".to_string(),
+                        value: script("@ * 2"),
+                    },
+                    FeatureModifier {
+                        property: "bonus".to_string(),
+                        value: CalculatedValue::StaticValue(StaticValueType::Dice(
+                            Default::default(),
+                        )),
+                    },
+                    FeatureModifier {
+                        property: "reach".to_string(),
+                        value: CalculatedValue::StaticValue(StaticValueType::Fraction(
+                            "1/3".parse().unwrap(),
+                        )),
+                    },
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            decompile(&feature_set)
+                .errors
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "Feature Rage: the description contains a `\"`, which can not be expressed in the DSL",
+                "Feature Rage: the modifier of damage can not be expressed in the DSL yet (the script `@ * 2` is not a bonus)",
+                "Feature Rage: the modifier of bonus can not be expressed in the DSL yet (dice values without any dice are not supported)",
+                "Feature Rage: the modifier of reach can not be expressed in the DSL yet (the fraction 1/3 can not be written as a decimal)",
+            ]
+        );
+    }
+}
@@ -1,4 +1,11 @@
 pub mod tokenizer;
 pub mod parser;
 pub mod serializer;
+pub mod compiler;
+pub mod decompiler;
+pub mod span;
+pub mod lint;
+pub mod resolver;
 
+#[cfg(test)]
+mod round_trip;
@@ -0,0 +1,352 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use types::fraction::Fraction;
+
+use crate::parser::ast::{Feature, ModifierValue, AST};
+use crate::span::{LineIndex, Span};
+
+/// All lints. Each lint can be configured individually with a [`LintConfig`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LintCode {
+    /// Multiple features have the same name.
+    DuplicateFeature,
+    /// A feature has no description.
+    EmptyDescription,
+    /// A feature sets the same property multiple times.
+    MultipleSets,
+    /// A bonus of 0.
+    ZeroBonus,
+    /// A property name that is very similar to another one.
+    PossibleTypo,
+}
+
+impl LintCode {
+    pub const ALL: [LintCode; 5] = [
+        LintCode::DuplicateFeature,
+        LintCode::EmptyDescription,
+        LintCode::MultipleSets,
+        LintCode::ZeroBonus,
+        LintCode::PossibleTypo,
+    ];
+
+    /// The name used to refer to the lint, e.g. in configurations.
+    pub fn name(&self) -> &'static str {
+        match self {
+            LintCode::DuplicateFeature => "duplicate-feature",
+            LintCode::EmptyDescription => "empty-description",
+            LintCode::MultipleSets => "multiple-sets",
+            LintCode::ZeroBonus => "zero-bonus",
+            LintCode::PossibleTypo => "possible-typo",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<LintCode> {
+        LintCode::ALL.into_iter().find(|code| code.name() == name)
+    }
+
+    pub fn default_level(&self) -> Level {
+        match self {
+            LintCode::DuplicateFeature => Level::Warning,
+            LintCode::EmptyDescription => Level::Info,
+            LintCode::MultipleSets => Level::Warning,
+            LintCode::ZeroBonus => Level::Warning,
+            LintCode::PossibleTypo => Level::Warning,
+        }
+    }
+}
+
+impl fmt::Display for LintCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// How a lint is reported. Denied lints make the parse fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Level {
+    Allow,
+    Info,
+    Warning,
+    Deny,
+}
+
+impl Level {
+    /// How diagnostics of this level are labeled, e.g. `warning`.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Level::Allow => "allowed",
+            Level::Info => "info",
+            Level::Warning => "warning",
+            Level::Deny => "error",
+        }
+    }
+}
+
+/// The levels of all lints. Lints that are not configured use their default level.
+#[derive(Debug, Clone, Default)]
+pub struct LintConfig {
+    levels: HashMap<LintCode, Level>,
+}
+
+impl LintConfig {
+    pub fn set(&mut self, code: LintCode, level: Level) -> &mut Self {
+        self.levels.insert(code, level);
+        self
+    }
+
+    pub fn allow(&mut self, code: LintCode) -> &mut Self {
+        self.set(code, Level::Allow)
+    }
+
+    pub fn deny(&mut self, code: LintCode) -> &mut Self {
+        self.set(code, Level::Deny)
+    }
+
+    pub fn level(&self, code: LintCode) -> Level {
+        self.levels
+            .get(&code)
+            .copied()
+            .unwrap_or_else(|| code.default_level())
+    }
+}
+
+/// A problem found by a lint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub code: LintCode,
+    pub level: Level,
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    /// Renders the diagnostic with its line and column and underlines it in the source line.
+    pub fn render(&self, index: &LineIndex) -> String {
+        let label = format!("{}[{}]", self.level.label(), self.code);
+        index.render_labeled(self.span, &label, &self.message)
+    }
+
+    /// Like [`render`](Diagnostic::render), but the location starts with the name of the file.
+    pub fn render_in_file(&self, file: &str, index: &LineIndex) -> String {
+        let label = format!("{}[{}]", self.level.label(), self.code);
+        index.render_in_file(file, self.span, &label, &self.message)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}]", self.message, self.code)
+    }
+}
+
+/// Runs all lints that are not allowed on the AST.
+pub fn lint(ast: &AST, config: &LintConfig) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        config,
+        diagnostics: vec![],
+    };
+
+    let mut feature_names: Vec<&str> = vec![];
+    for feature in &ast.model.features {
+        if feature_names.contains(&feature.name.as_str()) {
+            let message = format!("The feature `{}` is defined multiple times", feature.name);
+            linter.report(LintCode::DuplicateFeature, feature.span, message);
+        } else {
+            feature_names.push(&feature.name);
+        }
+
+        if feature.description.trim().is_empty() {
+            let message = format!("The feature `{}` has no description", feature.name);
+            linter.report(LintCode::EmptyDescription, feature.span, message);
+        }
+
+        linter.modifiers(feature);
+    }
+
+    linter.typos(ast);
+
+    linter.diagnostics.sort_by_key(|d| d.span.start);
+    linter.diagnostics
+}
+
+struct Linter<'a> {
+    config: &'a LintConfig,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter<'_> {
+    fn report(&mut self, code: LintCode, span: Span, message: String) {
+        let level = self.config.level(code);
+        if level != Level::Allow {
+            self.diagnostics.push(Diagnostic {
+                code,
+                level,
+                message,
+                span,
+            });
+        }
+    }
+
+    fn modifiers(&mut self, feature: &Feature) {
+        let mut set: HashSet<String> = HashSet::new();
+        for modifier in &feature.modifiers {
+            let property = modifier.referencing.qualified_name();
+            match &modifier.value {
+                ModifierValue::Set(_) | ModifierValue::SetExpression(_) => {
+                    if !set.insert(property.clone()) {
+                        let message = format!(
+                            "`{}` is set multiple times in the feature `{}`, only one value is used",
+                            property, feature.name
+                        );
+                        self.report(LintCode::MultipleSets, modifier.span, message);
+                    }
+                }
+                ModifierValue::SimpleBonus(n) | ModifierValue::Bonus(n)
+                    if *n == Fraction::from_integer(0) =>
+                {
+                    let message = format!("The bonus of 0 to `{}` has no effect", property);
+                    self.report(LintCode::ZeroBonus, modifier.span, message);
+                }
+                ModifierValue::SimpleBonus(_)
+                | ModifierValue::Bonus(_)
+                | ModifierValue::SimpleDiceBonus(_)
+                | ModifierValue::BonusExpression(_) => {}
+            }
+        }
+    }
+
+    /// Reports references to properties whose name is very similar to a more common one.
+    fn typos(&mut self, ast: &AST) {
+        let references: Vec<(String, Span)> = ast
+            .model
+            .features
+            .iter()
+            .flat_map(|f| &f.modifiers)
+            .map(|m| (m.referencing.qualified_name(), m.referencing.span))
+            .collect();
+        // how often each name is referenced and where it is referenced first
+        let mut counts: HashMap<&str, (usize, usize)> = HashMap::new();
+        for (i, (name, _)) in references.iter().enumerate() {
+            counts.entry(name).or_insert((0, i)).0 += 1;
+        }
+        // the more common name is preferred, on ties the one that is referenced first
+        let preferred = |a: &str, b: &str| {
+            let (a, b) = (counts[a], counts[b]);
+            a.0 > b.0 || (a.0 == b.0 && a.1 < b.1)
+        };
+
+        for (name, span) in &references {
+            let similar = counts
+                .keys()
+                .filter(|other| *other != name && preferred(other, name) && is_similar(name, other))
+                .min_by_key(|other| counts[*other].1);
+            if let Some(similar) = similar {
+                let message = format!("`{}` looks like a typo of `{}`", name, similar);
+                self.report(LintCode::PossibleTypo, *span, message);
+            }
+        }
+    }
+}
+
+/// Whether two names differ by only a few characters, ignoring case.
+fn is_similar(a: &str, b: &str) -> bool {
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+    let allowed = if a.len().min(b.len()) <= 4 { 1 } else { 2 };
+    a.len().abs_diff(b.len()) <= allowed && edit_distance(&a, &b) <= allowed
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint, Level, LintCode, LintConfig};
+    use crate::parser::parse;
+    use crate::span::LineIndex;
+    use crate::tokenizer::lex;
+
+    const INPUT: &str = r#"Name: "Dwarf";
+Description: "Short and sturdy";
+Modifiers:
+  +2 constitution;
+  set speed to 25;
+  +0 wisdom;
+  set speed to 30;
+---
+Name: "Dwarf";
+Modifiers:
+  +1 Constitution;
+  bonus to wisdom of 1;
+  +1 constitution;
+  +1 dexterity;
+  +1 dexterty;
+"#;
+
+    #[test]
+    fn lints() {
+        let ast = parse(&lex(INPUT)).unwrap().ast;
+        let diagnostics = lint(&ast, &LintConfig::default());
+        let found: Vec<(LintCode, Level, &str)> = diagnostics
+            .iter()
+            .map(|d| (d.code, d.level, &INPUT[d.span.start..d.span.end]))
+            .collect();
+
+        assert_eq!(
+            found,
+            vec![
+                (LintCode::ZeroBonus, Level::Warning, "+0 wisdom;"),
+                (LintCode::MultipleSets, Level::Warning, "set speed to 30;"),
+                (
+                    LintCode::DuplicateFeature,
+                    Level::Warning,
+                    &INPUT[INPUT.find("Name: \"Dwarf\";\nModifiers").unwrap()..INPUT.len() - 1]
+                ),
+                (
+                    LintCode::EmptyDescription,
+                    Level::Info,
+                    &INPUT[INPUT.find("Name: \"Dwarf\";\nModifiers").unwrap()..INPUT.len() - 1]
+                ),
+                (LintCode::PossibleTypo, Level::Warning, "Constitution"),
+                (LintCode::PossibleTypo, Level::Warning, "dexterty"),
+            ]
+        );
+        assert_eq!(
+            diagnostics[4].render(&LineIndex::new(INPUT)),
+            "warning[possible-typo]: `Constitution` looks like a typo of `constitution`\n  --> 11:6\n   |\n11 |   +1 Constitution;\n   |      ^^^^^^^^^^^^"
+        );
+    }
+
+    #[test]
+    fn configuration() {
+        let mut config = LintConfig::default();
+        config
+            .allow(LintCode::EmptyDescription)
+            .deny(LintCode::ZeroBonus);
+        let ast = parse(&lex(INPUT)).unwrap().ast;
+        let codes: Vec<(LintCode, Level)> = lint(&ast, &config)
+            .iter()
+            .map(|d| (d.code, d.level))
+            .collect();
+        assert!(!codes
+            .iter()
+            .any(|(code, _)| *code == LintCode::EmptyDescription));
+        assert!(codes.contains(&(LintCode::ZeroBonus, Level::Deny)));
+        assert_eq!(
+            LintCode::from_name("possible-typo"),
+            Some(LintCode::PossibleTypo)
+        );
+    }
+}
@@ -1,13 +1,28 @@
 pub mod ast;
 
 use thiserror::Error;
+use types::character_sheet_collection::{DiceValue, RoundingMode};
+use types::fraction::Fraction;
 
 use ast::Feature;
+use ast::Header;
+use ast::Import;
+use ast::Comment;
+use ast::Definition;
+use ast::Expression;
+use ast::ExpressionKind;
+use ast::Invocation;
 use ast::AST;
 use ast::Model;
 use ast::Modifier;
 use ast::Reference;
 
+use crate::lint::lint;
+use crate::lint::Diagnostic;
+use crate::lint::Level;
+use crate::lint::LintConfig;
+use crate::span::LineIndex;
+use crate::span::Span;
 use crate::tokenizer;
 use tokenizer::validate;
 use tokenizer::Token;
@@ -17,63 +32,193 @@ use tokenizer::TokenizationIssue;
 #[derive(Debug)]
 pub struct ParseSuccess {
     pub ast: AST,
-    pub warnings: Vec<String>, // todo
-    pub infos: Vec<String>,    // todo
+    pub warnings: Vec<Diagnostic>,
+    pub infos: Vec<Diagnostic>,
 }
 
 #[derive(Debug)]
 pub struct ParseFailure {
     pub errors: Vec<ParseError>,
+    /// The features that could be parsed without errors.
+    pub ast: AST,
 }
 
 #[derive(Debug, Error)]
 pub enum ParseError {
-    #[error("Unexpected token at offset {}: {} (expected: {})", .token.offset, .token.token_type.get_string(), .expected)]
+    #[error("Unexpected token: {} (expected: {})", .token.token_type.get_string(), .expected)]
     UnexpectedToken { token: Token, expected: String },
-    #[error("Unexpected text at offset {}: {}", .0, .1)]
-    UnexpectedText(i32, String),
-    #[error("Unknown error at offset {0}")]
-    UnknownError(i32),
+    #[error("Unexpected text: {}", .1)]
+    UnexpectedText(Span, String),
+    #[error("Unknown error")]
+    UnknownError(Span),
+    #[error("Unterminated block comment")]
+    UnterminatedComment(Span),
+    #[error("The number {1} is too large")]
+    NumberTooLarge(Span, String),
+    #[error("Unknown feature `{1}`")]
+    UnknownFeature(Span, String),
+    #[error("{1}")]
+    InvalidRounding(Span, String),
+    #[error("Invalid dice: {1}")]
+    InvalidDice(Span, String),
+    #[error("Expressions can not be nested more than {MAX_NESTING} levels deep")]
+    TooDeeplyNested(Span),
+    #[error("{0}")]
+    DeniedLint(Diagnostic),
 }
 
-pub fn parse(tokens: &[Token]) -> Result<ParseSuccess, ParseFailure> {
-    match validate(tokens) {
-        Some(errors) => Err(ParseFailure {
-            errors: errors
-                .iter()
-                .map(from_tokenization_issue)
-                .collect::<Vec<_>>(),
-        }),
-        None => do_parse(tokens),
+impl ParseError {
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::UnexpectedToken { token, .. } => token.span,
+            ParseError::UnexpectedText(span, _) => *span,
+            ParseError::UnknownError(span) => *span,
+            ParseError::UnterminatedComment(span) => *span,
+            ParseError::NumberTooLarge(span, _) => *span,
+            ParseError::UnknownFeature(span, _) => *span,
+            ParseError::InvalidRounding(span, _) => *span,
+            ParseError::InvalidDice(span, _) => *span,
+            ParseError::TooDeeplyNested(span) => *span,
+            ParseError::DeniedLint(diagnostic) => diagnostic.span,
+        }
+    }
+
+    /// Renders the error with its line and column and underlines it in the source line.
+    pub fn render(&self, index: &LineIndex) -> String {
+        index.render(self.span(), &self.to_string())
     }
 }
 
-fn from_tokenization_issue(ti: &TokenizationIssue) -> ParseError {
-    match ti {
-        TokenizationIssue::UnknownToken(offset, text) => {
-            ParseError::UnexpectedText(*offset, text.clone())
+/// Parses all tokens, even if there are errors.
+///
+/// On errors the parser skips to the next `;` or `---` and continues, so that all errors of a
+/// file are reported at once. The AST of a failed parse contains all features without errors.
+pub fn parse(tokens: &[Token]) -> Result<ParseSuccess, ParseFailure> {
+    parse_with(tokens, &LintConfig::default())
+}
+
+/// Like [`parse`], but with configured lints.
+/// Lints with the level [`Level::Deny`] are reported as errors.
+pub fn parse_with(tokens: &[Token], lints: &LintConfig) -> Result<ParseSuccess, ParseFailure> {
+    let mut errors = validate(tokens)
+        .map(|issues| issues.iter().map(from_tokenization_issue).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let mut parser = Parser {
+        tokens,
+        position: usize::MAX,
+        references: Vec::new(),
+        errors: Vec::new(),
+        comments: Vec::new(),
+        last_span: Span::default(),
+        depth: 0,
+    };
+    let model = parser.model();
+    let ast = AST { model, references: parser.references };
+
+    errors.append(&mut parser.errors);
+    if ast.model.imports.is_empty() {
+        // otherwise the features may be imported, which is checked by the resolver
+        errors.extend(unknown_features(&ast, &[]));
+    }
+    let mut warnings = vec![];
+    let mut infos = vec![];
+    for diagnostic in lint(&ast, lints) {
+        match diagnostic.level {
+            Level::Deny => errors.push(ParseError::DeniedLint(diagnostic)),
+            Level::Warning => warnings.push(diagnostic),
+            Level::Info => infos.push(diagnostic),
+            Level::Allow => {}
         }
     }
+
+    if errors.is_empty() {
+        Ok(ParseSuccess {
+            ast,
+            warnings,
+            infos,
+        })
+    } else {
+        errors.sort_by_key(|e| e.span().start);
+        Err(ParseFailure { errors, ast })
+    }
 }
 
-fn do_parse(tokens: &[Token]) -> Result<ParseSuccess, ParseFailure> {
+/// Parses a single expression, e.g. the value of a script without the `@ +` of a bonus.
+/// Only the first error is reported, because there are no modifiers to recover at.
+pub(crate) fn parse_expression(tokens: &[Token]) -> Result<Expression, ParseError> {
+    if let Some(error) = validate(tokens).and_then(|issues| issues.first().map(from_tokenization_issue)) {
+        return Err(error);
+    }
+
     let mut parser = Parser {
         tokens,
         position: usize::MAX,
         references: Vec::new(),
+        errors: Vec::new(),
+        comments: Vec::new(),
+        last_span: Span::default(),
+        depth: 0,
     };
-    let model = parser.model()?;
-    Ok(ParseSuccess {
-        ast: AST { model, references: parser.references },
-        infos: vec![],
-        warnings: vec![],
-    })
+    let expression = parser.expression()?;
+    parser.expect_explicit(&TokenType::EndOfInput, "end of the expression".to_string())?;
+    Ok(expression)
+}
+
+/// Checks that the features of all feature-qualified references exist, either in the file itself
+/// or in the imported ones.
+pub(crate) fn unknown_features(ast: &AST, imported: &[String]) -> Vec<ParseError> {
+    ast.references
+        .iter()
+        .filter_map(|reference| match &reference.scope {
+            ast::Scope::Feature(feature)
+                if !ast.model.features.iter().any(|f| &f.name == feature) && !imported.contains(feature) =>
+            {
+                Some(ParseError::UnknownFeature(reference.span, feature.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn from_tokenization_issue(ti: &TokenizationIssue) -> ParseError {
+    match ti {
+        TokenizationIssue::UnknownToken(span, text) => {
+            ParseError::UnexpectedText(*span, text.clone())
+        }
+        TokenizationIssue::UnterminatedComment(span) => ParseError::UnterminatedComment(*span),
+        TokenizationIssue::NumberTooLarge(span, text) => {
+            ParseError::NumberTooLarge(*span, text.clone())
+        }
+    }
+}
+
+/// Numbers in the DSL are never negative, so they can always be negated.
+fn negate(number: Fraction) -> Fraction {
+    number.checked_neg().expect("numbers are not negative")
+}
+
+/// How deep parentheses, calls and signs can be nested in an expression, so that parsing them
+/// can not overflow the stack.
+pub const MAX_NESTING: usize = 64;
+
+/// Tokens the parser skips. Unknown tokens are already reported by `validate`, comments are
+/// collected separately and attached to the nearest node.
+fn is_skipped(token_type: &TokenType) -> bool {
+    matches!(token_type, TokenType::Whitespace(_) | TokenType::Unknown(_) | TokenType::Comment(_))
 }
 
 struct Parser<'a> {
     tokens: &'a [Token],
     position: usize,
     references: Vec<Reference>,
+    errors: Vec<ParseError>,
+    /// Comments that were skipped, but not yet attached to a node.
+    comments: Vec<Comment>,
+    /// The span of the last token that is not skipped.
+    last_span: Span,
+    /// How many expressions the current expression is nested in.
+    depth: usize,
 }
 
 impl Parser<'_> {
@@ -86,44 +231,81 @@ impl Parser<'_> {
     }
 
     fn next_non_ws(&mut self) -> Token {
-        if self.position == usize::MAX {
-            self.position = 0;
-            self.curr()
-        } else if matches!(self.curr_t(), TokenType::EndOfInput) {
-            self.curr()
-        } else {
-            loop {
-                self.position += 1;
-                if !matches!(self.curr_t(), TokenType::Whitespace(_)) {
-                    break;
-                }
+        let next = self.peek_position();
+        self.collect_comments(next);
+        self.position = next;
+        self.last_span = self.curr().span;
+        self.curr()
+    }
+
+    /// Skips everything up to the next token that is not skipped, so that all comments in front
+    /// of it are collected.
+    fn skip_skipped(&mut self) {
+        let next = self.peek_position();
+        self.collect_comments(next);
+        if next > 0 && (self.position == usize::MAX || next - 1 > self.position) {
+            self.position = next - 1;
+        }
+    }
+
+    fn collect_comments(&mut self, until: usize) {
+        let from = self.position.wrapping_add(1);
+        if from >= until {
+            return;
+        }
+        for token in &self.tokens[from..until] {
+            if let TokenType::Comment(text) = &token.token_type {
+                self.comments.push(Comment {
+                    text: text.clone(),
+                    span: token.span,
+                });
+            }
+        }
+    }
+
+    /// Takes a comment that is on the same line as the current token.
+    fn trailing_comment(&mut self) -> Option<Comment> {
+        let mut pos = self.position + 1;
+        if let TokenType::Whitespace(ws) = &self.tokens[pos].token_type {
+            if ws.contains('\n') {
+                return None;
             }
-            self.curr()
+            pos += 1;
+        }
+        match &self.tokens[pos].token_type {
+            TokenType::Comment(text) => {
+                self.position = pos;
+                Some(Comment {
+                    text: text.clone(),
+                    span: self.tokens[pos].span,
+                })
+            }
+            _ => None,
         }
     }
 
     fn peek_non_ws(&mut self) -> Token {
-        if self.position == usize::MAX {
-            self.tokens[0].clone()
-        } else if self.curr_t() == TokenType::EndOfInput {
-            self.curr()
-        } else {
-            let mut pos = self.position;
-            loop {
-                pos += 1;
-                if !matches!(self.tokens[pos].token_type, TokenType::Whitespace(_)) {
-                    break;
-                }
+        self.tokens[self.peek_position()].clone()
+    }
+
+    fn peek_position(&self) -> usize {
+        let mut pos = self.position;
+        if pos != usize::MAX && self.tokens[pos].token_type == TokenType::EndOfInput {
+            return pos;
+        }
+        loop {
+            pos = pos.wrapping_add(1);
+            if !is_skipped(&self.tokens[pos].token_type) {
+                return pos;
             }
-            self.tokens[pos].clone()
         }
     }
 
-    fn expect(&mut self, token_type: &TokenType) -> Result<(), ParseFailure> {
+    fn expect(&mut self, token_type: &TokenType) -> Result<(), ParseError> {
         self.expect_explicit(token_type, token_type.get_string())
     }
 
-    fn expect_explicit(&mut self, token_type: &TokenType, expected: String) -> Result<(), ParseFailure> {
+    fn expect_explicit(&mut self, token_type: &TokenType, expected: String) -> Result<(), ParseError> {
         if !(&self.next_non_ws().token_type == token_type) {
             Err(self.fail(expected))
         } else {
@@ -140,74 +322,313 @@ impl Parser<'_> {
         }
     }
 
-    fn accept(&mut self, token: &TokenType, expected: String) -> Result<String, ParseFailure> {
+    fn accept(&mut self, token: &TokenType, expected: String) -> Result<String, ParseError> {
+        Ok(self.accept_token(token, expected)?.token_type.get_string())
+    }
+
+    fn accept_token(&mut self, token: &TokenType, expected: String) -> Result<Token, ParseError> {
         let next = self.next_non_ws();
         if !next.token_type.eq_type(token) {
             Err(self.fail(expected))
         } else {
-            Ok(next.token_type.get_string())
+            Ok(next)
         }
     }
 
-    fn fail(&self, expected: String) -> ParseFailure {
-        ParseFailure {
-            errors: vec![ParseError::UnexpectedToken {
-                token: self.curr().clone(),
-                expected,
-            }],
+    /// A property, optionally qualified with the feature it belongs to: `attack` or
+    /// `Longsword.attack`.
+    fn reference(&mut self) -> Result<Reference, ParseError> {
+        let first = self.accept_token(&TokenType::Identifier("".to_string()), "identifier".to_string())?;
+        self.qualified_reference(first)
+    }
+
+    /// The rest of a reference whose first identifier is already consumed.
+    fn qualified_reference(&mut self, first: Token) -> Result<Reference, ParseError> {
+        let reference = if self.peek_non_ws().token_type == TokenType::Dot {
+            self.next_non_ws();
+            let property = self.accept_token(&TokenType::Identifier("".to_string()), "identifier".to_string())?;
+            Reference {
+                scope: ast::Scope::Feature(first.token_type.get_string()),
+                name: property.token_type.get_string(),
+                span: first.span.to(property.span),
+            }
+        } else {
+            Reference {
+                scope: ast::Scope::Character,
+                name: first.token_type.get_string(),
+                span: first.span,
+            }
+        };
+        self.references.push(reference.clone());
+        Ok(reference)
+    }
+
+    fn number(&mut self) -> Result<Fraction, ParseError> {
+        let token = self.accept_token(&TokenType::Number(Fraction::from_integer(0)), "number".to_string())?;
+        match token.token_type {
+            TokenType::Number(number) => Ok(number),
+            _ => unreachable!("accept_token only returns numbers"),
+        }
+    }
+
+    fn dice(&mut self) -> Result<DiceValue, ParseError> {
+        let token = self.accept_token(&TokenType::Dice("".to_string()), "dice".to_string())?;
+        token.token_type.get_string().parse::<DiceValue>()
+            .map_err(|e| ParseError::InvalidDice(token.span, e.kind.to_string()))
+    }
+
+    fn fail(&self, expected: String) -> ParseError {
+        ParseError::UnexpectedToken {
+            token: self.curr().clone(),
+            expected,
+        }
+    }
+
+    /// Skips tokens after an error until the end of the modifier (`;`, which is consumed) or of
+    /// the feature (`---`, which is not consumed).
+    fn recover(&mut self, until_semicolon: bool) {
+        loop {
+            match self.curr_t() {
+                TokenType::Semicolon if until_semicolon => return,
+                TokenType::Section | TokenType::EndOfInput => {
+                    // step back, so that the next token is the end of the feature again
+                    self.position = self.position.checked_sub(1).unwrap_or(usize::MAX);
+                    return;
+                }
+                _ => {
+                    self.next_non_ws();
+                }
+            }
         }
     }
 
     // recursive descent
-    fn model(&mut self) -> Result<Model, ParseFailure> {
+    fn model(&mut self) -> Model {
+        let mut imports = Vec::new();
+        loop {
+            self.skip_skipped();
+            if self.peek_non_ws().token_type != TokenType::Identifier("Import".to_string()) {
+                break;
+            }
+            let comments = std::mem::take(&mut self.comments);
+            match self.import() {
+                Ok(mut import) => {
+                    import.comments = comments;
+                    import.trailing_comment = self.trailing_comment();
+                    imports.push(import);
+                }
+                Err(error) => {
+                    self.errors.push(error);
+                    self.recover(true);
+                }
+            }
+        }
+        if !imports.is_empty() && self.peek_non_ws().token_type == TokenType::EndOfInput {
+            return Model { imports, header: None, features: Vec::new() };
+        }
+
+        let mut header = None;
+        if self.peek_non_ws().token_type == TokenType::Identifier("FeatureSet".to_string()) {
+            let error_count = self.errors.len();
+            match self.header() {
+                Ok(parsed) if self.errors.len() == error_count => header = Some(Box::new(parsed)),
+                Ok(_) => {}
+                Err(error) => {
+                    self.errors.push(error);
+                    self.recover(false);
+                    self.comments.clear();
+                }
+            }
+            if !self.end_of_section() {
+                return Model { imports, header, features: Vec::new() };
+            }
+        }
+
         let mut features = Vec::new();
-        features.push(self.feature()?);
+        loop {
+            let error_count = self.errors.len();
+            match self.feature() {
+                Ok(feature) if self.errors.len() == error_count => features.push(feature),
+                Ok(_) => {} // the feature is incomplete, the errors are already recorded
+                Err(error) => {
+                    self.errors.push(error);
+                    self.recover(false);
+                    self.comments.clear();
+                }
+            }
 
-        while let TokenType::Section = self.curr().token_type {
-            self.next_non_ws();
-            features.push(self.feature()?);
+            if !self.end_of_section() {
+                break;
+            }
         }
 
-        Ok(Model { features })
+        Model { imports, header, features }
+    }
+
+    fn import(&mut self) -> Result<Import, ParseError> {
+        self.expect(&TokenType::Identifier("Import".to_string()))?;
+        let start = self.curr().span;
+        let path = self.accept(&TokenType::String("".to_string()), "path".to_string())?;
+        self.expect(&TokenType::Semicolon)?;
+
+        Ok(Import {
+            path,
+            span: start.to(self.curr().span),
+            comments: vec![],
+            trailing_comment: None,
+        })
+    }
+
+    /// Consumes the `---` after the header or a feature. Returns false at the end of the input.
+    fn end_of_section(&mut self) -> bool {
+        match self.peek_non_ws().token_type {
+            TokenType::Section => {
+                self.next_non_ws();
+                true
+            }
+            TokenType::EndOfInput => false,
+            _ => {
+                self.next_non_ws();
+                let error = self.fail("--- or End of Input".to_string());
+                self.errors.push(error);
+                self.recover(false);
+                if self.peek_non_ws().token_type == TokenType::EndOfInput {
+                    return false;
+                }
+                self.next_non_ws(); // skip "---"
+                true
+            }
+        }
+    }
+
+    fn header(&mut self) -> Result<Header, ParseError> {
+        self.skip_skipped();
+        let comments = std::mem::take(&mut self.comments);
+        self.expect(&TokenType::Identifier("FeatureSet".to_string()))?;
+        let start = self.curr().span;
+        self.expect(&TokenType::Colon)?;
+        let name = self.accept(&TokenType::String("".to_string()), "name".to_string())?;
+        self.expect(&TokenType::Semicolon)?;
+        let name_comment = self.trailing_comment();
+        let (description, description_comment) = self.string_field("Description")?.unwrap_or_default();
+        let (source, source_comment) = self.string_field("Source")?.unwrap_or_default();
+
+        let span = start.to(self.last_span);
+        self.skip_skipped();
+        Ok(Header {
+            name,
+            description,
+            source,
+            span,
+            comments,
+            name_comment,
+            description_comment,
+            source_comment,
+            trailing_comments: std::mem::take(&mut self.comments),
+        })
     }
 
-    fn feature(&mut self) -> Result<Feature, ParseFailure> {
+    /// An optional `Key: "value";` field, with the comment on the same line after it.
+    fn string_field(&mut self, key: &str) -> Result<Option<(String, Option<Comment>)>, ParseError> {
+        if self.peek_expect(&TokenType::Identifier(key.to_string())).is_none() {
+            return Ok(None);
+        }
+        let _ = self.next_non_ws(); // skip the key
+        self.expect(&TokenType::Colon)?;
+        let value = self.accept(&TokenType::String("".to_string()), "string".to_string())?;
+        self.expect(&TokenType::Semicolon)?;
+        Ok(Some((value, self.trailing_comment())))
+    }
+
+    fn feature(&mut self) -> Result<Feature, ParseError> {
+        self.skip_skipped();
+        let comments = std::mem::take(&mut self.comments);
         self.expect(&TokenType::Identifier("Name".to_string()))?;
+        let start = self.curr().span;
         self.expect(&TokenType::Colon)?;
         let name = self.accept(&TokenType::String("".to_string()), "name".to_string())?;
         self.expect(&TokenType::Semicolon)?;
+        let name_comment = self.trailing_comment();
+        let (base_type, type_comment) = self.string_field("Type")?.unwrap_or_default();
+        let (description, description_comment) = self.string_field("Description")?.unwrap_or_default();
 
-        let description = self
-            .peek_expect(&TokenType::Identifier("Description".to_string()))
-            .map(|_| -> Result<String, ParseFailure> {
-                let _ = self.next_non_ws(); // skip "Description"
+        let definitions = self
+            .peek_expect(&TokenType::Identifier("Definitions".to_string()))
+            .map(|_| -> Result<Vec<Definition>, ParseError> {
+                let _ = self.next_non_ws(); // skip "Definitions"
                 self.expect(&TokenType::Colon)?;
-                let desc = self.accept(&TokenType::String("".to_string()), "string".to_string())?;
-                self.expect(&TokenType::Semicolon)?;
 
-                Ok(desc)
+                let mut definitions: Vec<Definition> = vec![];
+                loop {
+                    self.skip_skipped();
+                    let comments = std::mem::take(&mut self.comments);
+                    let definition = match self.peek_non_ws().token_type {
+                        TokenType::Identifier(m) if m == "Modifiers" => {
+                            // they belong to the next node instead
+                            self.comments = comments;
+                            break;
+                        }
+                        TokenType::Identifier(_) => self.definition(),
+                        TokenType::Section | TokenType::EndOfInput => {
+                            self.comments = comments;
+                            break;
+                        }
+                        _ => {
+                            self.next_non_ws();
+                            Err(self.fail("definition or Modifiers".to_string()))
+                        }
+                    };
+                    match definition {
+                        Ok(mut definition) => {
+                            definition.comments = comments;
+                            definition.trailing_comment = self.trailing_comment();
+                            definitions.push(definition);
+                        }
+                        Err(error) => {
+                            self.errors.push(error);
+                            self.recover(true);
+                        }
+                    }
+                }
+
+                Ok(definitions)
             })
-            .unwrap_or(Ok("".to_string()))?;
+            .unwrap_or_else(|| Ok(vec![]))?;
 
         let modifiers = self
             .peek_expect(&TokenType::Identifier("Modifiers".to_string()))
-            .map(|_| -> Result<Vec<Modifier>, ParseFailure> {
+            .map(|_| -> Result<Vec<Modifier>, ParseError> {
                 let _ = self.next_non_ws(); // skip "Modifiers"
                 self.expect(&TokenType::Colon)?;
 
                 let mut modifiers: Vec<Modifier> = vec![];
                 loop {
-                    match self.peek_non_ws().token_type {
-                        TokenType::Operator(o) if o == "+" || o == "-" => {
-                            modifiers.push(self.modifier_short()?)
+                    self.skip_skipped();
+                    let comments = std::mem::take(&mut self.comments);
+                    let modifier = match self.peek_non_ws().token_type {
+                        TokenType::Operator(o) if o == "+" || o == "-" => self.modifier_short(),
+                        TokenType::Identifier(b) if b == "bonus" => self.modifier_long(),
+                        TokenType::Identifier(s) if s == "set" => self.modifier_set(),
+                        TokenType::Section | TokenType::EndOfInput => {
+                            // they belong to the feature instead
+                            self.comments = comments;
+                            break;
+                        }
+                        _ => {
+                            self.next_non_ws();
+                            Err(self.fail("modifier (+, -, bonus or set)".to_string()))
                         }
-                        TokenType::Identifier(b) if b == "bonus" => {
-                            modifiers.push(self.modifier_long()?)
+                    };
+                    match modifier {
+                        Ok(mut modifier) => {
+                            modifier.comments = comments;
+                            modifier.trailing_comment = self.trailing_comment();
+                            modifiers.push(modifier);
                         }
-                        TokenType::Identifier(s) if s == "set" => {
-                            modifiers.push(self.modifier_set()?)
+                        Err(error) => {
+                            self.errors.push(error);
+                            self.recover(true);
                         }
-                        _ => break,
                     }
                 }
 
@@ -215,114 +636,458 @@ impl Parser<'_> {
             })
             .unwrap_or_else(|| Ok(vec![]))?;
 
+        let span = start.to(self.last_span);
+        self.skip_skipped();
         Ok(Feature {
             name,
+            base_type,
             description,
+            definitions,
             modifiers,
+            span,
+            comments,
+            name_comment,
+            type_comment,
+            description_comment,
+            trailing_comments: std::mem::take(&mut self.comments),
         })
     }
 
-    fn modifier_short(&mut self) -> Result<Modifier, ParseFailure> {
-        let op = self.accept(&TokenType::Operator("".to_string()), "+ or -".to_string())?;
-        let num: i32 = self
-            .accept(&TokenType::Number(0), "number".to_string())?
-            .parse()
-            .unwrap();
-        let iden = self.accept(
-            &TokenType::Identifier("".to_string()),
-            "identifier".to_string(),
-        )?;
+    /// `property;` or `property: selector args, limiter args, ..., round mode;`
+    fn definition(&mut self) -> Result<Definition, ParseError> {
+        let property = self.reference()?;
+        let start = property.span;
+        let mut selector = None;
+        let mut limiters = vec![];
+        let mut rounding = None;
+        if self.peek_non_ws().token_type == TokenType::Colon {
+            self.next_non_ws();
+            selector = Some(self.invocation("selector")?);
+            while self.peek_non_ws().token_type == TokenType::Comma {
+                self.next_non_ws();
+                let limiter = self.invocation("limiter or round")?;
+                if limiter.identifier != "round" {
+                    limiters.push(limiter);
+                    continue;
+                }
+                let mode = match limiter.arguments.as_slice() {
+                    [mode] => mode.parse::<RoundingMode>(),
+                    _ => Err("`round` expects exactly one rounding mode".to_string()),
+                };
+                rounding = Some(mode.map_err(|message| ParseError::InvalidRounding(limiter.span, message))?);
+            }
+        }
         self.expect(&TokenType::Semicolon)?;
 
-        let bonus = if op == "-" { -num } else { num };
+        Ok(Definition {
+            property,
+            selector,
+            limiters,
+            rounding,
+            span: start.to(self.curr().span),
+            comments: vec![],
+            trailing_comment: None,
+        })
+    }
+
+    /// A selector or limiter: an identifier followed by any number of numbers, identifiers or
+    /// strings.
+    fn invocation(&mut self, expected: &str) -> Result<Invocation, ParseError> {
+        let identifier = self.accept_token(&TokenType::Identifier("".to_string()), expected.to_string())?;
+        let mut arguments = vec![];
+        loop {
+            match self.peek_non_ws().token_type {
+                TokenType::Identifier(text) | TokenType::String(text) => {
+                    self.next_non_ws();
+                    arguments.push(text);
+                }
+                TokenType::Number(number) => {
+                    self.next_non_ws();
+                    arguments.push(number.to_string());
+                }
+                TokenType::Operator(o) if o == "-" => {
+                    self.next_non_ws();
+                    arguments.push(negate(self.number()?).to_string());
+                }
+                _ => break,
+            }
+        }
 
-        Ok(Modifier {
-            referencing: Reference {
-                name: iden,
-                scope: ast::Scope::Character,
-            },
-            value: ast::ModifierValue::SimpleBonus(bonus),
+        Ok(Invocation {
+            identifier: identifier.token_type.get_string(),
+            arguments,
+            span: identifier.span.to(self.last_span),
         })
     }
 
-    fn modifier_long(&mut self) -> Result<Modifier, ParseFailure> {
-        self.expect(&TokenType::Identifier("bonus".to_string()))?;
-        self.expect(&TokenType::Identifier("to".to_string()))?;
-        let iden = self.accept(&TokenType::Identifier("".to_string()), "identifier".to_string())?;
-        self.expect(&TokenType::Identifier("of".to_string()))?;
-        let op: String;
+    /// `term (+|- term)*`
+    fn expression(&mut self) -> Result<Expression, ParseError> {
+        self.binary(&["+", "-"], Self::term)
+    }
+
+    /// `unary (*|/ unary)*`
+    fn term(&mut self) -> Result<Expression, ParseError> {
+        self.binary(&["*", "/"], Self::unary)
+    }
+
+    fn binary(
+        &mut self,
+        operators: &[&str],
+        operand: fn(&mut Self) -> Result<Expression, ParseError>,
+    ) -> Result<Expression, ParseError> {
+        let mut left = operand(self)?;
+        loop {
+            match self.peek_non_ws().token_type {
+                TokenType::Operator(o) if operators.contains(&o.as_str()) => {
+                    self.next_non_ws();
+                    let right = operand(self)?;
+                    left = Expression {
+                        span: left.span.to(right.span),
+                        kind: ExpressionKind::Binary(o, Box::new(left), Box::new(right)),
+                    };
+                }
+                _ => return Ok(left),
+            }
+        }
+    }
+
+    /// Parses an expression inside of another one, unless they are already nested too deep.
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Expression, ParseError>) -> Result<Expression, ParseError> {
+        if self.depth == MAX_NESTING {
+            self.next_non_ws();
+            return Err(ParseError::TooDeeplyNested(self.curr().span));
+        }
+        self.depth += 1;
+        let expression = parse(self);
+        self.depth -= 1;
+        expression
+    }
+
+    /// `+ unary`, `- unary` or a number, dice, property, function call or `( expression )`
+    fn unary(&mut self) -> Result<Expression, ParseError> {
+        let expected = "number, dice, property or (";
         match self.peek_non_ws().token_type {
             TokenType::Operator(o) if o == "+" || o == "-" => {
-                self.next_non_ws();
-                op = o;
-            },
-            TokenType::Operator(..) => {
-                return Err(ParseFailure {
-                    errors: vec![ParseError::UnexpectedToken {
-                        token: self.curr().clone(),
-                        expected: "+ or -".to_string(),
-                    }],
+                let start = self.next_non_ws().span;
+                let inner = self.nested(Self::unary)?;
+                Ok(Expression {
+                    span: start.to(inner.span),
+                    kind: ExpressionKind::Unary(o, Box::new(inner)),
+                })
+            }
+            TokenType::Number(_) => {
+                let number = self.number()?;
+                Ok(Expression { kind: ExpressionKind::Number(number), span: self.curr().span })
+            }
+            TokenType::Dice(_) => {
+                let dice = self.dice()?;
+                Ok(Expression { kind: ExpressionKind::Dice(dice), span: self.curr().span })
+            }
+            TokenType::OpeningParenthesis => {
+                let start = self.next_non_ws().span;
+                let inner = self.nested(Self::expression)?;
+                self.expect(&TokenType::ClosingParenthesis)?;
+                Ok(Expression {
+                    kind: ExpressionKind::Parenthesized(Box::new(inner)),
+                    span: start.to(self.curr().span),
                 })
-            },
+            }
+            TokenType::Identifier(_) => {
+                let first = self.next_non_ws();
+                if self.peek_non_ws().token_type != TokenType::OpeningParenthesis {
+                    let reference = self.qualified_reference(first)?;
+                    return Ok(Expression { span: reference.span, kind: ExpressionKind::Reference(reference) });
+                }
+                self.next_non_ws(); // skip "("
+                let mut arguments = vec![];
+                if self.peek_non_ws().token_type != TokenType::ClosingParenthesis {
+                    arguments.push(self.nested(Self::expression)?);
+                    while self.peek_non_ws().token_type == TokenType::Comma {
+                        self.next_non_ws();
+                        arguments.push(self.nested(Self::expression)?);
+                    }
+                }
+                self.expect_explicit(&TokenType::ClosingParenthesis, ", or )".to_string())?;
+                Ok(Expression {
+                    kind: ExpressionKind::Call(first.token_type.get_string(), arguments),
+                    span: first.span.to(self.curr().span),
+                })
+            }
             _ => {
-                op = "+".to_string();
-            },
+                self.next_non_ws();
+                Err(self.fail(expected.to_string()))
+            }
         }
+    }
 
-        let num: i32 = self
-            .accept(&TokenType::Number(0), "number".to_string())?
-            .parse()
-            .unwrap();
+    fn modifier_short(&mut self) -> Result<Modifier, ParseError> {
+        let op = self.accept(&TokenType::Operator("".to_string()), "+ or -".to_string())?;
+        let start = self.curr().span;
+        let value = if let TokenType::Dice(_) = self.peek_non_ws().token_type {
+            let dice = self.dice()?;
+            let bonus = if op == "-" {
+                ast::negate_dice(&dice).ok_or_else(|| ParseError::InvalidDice(self.curr().span, "the dice can not be negated".to_string()))?
+            } else {
+                dice
+            };
+            ast::ModifierValue::SimpleDiceBonus(bonus)
+        } else {
+            let num = self.number()?;
+            ast::ModifierValue::SimpleBonus(if op == "-" { negate(num) } else { num })
+        };
+        let referencing = self.reference()?;
         self.expect(&TokenType::Semicolon)?;
 
-        let bonus = if op == "-" { -num } else { num };
+        Ok(Modifier {
+            referencing,
+            value,
+            span: start.to(self.curr().span),
+            comments: vec![],
+            trailing_comment: None,
+        })
+    }
+
+    fn modifier_long(&mut self) -> Result<Modifier, ParseError> {
+        self.expect(&TokenType::Identifier("bonus".to_string()))?;
+        let start = self.curr().span;
+        self.expect(&TokenType::Identifier("to".to_string()))?;
+        let referencing = self.reference()?;
+        self.expect(&TokenType::Identifier("of".to_string()))?;
+        let expression = self.expression()?;
+        self.expect(&TokenType::Semicolon)?;
+
+        let value = match expression.number() {
+            Some(bonus) => ast::ModifierValue::Bonus(bonus),
+            None => ast::ModifierValue::BonusExpression(expression),
+        };
 
         Ok(Modifier {
-            referencing: Reference {
-                name: iden,
-                scope: ast::Scope::Character,
-            },
-            value: ast::ModifierValue::Bonus(bonus),
+            referencing,
+            value,
+            span: start.to(self.curr().span),
+            comments: vec![],
+            trailing_comment: None,
         })
     }
 
-    fn modifier_set(&mut self) -> Result<Modifier, ParseFailure> {
+    fn modifier_set(&mut self) -> Result<Modifier, ParseError> {
         self.expect(&TokenType::Identifier("set".to_string()))?;
-        let iden = self.accept(&TokenType::Identifier("".to_string()), "identifier".to_string())?;
+        let start = self.curr().span;
+        let referencing = self.reference()?;
         self.expect(&TokenType::Identifier("to".to_string()))?;
-        let op: String;
-        match self.peek_non_ws().token_type {
-            TokenType::Operator(o) if o == "+" || o == "-" => {
-                self.next_non_ws();
-                op = o;
-            },
-            TokenType::Operator(..) => {
-                return Err(ParseFailure {
-                    errors: vec![ParseError::UnexpectedToken {
-                        token: self.curr().clone(),
-                        expected: "+ or -".to_string(),
-                    }],
-                })
-            },
-            _ => {
-                op = "+".to_string();
-            },
-        }
-
-        let num: i32 = self
-            .accept(&TokenType::Number(0), "number".to_string())?
-            .parse()
-            .unwrap();
+        let expression = self.expression()?;
         self.expect(&TokenType::Semicolon)?;
 
-        let bonus = if op == "-" { -num } else { num };
+        let value = match expression.number() {
+            Some(number) => ast::ModifierValue::Set(number),
+            None => ast::ModifierValue::SetExpression(expression),
+        };
 
         Ok(Modifier {
-            referencing: Reference {
-                name: iden,
-                scope: ast::Scope::Character,
-            },
-            value: ast::ModifierValue::Set(bonus),
+            referencing,
+            value,
+            span: start.to(self.curr().span),
+            comments: vec![],
+            trailing_comment: None,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ast::{ModifierValue, Scope};
+    use super::{parse, MAX_NESTING};
+    use crate::span::LineIndex;
+    use crate::tokenizer::lex;
+
+    #[test]
+    fn spans() {
+        let input = "Name: \"Dwarf\";\nModifiers:\n  +2 constitution;\n  set speed to 25;\n";
+        let ast = parse(&lex(input)).unwrap().ast;
+        let feature = &ast.model.features[0];
+        let text = |span: crate::span::Span| &input[span.start..span.end];
+
+        assert_eq!(text(feature.span), input.trim_end());
+        assert_eq!(text(feature.modifiers[0].span), "+2 constitution;");
+        assert_eq!(text(feature.modifiers[0].referencing.span), "constitution");
+        assert_eq!(text(feature.modifiers[1].span), "set speed to 25;");
+        assert_eq!(text(feature.modifiers[1].referencing.span), "speed");
+    }
+
+    #[test]
+    fn recover_from_errors() {
+        let input = r#"Name: "Dwarf";
+Modifiers:
+  +2 constitution;
+  +two wisdom;
+  bonus to speed of * 5;
+  strength +1;
+  set darkvision to 60;
+---
+Name: "Elf" ;
+Modifiers:
+  +2 dexterity;
+---
+Name "Gnome";
+Modifiers:
+  +1 intelligence;
+---
+Name: "Halfling";
+Description: "Small" Modifiers:
+---
+Name: "Human";
+Modifiers:
+  +1 strength
+"#;
+        let failure = parse(&lex(input)).unwrap_err();
+        let errors: Vec<(&str, String)> = failure
+            .errors
+            .iter()
+            .map(|e| (&input[e.span().start..e.span().end], e.to_string()))
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                ("two", "Unexpected token: two (expected: number)".to_string()),
+                ("*", "Unexpected token: * (expected: number, dice, property or ()".to_string()),
+                ("strength", "Unexpected token: strength (expected: modifier (+, -, bonus or set))".to_string()),
+                ("\"Gnome\"", "Unexpected token: Gnome (expected: :)".to_string()),
+                ("Modifiers", "Unexpected token: Modifiers (expected: ;)".to_string()),
+                ("", "Unexpected token: End of Input (expected: ;)".to_string()),
+            ]
+        );
+        let names: Vec<&str> = failure.ast.model.features.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["Elf"], "Only features without errors are kept.");
+    }
+
+    #[test]
+    fn rendered_errors() {
+        let input = "Name: \"Zwerg\";\nModifiers:\n  +2 Stärke;\n";
+        let errors = parse(&lex(input)).unwrap_err().errors;
+        let index = LineIndex::new(input);
+        let rendered: Vec<String> = errors.iter().map(|e| e.render(&index)).collect();
+
+        assert_eq!(
+            rendered,
+            vec![
+                "error: Unexpected text: ä\n --> 3:8\n  |\n3 |   +2 Stärke;\n  |        ^",
+                "error: Unexpected token: rke (expected: ;)\n --> 3:9\n  |\n3 |   +2 Stärke;\n  |         ^^^",
+            ]
+        );
+    }
+
+    #[test]
+    fn feature_references() {
+        let input = "Name: \"Rage\";\nModifiers:\n  set Rage.uses to 3;\n  +2 Longsword.damage;\n";
+        let failure = parse(&lex(input)).unwrap_err();
+        assert_eq!(failure.errors.len(), 1);
+        assert_eq!(failure.errors[0].to_string(), "Unknown feature `Longsword`");
+        assert_eq!(
+            &input[failure.errors[0].span().start..failure.errors[0].span().end],
+            "Longsword.damage"
+        );
+
+        let reference = &failure.ast.model.features[0].modifiers[0].referencing;
+        assert_eq!(reference.scope, Scope::Feature("Rage".to_string()));
+        assert_eq!(reference.name, "uses");
+        assert_eq!(reference.qualified_name(), "Rage.uses");
+    }
+
+    #[test]
+    fn expressions() {
+        let input = "Name: \"Rogue\";\nModifiers:\n  set hp to 8 + 2 * (constitution - 1d6);\n  +1d0 speed;\n  bonus to ac of 2 +;\n";
+        let failure = parse(&lex(input)).unwrap_err();
+        let errors: Vec<(&str, String)> = failure
+            .errors
+            .iter()
+            .map(|e| (&input[e.span().start..e.span().end], e.to_string()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("1d0", "Invalid dice: Dice need at least one side".to_string()),
+                (";", "Unexpected token: ; (expected: number, dice, property or ()".to_string()),
+            ]
+        );
+
+        let input = "Name: \"Rogue\";\nModifiers:\n  set hp to 8 + 2 * (constitution - 1d6);\n";
+        let ast = parse(&lex(input)).unwrap().ast;
+        match &ast.model.features[0].modifiers[0].value {
+            ModifierValue::SetExpression(expression) => {
+                assert_eq!(expression.to_string(), "8 + 2 * (constitution - 1d6)");
+                assert_eq!(&input[expression.span.start..expression.span.end], "8 + 2 * (constitution - 1d6)");
+                let dependencies: Vec<String> = expression.dependencies().iter().map(|r| r.qualified_name()).collect();
+                assert_eq!(dependencies, vec!["constitution"]);
+            }
+            value => panic!("expected an expression, but got {:?}", value),
+        }
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let nested = |open: &str, depth: usize, close: &str| {
+            format!("Name: \"Deep\";\nModifiers:\n  set hp to {}1{};\n  +1 ac;\n", open.repeat(depth), close.repeat(depth))
+        };
+        assert!(parse(&lex(&nested("(", MAX_NESTING, ")"))).is_ok());
+        assert!(parse(&lex(&nested("max(", MAX_NESTING, ")"))).is_ok());
+        for input in [
+            nested("(", MAX_NESTING + 1, ")"),
+            nested("(", 10_000, ")"),
+            nested("max(", 10_000, ")"),
+            nested("- ", 10_000, ""),
+        ] {
+            let failure = parse(&lex(&input)).unwrap_err();
+            assert_eq!(failure.errors.len(), 1);
+            assert_eq!(failure.errors[0].to_string(), "Expressions can not be nested more than 64 levels deep");
+            assert!(failure.ast.model.features.is_empty());
+        }
+    }
+
+    #[test]
+    fn call_arguments() {
+        let input = "Name: \"Fighter\";\nModifiers:\n  set hp to max(1,2);\n  set ac to max(1, 2);\n  set speed to max(min(1,5),2.5);\n  bonus to damage of (1,5 + 2);\n  +0,5 luck;\n";
+        let ast = parse(&lex(input)).unwrap().ast;
+        let values: Vec<String> = ast.model.features[0]
+            .modifiers
+            .iter()
+            .map(|m| match &m.value {
+                ModifierValue::SetExpression(e) | ModifierValue::BonusExpression(e) => e.to_string(),
+                ModifierValue::SimpleBonus(n) => n.to_string(),
+                value => panic!("expected an expression, but got {:?}", value),
+            })
+            .collect();
+        assert_eq!(values, vec!["max(1, 2)", "max(1, 2)", "max(min(1, 5), 2.5)", "(1.5 + 2)", "0.5"]);
+    }
+
+    #[test]
+    fn definitions() {
+        let input = "Name: \"Attributes\";\nDefinitions:\n  strength: highest 2, max 20;\n  luck;\n";
+        let definitions = &parse(&lex(input)).unwrap().ast.model.features[0].definitions;
+        assert_eq!(definitions.len(), 2);
+        let selector = definitions[0].selector.as_ref().unwrap();
+        assert_eq!(selector.identifier, "highest");
+        assert_eq!(selector.arguments, vec!["2"]);
+        assert_eq!(definitions[0].limiters[0].identifier, "max");
+        assert!(definitions[1].selector.is_none());
+
+        let input = "Name: \"Attributes\";\nDefinitions:\n  strength: highest, round sideways;\n";
+        let errors = parse(&lex(input)).unwrap_err().errors;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            "unknown rounding `sideways`, expected `down`, `up`, `nearest` or `towardZero`"
+        );
+        assert_eq!(&input[errors[0].span().start..errors[0].span().end], "round sideways");
+
+        // the `,` separates the limiters instead of being a decimal comma
+        let input = "Name: \"Attributes\";\nDefinitions:\n  x: last, oneOf 2,4;\n";
+        let errors = parse(&lex(input)).unwrap_err().errors;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(&input[errors[0].span().start..errors[0].span().end], "4");
+        let input = "Name: \"Attributes\";\nDefinitions:\n  x: last, oneOf 2.5 4;\nModifiers:\n  +0,5 luck;\n";
+        let feature = &parse(&lex(input)).unwrap().ast.model.features[0];
+        assert_eq!(feature.definitions[0].limiters[0].arguments, vec!["2.5", "4"]);
+        assert_eq!(feature.modifiers[0].value, ModifierValue::SimpleBonus("0.5".parse().unwrap()));
+    }
+}
@@ -1,43 +1,325 @@
-#[derive(Debug)]
+use std::fmt;
+
+use types::character_sheet_collection::{DiceValue, RoundingMode};
+use types::fraction::Fraction;
+
+use crate::span::Span;
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct AST {
     pub model: Model,
     pub references: Vec<Reference>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Scope {
     Character, // essentially global scope
-    Feature(String), // for referencing properties
+    Feature(String), // for referencing properties of the feature with this name, e.g. `Rage.uses`
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Reference {
     pub scope: Scope,
     pub name: String,
+    pub span: Span,
+}
+
+impl Reference {
+    /// The name of the property including the feature it belongs to, e.g. `Rage.uses`.
+    pub fn qualified_name(&self) -> String {
+        match &self.scope {
+            Scope::Character => self.name.clone(),
+            Scope::Feature(feature) => format!("{}.{}", feature, self.name),
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct Model {
+    /// The `Import` statements at the start of the file.
+    pub imports: Vec<Import>,
+    /// The `FeatureSet:` block at the start of the file, if there is one.
+    pub header: Option<Box<Header>>,
     pub features: Vec<Feature>,
 }
 
-#[derive(Debug)]
+/// `Import "classes/fighter.cs";`, the path is relative to the importing file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Import {
+    pub path: String,
+    pub span: Span,
+    /// Comments in front of the import.
+    pub comments: Vec<Comment>,
+    /// A comment on the same line after the import.
+    pub trailing_comment: Option<Comment>,
+}
+
+/// The metadata of the feature set, e.g. `FeatureSet: "Basic rules"; Source: "p. 18";`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Header {
+    pub name: String,
+    pub description: String,
+    /// Where the features come from, e.g. a book and page.
+    pub source: String,
+    pub span: Span,
+    /// Comments in front of the header.
+    pub comments: Vec<Comment>,
+    /// A comment on the same line after the `FeatureSet:` field.
+    pub name_comment: Option<Comment>,
+    /// A comment on the same line after the `Description:` field.
+    pub description_comment: Option<Comment>,
+    /// A comment on the same line after the `Source:` field.
+    pub source_comment: Option<Comment>,
+    /// Comments after the last field of the header.
+    pub trailing_comments: Vec<Comment>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct Feature {
     pub name: String,
+    /// The `Type:` of the feature, empty if there is none.
+    pub base_type: String,
     pub description: String,
+    pub definitions: Vec<Definition>,
     pub modifiers: Vec<Modifier>,
+    pub span: Span,
+    /// Comments in front of the feature.
+    pub comments: Vec<Comment>,
+    /// A comment on the same line after the `Name:` field.
+    pub name_comment: Option<Comment>,
+    /// A comment on the same line after the `Type:` field.
+    pub type_comment: Option<Comment>,
+    /// A comment on the same line after the `Description:` field.
+    pub description_comment: Option<Comment>,
+    /// Comments after the last modifier or definition, or after the description if there are
+    /// neither.
+    pub trailing_comments: Vec<Comment>,
 }
 
-#[derive(Debug)]
+/// The definition of a property, e.g. `strength: highest, min 0, round down;`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Definition {
+    pub property: Reference,
+    /// Without a selector the default selector of the engine is used.
+    pub selector: Option<Invocation>,
+    pub limiters: Vec<Invocation>,
+    pub rounding: Option<RoundingMode>,
+    pub span: Span,
+    /// Comments in front of the definition.
+    pub comments: Vec<Comment>,
+    /// A comment on the same line after the definition.
+    pub trailing_comment: Option<Comment>,
+}
+
+/// A selector or limiter with its arguments, e.g. `highest 2`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Invocation {
+    pub identifier: String,
+    pub arguments: Vec<String>,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct Modifier {
     pub referencing: Reference, // todo: ownership?
     pub value: ModifierValue,
+    pub span: Span,
+    /// Comments in front of the modifier.
+    pub comments: Vec<Comment>,
+    /// A comment on the same line after the modifier.
+    pub trailing_comment: Option<Comment>,
+}
+
+/// A comment, including its `#`, `//` or `/* */`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+    pub text: String,
+    pub span: Span,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum ModifierValue {
-    SimpleBonus(i32),
-    Bonus(i32),
-    Set(i32),
+    SimpleBonus(Fraction),
+    Bonus(Fraction),
+    Set(Fraction),
+    /// `+2d4x sneak_attack;`, the amount of dice is negative for `-1d6 speed;`
+    SimpleDiceBonus(DiceValue),
+    /// `bonus to damage of 1d6;`, any bonus that is not a single number
+    BonusExpression(Expression),
+    /// `set hp to 8 + constitution;`, any value that is not a single number
+    SetExpression(Expression),
+}
+
+/// The value of a modifier, e.g. `8 + constitution` or `2 * (1d6 + Rage.damage)`.
+///
+/// Expressions are written the same way in the DSL and in scripts, so `Display` can be used
+/// for both.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExpressionKind {
+    Number(Fraction),
+    Dice(DiceValue),
+    Reference(Reference),
+    /// A function of the script language, e.g. `floor(level / 2)`.
+    Call(String, Vec<Expression>),
+    Unary(String, Box<Expression>),
+    Binary(String, Box<Expression>, Box<Expression>),
+    Parenthesized(Box<Expression>),
+}
+
+impl Expression {
+    /// The number if the expression is nothing else, e.g. `5` or `-5`.
+    pub fn number(&self) -> Option<Fraction> {
+        match &self.kind {
+            ExpressionKind::Number(n) => Some(*n),
+            ExpressionKind::Unary(op, inner) => match (op.as_str(), &inner.kind) {
+                ("+", ExpressionKind::Number(n)) => Some(*n),
+                ("-", ExpressionKind::Number(n)) => n.checked_neg(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The dice value if the expression only adds and subtracts dice and whole numbers, e.g.
+    /// `1d8 + 1d6 - 2`. Dice are kept in the order they are written.
+    pub fn dice(&self) -> Option<DiceValue> {
+        let value = self.dice_sum()?;
+        if value.dice.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    fn dice_sum(&self) -> Option<DiceValue> {
+        match &self.kind {
+            ExpressionKind::Dice(d) => Some(d.clone()),
+            ExpressionKind::Number(n) if n.is_integer() => Some(DiceValue {
+                dice: vec![],
+                bonus: i32::try_from(n.numerator()).ok()?,
+            }),
+            ExpressionKind::Parenthesized(inner) => inner.dice_sum(),
+            ExpressionKind::Unary(op, inner) if op == "+" => inner.dice_sum(),
+            ExpressionKind::Unary(op, inner) if op == "-" => negate_dice(&inner.dice_sum()?),
+            ExpressionKind::Binary(op, left, right) if op == "+" || op == "-" => {
+                let mut left = left.dice_sum()?;
+                let right = match op.as_str() {
+                    "-" => negate_dice(&right.dice_sum()?)?,
+                    _ => right.dice_sum()?,
+                };
+                left.dice.extend(right.dice);
+                left.bonus = left.bonus.checked_add(right.bonus)?;
+                Some(left)
+            }
+            _ => None,
+        }
+    }
+
+    /// The properties the expression depends on, in the order of their first use.
+    pub fn dependencies(&self) -> Vec<&Reference> {
+        let mut dependencies: Vec<&Reference> = vec![];
+        self.collect_dependencies(&mut dependencies);
+        dependencies
+    }
+
+    fn collect_dependencies<'a>(&'a self, dependencies: &mut Vec<&'a Reference>) {
+        match &self.kind {
+            ExpressionKind::Number(_) | ExpressionKind::Dice(_) => {}
+            ExpressionKind::Reference(reference) => {
+                let name = reference.qualified_name();
+                if !dependencies.iter().any(|d| d.qualified_name() == name) {
+                    dependencies.push(reference);
+                }
+            }
+            ExpressionKind::Call(_, arguments) => {
+                for argument in arguments {
+                    argument.collect_dependencies(dependencies);
+                }
+            }
+            ExpressionKind::Unary(_, inner) | ExpressionKind::Parenthesized(inner) => {
+                inner.collect_dependencies(dependencies)
+            }
+            ExpressionKind::Binary(_, left, right) => {
+                left.collect_dependencies(dependencies);
+                right.collect_dependencies(dependencies);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExpressionKind::Number(n) => write!(f, "{}", n),
+            ExpressionKind::Dice(d) => write!(f, "{}", d),
+            ExpressionKind::Reference(reference) => write!(f, "{}", reference.qualified_name()),
+            ExpressionKind::Call(name, arguments) => {
+                write!(f, "{}(", name)?;
+                for (index, argument) in arguments.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", argument)?;
+                }
+                write!(f, ")")
+            }
+            ExpressionKind::Unary(op, inner) => match &inner.kind {
+                ExpressionKind::Binary(..) => write!(f, "{}({})", op, inner),
+                // `- -x` instead of `--x`, three of them would be a `---`
+                ExpressionKind::Unary(..) => write!(f, "{} {}", op, inner),
+                _ => write!(f, "{}{}", op, inner),
+            },
+            ExpressionKind::Binary(op, left, right) => {
+                if left.binds_weaker_than(op, false) {
+                    write!(f, "({})", left)?;
+                } else {
+                    write!(f, "{}", left)?;
+                }
+                write!(f, " {} ", op)?;
+                if right.binds_weaker_than(op, true) {
+                    write!(f, "({})", right)
+                } else {
+                    write!(f, "{}", right)
+                }
+            }
+            ExpressionKind::Parenthesized(inner) => write!(f, "({})", inner),
+        }
+    }
+}
+
+impl Expression {
+    /// Whether the expression needs parentheses as an operand of the operator, e.g. `a + b` in
+    /// `(a + b) * c`. Expressions built by the parser already contain these parentheses.
+    pub fn binds_weaker_than(&self, op: &str, right: bool) -> bool {
+        match &self.kind {
+            // operators are left associative, so `a - (b - c)` needs them on the right
+            ExpressionKind::Binary(inner, _, _) if right => precedence(inner) <= precedence(op),
+            ExpressionKind::Binary(inner, _, _) => precedence(inner) < precedence(op),
+            _ => false,
+        }
+    }
+}
+
+fn precedence(op: &str) -> u8 {
+    match op {
+        "*" | "/" => 2,
+        _ => 1,
+    }
+}
+
+/// Negates all dice and the bonus, e.g. `1d6+2` becomes `-1d6-2`.
+pub fn negate_dice(value: &DiceValue) -> Option<DiceValue> {
+    let mut negated = value.clone();
+    for dice in &mut negated.dice {
+        dice.amount = dice.amount.checked_neg()?;
+    }
+    negated.bonus = negated.bonus.checked_neg()?;
+    Some(negated)
 }
 
@@ -0,0 +1,395 @@
+//! Loads a DSL file together with all files it imports.
+//!
+//! Imports are relative to the importing file. Files are loaded by a [`FileLoader`], so that they
+//! can come from the file system ([`FileSystemLoader`]) or from memory ([`MemoryLoader`]), e.g.
+//! sources supplied by the wasm binding.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::compiler::{compile_with, Compilation};
+use crate::lint::{Diagnostic, LintConfig};
+use crate::parser::ast::AST;
+use crate::parser::{parse_with, unknown_features, ParseError};
+use crate::span::{LineIndex, Span};
+use crate::tokenizer::lex;
+
+/// Loads the source of a DSL file.
+pub trait FileLoader {
+    /// Reads the file at the path. The error is shown to the user.
+    fn load(&self, path: &Path) -> Result<String, String>;
+}
+
+/// Loads files from the file system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileSystemLoader;
+
+impl FileLoader for FileSystemLoader {
+    fn load(&self, path: &Path) -> Result<String, String> {
+        std::fs::read_to_string(path).map_err(|e| e.to_string())
+    }
+}
+
+/// Loads files from memory. Paths are normalized, so `./races/../core.cs` finds `core.cs`.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryLoader {
+    files: HashMap<PathBuf, String>,
+}
+
+impl MemoryLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, path: impl AsRef<Path>, source: impl Into<String>) {
+        self.files.insert(normalize(path.as_ref()), source.into());
+    }
+}
+
+impl FileLoader for MemoryLoader {
+    fn load(&self, path: &Path) -> Result<String, String> {
+        self.files
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| "file not found".to_string())
+    }
+}
+
+/// A loaded and parsed file.
+#[derive(Debug)]
+pub struct Module {
+    /// The normalized path of the file.
+    pub path: PathBuf,
+    pub source: String,
+    /// The AST of the file. If there were errors, it only contains the features without errors.
+    pub ast: AST,
+    /// The names of the features of all files this file imports, directly or indirectly.
+    pub imported_features: Vec<String>,
+    pub warnings: Vec<Diagnostic>,
+    pub infos: Vec<Diagnostic>,
+}
+
+/// The result of resolving a file and its imports.
+#[derive(Debug)]
+pub struct Resolution {
+    /// All loaded files. Every file comes after the files it imports, so the entry file is last.
+    pub modules: Vec<Module>,
+    pub errors: Vec<FileError>,
+}
+
+/// An error together with the file it occured in.
+#[derive(Debug, Error)]
+#[error("{}: {error}", .path.display())]
+pub struct FileError {
+    pub path: PathBuf,
+    pub error: ResolveError,
+}
+
+#[derive(Debug, Error)]
+pub enum ResolveError {
+    #[error("{0}")]
+    Parse(ParseError),
+    #[error("The file {} can not be loaded: {reason}", .path.display())]
+    Load {
+        /// The import, or an empty span if the entry file can not be loaded.
+        span: Span,
+        path: PathBuf,
+        reason: String,
+    },
+    #[error("The import of {} creates a cycle: {}", .cycle.last().unwrap().display(), Cycle(.cycle))]
+    Cycle { span: Span, cycle: Vec<PathBuf> },
+}
+
+impl ResolveError {
+    pub fn span(&self) -> Span {
+        match self {
+            ResolveError::Parse(error) => error.span(),
+            ResolveError::Load { span, .. } => *span,
+            ResolveError::Cycle { span, .. } => *span,
+        }
+    }
+}
+
+/// Formats the files of a cycle as `a.cs -> b.cs -> a.cs`.
+struct Cycle<'a>(&'a [PathBuf]);
+
+impl fmt::Display for Cycle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, path) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "{}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl Resolution {
+    /// Compiles every file into its own feature set, in the order of [`modules`](Self::modules).
+    pub fn compile(&self) -> Compilation {
+        let mut compilation = Compilation {
+            feature_sets: vec![],
+            errors: vec![],
+        };
+        for module in &self.modules {
+            let mut compiled = compile_with(&module.ast, &module.imported_features);
+            compilation.feature_sets.append(&mut compiled.feature_sets);
+            compilation.errors.append(&mut compiled.errors);
+        }
+        compilation
+    }
+
+    /// Renders the error with the file, line and column and underlines it in the source line.
+    pub fn render(&self, error: &FileError) -> String {
+        let file = error.path.display().to_string();
+        match self.modules.iter().find(|m| m.path == error.path) {
+            Some(module) => LineIndex::new(&module.source).render_in_file(
+                &file,
+                error.error.span(),
+                "error",
+                &error.error.to_string(),
+            ),
+            None => format!("error: {}\n --> {}", error.error, file),
+        }
+    }
+}
+
+/// Loads and parses the entry file and all files it imports.
+pub fn resolve(entry: &Path, loader: &dyn FileLoader) -> Resolution {
+    resolve_with(entry, loader, &LintConfig::default())
+}
+
+/// Like [`resolve`], but with configured lints.
+pub fn resolve_with(entry: &Path, loader: &dyn FileLoader, lints: &LintConfig) -> Resolution {
+    let mut resolver = Resolver {
+        loader,
+        lints,
+        modules: vec![],
+        errors: vec![],
+        stack: vec![],
+        loaded: HashSet::new(),
+    };
+    let entry = normalize(entry);
+    match loader.load(&entry) {
+        Ok(source) => resolver.load(entry, source),
+        Err(reason) => resolver.errors.push(FileError {
+            path: entry.clone(),
+            error: ResolveError::Load {
+                span: Span::default(),
+                path: entry,
+                reason,
+            },
+        }),
+    }
+
+    Resolution {
+        modules: resolver.modules,
+        errors: resolver.errors,
+    }
+}
+
+struct Resolver<'a> {
+    loader: &'a dyn FileLoader,
+    lints: &'a LintConfig,
+    modules: Vec<Module>,
+    errors: Vec<FileError>,
+    /// The files that are currently being loaded, the last one imports the next file.
+    stack: Vec<PathBuf>,
+    loaded: HashSet<PathBuf>,
+}
+
+impl Resolver<'_> {
+    fn load(&mut self, path: PathBuf, source: String) {
+        let error = |error| FileError {
+            path: path.clone(),
+            error,
+        };
+        let (ast, warnings, infos) = match parse_with(&lex(&source), self.lints) {
+            Ok(success) => (success.ast, success.warnings, success.infos),
+            Err(failure) => {
+                let errors = failure.errors.into_iter().map(ResolveError::Parse);
+                self.errors.extend(errors.map(error));
+                (failure.ast, vec![], vec![])
+            }
+        };
+
+        self.stack.push(path.clone());
+        let directory = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let mut imported = vec![];
+        for import in &ast.model.imports {
+            let target = normalize(&directory.join(&import.path));
+            if let Some(start) = self.stack.iter().position(|p| p == &target) {
+                let mut cycle = self.stack[start..].to_vec();
+                cycle.push(target);
+                self.errors.push(error(ResolveError::Cycle {
+                    span: import.span,
+                    cycle,
+                }));
+                continue;
+            }
+            if !self.loaded.contains(&target) {
+                match self.loader.load(&target) {
+                    Ok(source) => self.load(target.clone(), source),
+                    Err(reason) => {
+                        self.errors.push(error(ResolveError::Load {
+                            span: import.span,
+                            path: target,
+                            reason,
+                        }));
+                        continue;
+                    }
+                }
+            }
+            if !imported.contains(&target) {
+                imported.push(target);
+            }
+        }
+        self.stack.pop();
+
+        let mut imported_features: Vec<String> = vec![];
+        for module in self.modules.iter().filter(|m| imported.contains(&m.path)) {
+            let features = module.ast.model.features.iter().map(|f| &f.name);
+            for name in features.chain(&module.imported_features) {
+                if !imported_features.contains(name) {
+                    imported_features.push(name.clone());
+                }
+            }
+        }
+        if !ast.model.imports.is_empty() {
+            let errors = unknown_features(&ast, &imported_features);
+            self.errors
+                .extend(errors.into_iter().map(ResolveError::Parse).map(error));
+        }
+
+        self.loaded.insert(path.clone());
+        self.modules.push(Module {
+            path,
+            source,
+            ast,
+            imported_features,
+            warnings,
+            infos,
+        });
+    }
+}
+
+/// Removes `.` and resolves `..` without accessing the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) => {}
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{resolve, MemoryLoader, ResolveError};
+
+    fn loader(files: &[(&str, &str)]) -> MemoryLoader {
+        let mut loader = MemoryLoader::new();
+        for (path, source) in files {
+            loader.insert(path, *source);
+        }
+        loader
+    }
+
+    #[test]
+    fn imports() {
+        let loader = loader(&[
+            (
+                "main.cs",
+                "Import \"classes/fighter.cs\";\nImport \"./core.cs\";\nName: \"Hero\";\nModifiers:\n  +1 Fighter.level;\n",
+            ),
+            (
+                "classes/fighter.cs",
+                "Import \"../core.cs\";\nName: \"Fighter\";\nModifiers:\n  set Attributes.strength to 10;\n",
+            ),
+            ("core.cs", "Name: \"Attributes\";\n"),
+        ]);
+        let resolution = resolve(Path::new("main.cs"), &loader);
+
+        assert!(resolution.errors.is_empty(), "{:?}", resolution.errors);
+        let paths: Vec<&Path> = resolution
+            .modules
+            .iter()
+            .map(|m| m.path.as_path())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("core.cs"),
+                Path::new("classes/fighter.cs"),
+                Path::new("main.cs"),
+            ],
+            "every file is loaded once and after its imports"
+        );
+        assert_eq!(
+            resolution.modules[2].imported_features,
+            vec!["Attributes", "Fighter"]
+        );
+
+        let compilation = resolution.compile();
+        assert_eq!(compilation.errors, vec![]);
+        let names: Vec<&str> = compilation
+            .feature_sets
+            .iter()
+            .map(|s| s.features[0].name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Attributes", "Fighter", "Hero"]);
+    }
+
+    #[test]
+    fn report_errors_with_their_file() {
+        let loader = loader(&[
+            ("main.cs", "Import \"races.cs\";\nImport \"missing.cs\";\nName: \"Hero\";\n"),
+            (
+                "races.cs",
+                "Import \"main.cs\";\nName: \"Dwarf\";\nModifiers:\n  +2 Elf.speed;\n  +two wisdom;\n",
+            ),
+        ]);
+        let resolution = resolve(Path::new("main.cs"), &loader);
+        let rendered: Vec<String> = resolution
+            .errors
+            .iter()
+            .map(|e| resolution.render(e))
+            .collect();
+
+        assert_eq!(
+            rendered,
+            vec![
+                "error: Unexpected token: two (expected: number)\n --> races.cs:5:4\n  |\n5 |   +two wisdom;\n  |    ^^^",
+                "error: The import of main.cs creates a cycle: main.cs -> races.cs -> main.cs\n --> races.cs:1:1\n  |\n1 | Import \"main.cs\";\n  | ^^^^^^^^^^^^^^^^^",
+                "error: Unknown feature `Elf`\n --> races.cs:4:6\n  |\n4 |   +2 Elf.speed;\n  |      ^^^^^^^^^",
+                "error: The file missing.cs can not be loaded: file not found\n --> main.cs:2:1\n  |\n2 | Import \"missing.cs\";\n  | ^^^^^^^^^^^^^^^^^^^^",
+            ]
+        );
+        assert_eq!(resolution.errors[0].path, PathBuf::from("races.cs"));
+
+        let resolution = resolve(Path::new("other.cs"), &loader);
+        assert!(matches!(
+            resolution.errors[0].error,
+            ResolveError::Load { .. }
+        ));
+        assert_eq!(
+            resolution.render(&resolution.errors[0]),
+            "error: The file other.cs can not be loaded: file not found\n --> other.cs"
+        );
+    }
+}
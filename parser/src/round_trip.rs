@@ -0,0 +1,501 @@
+//! Property tests for the round trip between the tokenizer, the parser and the serializer.
+//!
+//! The ASTs are generated without spans and references, and with parentheses only where they
+//! are needed, so they are compared with the parsed ASTs after these are normalized.
+
+use proptest::collection::vec;
+use proptest::option;
+use proptest::prelude::*;
+
+use types::character_sheet_collection::{DiceValue, RoundingMode};
+use types::fraction::Fraction;
+
+use crate::parser::ast::*;
+use crate::parser::parse;
+use crate::serializer::{serialize, serialize_with, FormatOptions, ModifierStyle};
+use crate::span::Span;
+use crate::tokenizer::lex;
+
+const KEYWORDS: [&str; 8] = [
+    "Name",
+    "Type",
+    "Description",
+    "Definitions",
+    "Modifiers",
+    "Import",
+    "FeatureSet",
+    "Source",
+];
+
+fn identifier() -> impl Strategy<Value = String> {
+    "[a-z][a-z_]{0,7}"
+}
+
+fn feature_name() -> impl Strategy<Value = String> {
+    "[A-Z][a-z]{0,7}".prop_filter("keywords end the previous feature", |name| {
+        !KEYWORDS.contains(&name.as_str())
+    })
+}
+
+fn text() -> impl Strategy<Value = String> {
+    "[a-zA-Z0-9 .,!?-]{0,12}"
+}
+
+fn comment() -> impl Strategy<Value = Comment> {
+    prop_oneof![
+        "[a-z ]{0,10}".prop_map(|text| format!("# {}", text)),
+        "[a-z ]{0,10}".prop_map(|text| format!("// {}", text)),
+        "[a-z \n]{0,10}".prop_map(|text| format!("/* {} */", text)),
+    ]
+    .prop_map(|text| Comment {
+        text,
+        span: Span::default(),
+    })
+}
+
+fn comments() -> impl Strategy<Value = Vec<Comment>> {
+    vec(comment(), 0..3)
+}
+
+fn number() -> impl Strategy<Value = Fraction> {
+    (0i64..1000, 0u32..3)
+        .prop_map(|(number, digits)| Fraction::new(number, 10i64.pow(digits)).unwrap())
+}
+
+fn dice() -> impl Strategy<Value = DiceValue> {
+    prop_oneof![
+        Just("1d6"),
+        Just("2d4x"),
+        Just("4d6kh3"),
+        Just("1d20"),
+        Just("3d8x=8")
+    ]
+    .prop_map(|dice| dice.parse::<DiceValue>().unwrap())
+}
+
+/// A property of the character, or of the feature it is used in.
+fn reference(feature: String) -> impl Strategy<Value = Reference> {
+    (identifier(), any::<bool>()).prop_map(move |(name, qualified)| Reference {
+        scope: if qualified {
+            Scope::Feature(feature.clone())
+        } else {
+            Scope::Character
+        },
+        name,
+        span: Span::default(),
+    })
+}
+
+fn expression(feature: String) -> impl Strategy<Value = Expression> {
+    let leaf = prop_oneof![
+        number().prop_map(ExpressionKind::Number),
+        dice().prop_map(ExpressionKind::Dice),
+        reference(feature).prop_map(ExpressionKind::Reference),
+    ];
+    let kind = leaf.prop_recursive(3, 16, 3, |inner| {
+        let inner = inner.prop_map(|kind| Expression {
+            kind,
+            span: Span::default(),
+        });
+        prop_oneof![
+            (
+                prop_oneof![Just("floor"), Just("max"), Just("min")],
+                vec(inner.clone(), 1..3)
+            )
+                .prop_map(|(name, arguments)| ExpressionKind::Call(name.to_string(), arguments)),
+            (prop_oneof![Just("+"), Just("-")], inner.clone())
+                .prop_map(|(op, inner)| ExpressionKind::Unary(op.to_string(), Box::new(inner))),
+            (
+                prop_oneof![Just("+"), Just("-"), Just("*"), Just("/")],
+                inner.clone(),
+                inner.clone()
+            )
+                .prop_map(|(op, left, right)| ExpressionKind::Binary(
+                    op.to_string(),
+                    Box::new(left),
+                    Box::new(right)
+                )),
+            inner.prop_map(|inner| ExpressionKind::Parenthesized(Box::new(inner))),
+        ]
+    });
+    kind.prop_map(|kind| Expression {
+        kind,
+        span: Span::default(),
+    })
+}
+
+fn modifier_value(feature: String) -> impl Strategy<Value = ModifierValue> {
+    let signed = (number(), any::<bool>()).prop_map(|(n, negative)| {
+        if negative {
+            n.checked_neg().unwrap()
+        } else {
+            n
+        }
+    });
+    prop_oneof![
+        signed.prop_map(ModifierValue::SimpleBonus),
+        number().prop_map(ModifierValue::Bonus),
+        number().prop_map(ModifierValue::Set),
+        (dice(), any::<bool>()).prop_map(|(dice, negative)| {
+            ModifierValue::SimpleDiceBonus(if negative {
+                negate_dice(&dice).unwrap()
+            } else {
+                dice
+            })
+        }),
+        expression(feature.clone()).prop_map(ModifierValue::BonusExpression),
+        expression(feature).prop_map(ModifierValue::SetExpression),
+    ]
+}
+
+fn modifier(feature: String) -> impl Strategy<Value = Modifier> {
+    (
+        reference(feature.clone()),
+        modifier_value(feature),
+        comments(),
+        option::of(comment()),
+    )
+        .prop_map(
+            |(referencing, value, comments, trailing_comment)| Modifier {
+                referencing,
+                value,
+                span: Span::default(),
+                comments,
+                trailing_comment,
+            },
+        )
+}
+
+fn invocation(identifiers: &'static [&'static str]) -> impl Strategy<Value = Invocation> {
+    (
+        proptest::sample::select(identifiers),
+        vec("[a-z0-9 -]{0,6}", 0..3),
+    )
+        .prop_map(|(identifier, arguments)| Invocation {
+            identifier: identifier.to_string(),
+            arguments,
+            span: Span::default(),
+        })
+}
+
+fn definition(feature: String) -> impl Strategy<Value = Definition> {
+    let rounding = prop_oneof![
+        Just(RoundingMode::Down),
+        Just(RoundingMode::Up),
+        Just(RoundingMode::Nearest),
+        Just(RoundingMode::TowardZero),
+    ];
+    // limiters and rounding are only possible after a selector
+    let limits = option::of((
+        invocation(&["highest", "lowest", "sum", "first"]),
+        vec(invocation(&["min", "max", "step", "oneOf"]), 0..3),
+        option::of(rounding),
+    ));
+    (
+        reference(feature),
+        limits,
+        comments(),
+        option::of(comment()),
+    )
+        .prop_map(|(property, limits, comments, trailing_comment)| {
+            let (selector, limiters, rounding) = match limits {
+                Some((selector, limiters, rounding)) => (Some(selector), limiters, rounding),
+                None => (None, vec![], None),
+            };
+            Definition {
+                property,
+                selector,
+                limiters,
+                rounding,
+                span: Span::default(),
+                comments,
+                trailing_comment,
+            }
+        })
+}
+
+fn feature() -> impl Strategy<Value = Feature> {
+    feature_name().prop_flat_map(|name| {
+        (
+            Just(name.clone()),
+            prop_oneof![Just(String::new()), text()],
+            prop_oneof![Just(String::new()), text()],
+            vec(definition(name.clone()), 0..3),
+            vec(modifier(name), 0..4),
+            comments(),
+            vec(option::of(comment()), 3),
+            comments(),
+        )
+            .prop_map(
+                |(
+                    name,
+                    base_type,
+                    description,
+                    definitions,
+                    modifiers,
+                    comments,
+                    mut field_comments,
+                    trailing_comments,
+                )| Feature {
+                    name,
+                    base_type,
+                    description,
+                    definitions,
+                    modifiers,
+                    span: Span::default(),
+                    comments,
+                    description_comment: field_comments.pop().flatten(),
+                    type_comment: field_comments.pop().flatten(),
+                    name_comment: field_comments.pop().flatten(),
+                    trailing_comments,
+                },
+            )
+    })
+}
+
+fn header() -> impl Strategy<Value = Header> {
+    (
+        text(),
+        text(),
+        text(),
+        comments(),
+        vec(option::of(comment()), 3),
+        comments(),
+    )
+        .prop_map(
+            |(name, description, source, comments, mut field_comments, trailing_comments)| Header {
+                name,
+                description,
+                source,
+                span: Span::default(),
+                comments,
+                source_comment: field_comments.pop().flatten(),
+                description_comment: field_comments.pop().flatten(),
+                name_comment: field_comments.pop().flatten(),
+                trailing_comments,
+            },
+        )
+}
+
+fn import() -> impl Strategy<Value = Import> {
+    ("[a-z/]{1,10}\\.cs", comments(), option::of(comment())).prop_map(
+        |(path, comments, trailing_comment)| Import {
+            path,
+            span: Span::default(),
+            comments,
+            trailing_comment,
+        },
+    )
+}
+
+fn ast() -> impl Strategy<Value = AST> {
+    (
+        vec(import(), 0..3),
+        option::of(header().prop_map(Box::new)),
+        vec(feature(), 0..4),
+    )
+        .prop_filter(
+            "an empty file is not valid",
+            |(imports, header, features)| {
+                !imports.is_empty() || header.is_some() || !features.is_empty()
+            },
+        )
+        .prop_map(|(imports, header, features)| AST {
+            model: Model {
+                imports,
+                header,
+                features,
+            },
+            references: vec![],
+        })
+}
+
+fn format_options() -> impl Strategy<Value = FormatOptions> {
+    let style = prop_oneof![
+        Just(ModifierStyle::Keep),
+        Just(ModifierStyle::Short),
+        Just(ModifierStyle::Long)
+    ];
+    (
+        1usize..5,
+        any::<bool>(),
+        0usize..3,
+        0usize..3,
+        style,
+        20usize..120,
+    )
+        .prop_map(
+            |(
+                indent_width,
+                use_tabs,
+                blank_lines_between_features,
+                max_blank_lines,
+                modifier_style,
+                line_width,
+            )| {
+                FormatOptions {
+                    indent_width,
+                    use_tabs,
+                    blank_lines_between_features,
+                    max_blank_lines,
+                    modifier_style,
+                    line_width,
+                }
+            },
+        )
+}
+
+/// Removes everything the generated ASTs do not have: spans, references and parentheses.
+/// Parsed single numbers are the same as generated ones, e.g. `set x to 5` or `set x to (5)`.
+fn normalize(mut ast: AST) -> AST {
+    ast.references.clear();
+    for import in &mut ast.model.imports {
+        import.span = Span::default();
+        normalize_comments(&mut import.comments);
+        normalize_comment(&mut import.trailing_comment);
+    }
+    if let Some(header) = &mut ast.model.header {
+        header.span = Span::default();
+        normalize_comments(&mut header.comments);
+        normalize_comment(&mut header.name_comment);
+        normalize_comment(&mut header.description_comment);
+        normalize_comment(&mut header.source_comment);
+        normalize_comments(&mut header.trailing_comments);
+    }
+    for feature in &mut ast.model.features {
+        feature.span = Span::default();
+        normalize_comments(&mut feature.comments);
+        normalize_comment(&mut feature.name_comment);
+        normalize_comment(&mut feature.type_comment);
+        normalize_comment(&mut feature.description_comment);
+        normalize_comments(&mut feature.trailing_comments);
+        for definition in &mut feature.definitions {
+            definition.span = Span::default();
+            definition.property.span = Span::default();
+            for invocation in definition
+                .selector
+                .iter_mut()
+                .chain(&mut definition.limiters)
+            {
+                invocation.span = Span::default();
+            }
+            normalize_comments(&mut definition.comments);
+            normalize_comment(&mut definition.trailing_comment);
+        }
+        for modifier in &mut feature.modifiers {
+            modifier.span = Span::default();
+            modifier.referencing.span = Span::default();
+            normalize_comments(&mut modifier.comments);
+            normalize_comment(&mut modifier.trailing_comment);
+            modifier.value = match std::mem::replace(
+                &mut modifier.value,
+                ModifierValue::Set(Fraction::from_integer(0)),
+            ) {
+                ModifierValue::BonusExpression(e) => {
+                    let e = normalize_expression(e);
+                    match e.number() {
+                        Some(n) => ModifierValue::Bonus(n),
+                        None => ModifierValue::BonusExpression(e),
+                    }
+                }
+                ModifierValue::SetExpression(e) => {
+                    let e = normalize_expression(e);
+                    match e.number() {
+                        Some(n) => ModifierValue::Set(n),
+                        None => ModifierValue::SetExpression(e),
+                    }
+                }
+                value => value,
+            };
+        }
+    }
+    ast
+}
+
+fn normalize_comments(comments: &mut [Comment]) {
+    for comment in comments {
+        comment.span = Span::default();
+    }
+}
+
+fn normalize_comment(comment: &mut Option<Comment>) {
+    normalize_comments(comment.as_mut_slice());
+}
+
+fn normalize_expression(expression: Expression) -> Expression {
+    let normalize = |expression: Box<Expression>| Box::new(normalize_expression(*expression));
+    let kind = match expression.kind {
+        ExpressionKind::Parenthesized(inner) => return normalize_expression(*inner),
+        ExpressionKind::Reference(reference) => ExpressionKind::Reference(Reference {
+            span: Span::default(),
+            ..reference
+        }),
+        ExpressionKind::Call(name, arguments) => ExpressionKind::Call(
+            name,
+            arguments.into_iter().map(normalize_expression).collect(),
+        ),
+        ExpressionKind::Unary(op, inner) => ExpressionKind::Unary(op, normalize(inner)),
+        ExpressionKind::Binary(op, left, right) => {
+            ExpressionKind::Binary(op, normalize(left), normalize(right))
+        }
+        kind => kind,
+    };
+    Expression {
+        kind,
+        span: Span::default(),
+    }
+}
+
+/// Parses the text and fails the test case with the rendered errors if that is not possible.
+fn parse_text(text: &str) -> Result<AST, TestCaseError> {
+    parse(&lex(text))
+        .map(|success| success.ast)
+        .map_err(|failure| {
+            let errors: Vec<String> = failure.errors.iter().map(|e| e.to_string()).collect();
+            TestCaseError::fail(format!("{:?} in\n{}", errors, text))
+        })
+}
+
+proptest! {
+    #[test]
+    fn serialized_asts_parse_to_the_same_ast(ast in ast(), options in format_options()) {
+        let serialized = serialize_with(&ast, &options);
+        let parsed = parse_text(&serialized)?;
+        prop_assert_eq!(parsed.model.features.len(), ast.model.features.len());
+
+        let reserialized = serialize_with(&parsed, &options);
+        prop_assert_eq!(&reserialized, &serialized);
+        prop_assert_eq!(parse_text(&reserialized)?, parsed);
+
+        // the other styles change the kind of the bonuses
+        let parsed = parse_text(&serialize(&ast))?;
+        prop_assert_eq!(normalize(parsed), normalize(ast));
+    }
+
+    #[test]
+    fn lex_and_parse_never_panic(
+        input in prop_oneof![
+            "\\PC{0,100}",
+            // expressions nested too deep are errors instead of overflowing the stack
+            "(Name: \"a\";\nModifiers:\n  set x to )?[(+-]{0,5000}1?[)]{0,5000};?",
+        ]
+    ) {
+        if let Ok(success) = parse(&lex(&input)) {
+            serialize(&success.ast);
+        }
+    }
+
+    #[test]
+    fn lex_and_parse_never_panic_on_dsl_fragments(
+        input in vec(prop_oneof![
+            Just("Name: \"a\";"), Just("Modifiers:"), Just("Definitions:"), Just("---"), Just("Import \"a.cs\";"),
+            Just("FeatureSet:"), Just("bonus to"), Just("set"), Just(" of "), Just("+"), Just("-"), Just("*"),
+            Just("("), Just(")"), Just(";"), Just(","), Just("."), Just(":"), Just("1d6x"), Just("99999999999999999999"),
+            Just("2.5"), Just("strength"), Just("round"), Just("# c\n"), Just("/* c"), Just("*/"), Just("\""), Just(" "),
+        ], 0..40).prop_map(|parts| parts.concat())
+    ) {
+        if let Ok(success) = parse(&lex(&input)) {
+            serialize(&success.ast);
+        }
+    }
+}
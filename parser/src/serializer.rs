@@ -1,12 +1,75 @@
+use types::character_sheet_collection::DiceValue;
+use types::fraction::Fraction;
+
 use crate::parser::ast::*;
+use crate::span::Span;
+
+/// Configures the layout of the serialized DSL.
+///
+/// Formatting is idempotent: serializing the AST of a formatted file again with the same options
+/// results in the same text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// The amount of spaces per indentation level. Ignored if `use_tabs` is set.
+    pub indent_width: usize,
+    /// Indent with one tab per level instead of spaces.
+    pub use_tabs: bool,
+    /// The blank lines in front of the `---` between two features.
+    pub blank_lines_between_features: usize,
+    /// The most blank lines that are kept between modifiers, definitions, imports and comments.
+    /// Only used by [`format`], because the AST alone does not know about blank lines.
+    pub max_blank_lines: usize,
+    pub modifier_style: ModifierStyle,
+    /// Modifier values that would make the line longer than this are split up before their
+    /// operators, e.g. `+ strength` continues on the next line.
+    pub line_width: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            indent_width: 2,
+            use_tabs: false,
+            blank_lines_between_features: 0,
+            max_blank_lines: 1,
+            modifier_style: ModifierStyle::Keep,
+            line_width: 100,
+        }
+    }
+}
+
+/// How bonuses are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ModifierStyle {
+    /// As they were written.
+    #[default]
+    Keep,
+    /// `+2 strength;` and `+1d6 damage;` wherever possible.
+    Short,
+    /// `bonus to strength of 2;` for all bonuses.
+    Long,
+}
 
 pub fn serialize(ast: &AST) -> String {
-    let serialize_nodes = create_serialize_nodes(&ast);
+    serialize_with(ast, &FormatOptions::default())
+}
+
+pub fn serialize_with(ast: &AST, options: &FormatOptions) -> String {
+    let serialize_nodes = create_serialize_nodes(ast, None, options);
+
+    print_nodes(serialize_nodes, options)
+}
+
+/// Like [`serialize_with`], but keeps the blank lines (up to `max_blank_lines`) of the source the
+/// AST was parsed from.
+pub fn format(ast: &AST, source: &str, options: &FormatOptions) -> String {
+    let serialize_nodes = create_serialize_nodes(ast, Some(source), options);
 
-    print_nodes(serialize_nodes)
+    print_nodes(serialize_nodes, options)
 }
 
-fn print_nodes(serialize_nodes: Vec<SerializeNode>) -> String {
+fn print_nodes(serialize_nodes: Vec<SerializeNode>, options: &FormatOptions) -> String {
+    let indent = if options.use_tabs { "\t".to_string() } else { " ".repeat(options.indent_width) };
     let mut output: String = String::new();
     let mut curr_indent = 0;
     for node in serialize_nodes {
@@ -21,7 +84,7 @@ fn print_nodes(serialize_nodes: Vec<SerializeNode>) -> String {
                 let nl = ws.newline.min(ws.newline_max).max(ws.newline_min);
                 if nl > 0 {
                     output.push_str(&("\n".repeat(nl.try_into().unwrap())));
-                    output.push_str(&("  ".repeat(curr_indent.try_into().unwrap())));
+                    output.push_str(&indent.repeat(curr_indent.try_into().unwrap()));
                 } else {
                     output.push_str(&(" ".repeat(ws.spaces.max(0).try_into().unwrap())));
                 }
@@ -102,15 +165,23 @@ impl WhitespaceOptions {
         }
     }
 
+    /// Exactly one newline, e.g. to continue a long value on the next line.
+    pub fn new_line_break() -> Self {
+        WhitespaceOptions {
+            newline_max: 1,
+            ..Self::new_newline()
+        }
+    }
+
     pub fn with_indent_incr(mut self, i: i8) -> Self {
-        self.indent_incr = self.indent_incr + i;
+        self.indent_incr += i;
         self
     }
 }
 
-fn create_serialize_nodes(ast: &AST) -> Vec<SerializeNode> {
+fn create_serialize_nodes(ast: &AST, source: Option<&str>, options: &FormatOptions) -> Vec<SerializeNode> {
     let nodes = Vec::new();
-    let mut serializer = Serializer { nodes };
+    let mut serializer = Serializer { nodes, options, source, last_end: None };
 
     serializer.serialize_model(&ast.model);
 
@@ -118,20 +189,25 @@ fn create_serialize_nodes(ast: &AST) -> Vec<SerializeNode> {
 }
 
 #[derive(Debug)]
-struct Serializer {
+struct Serializer<'a> {
     pub nodes: Vec<SerializeNode>,
+    options: &'a FormatOptions,
+    /// The source of the AST, to find the blank lines between the nodes.
+    source: Option<&'a str>,
+    /// The end of the previous node in a list of nodes, `None` at the start of a list.
+    last_end: Option<usize>,
 }
 
-impl Serializer {
+impl Serializer<'_> {
     fn decrease_indent(&mut self, nr: i8) {
         match self.nodes.split_last_mut() {
             Some((SerializeNode::Whitespace(ws), _)) => {
-                ws.indent_decr = ws.indent_decr + nr;
+                ws.indent_decr += nr;
             },
             Some((_, nodes)) => {
                 match nodes.last_mut() {
                     Some(SerializeNode::Whitespace(ws)) => {
-                        ws.indent_decr = ws.indent_decr + nr;
+                        ws.indent_decr += nr;
                     },
                     Some(_) => {
                         panic!("invalid node structure: {:?}", self);
@@ -143,83 +219,621 @@ impl Serializer {
         }
     }
 
+    /// Keeps the blank lines in front of the node, if it is not the first node of its list.
+    fn keep_blank_lines(&mut self, span: Span) {
+        let blank_lines = match (self.source, self.last_end) {
+            (Some(source), Some(last_end)) => source.get(last_end..span.start)
+                .map_or(0, |between| between.matches('\n').count().saturating_sub(1)),
+            _ => 0,
+        };
+        let newlines = i8::try_from(blank_lines.min(self.options.max_blank_lines) + 1).unwrap_or(i8::MAX);
+        if let Some(SerializeNode::Whitespace(ws)) = self.nodes.last_mut() {
+            if ws.newline > 0 {
+                ws.newline = newlines;
+                ws.newline_max = newlines;
+            }
+        }
+        self.last_end = Some(span.end);
+    }
+
+    /// Sets the exact amount of newlines in front of the next node, e.g. a `---`.
+    fn set_newlines(&mut self, newlines: usize) {
+        if let Some(SerializeNode::Whitespace(ws)) = self.nodes.last_mut() {
+            if ws.newline > 0 {
+                let newlines = i8::try_from(newlines).unwrap_or(i8::MAX);
+                ws.newline = newlines;
+                ws.newline_min = newlines;
+                ws.newline_max = newlines;
+            }
+        }
+    }
+
     fn serialize_model(&mut self, model: &Model) {
         self.nodes.push(SerializeNode::new_no_space());
 
-        for field in &model.features {
+        for import in &model.imports {
+            self.serialize_comments(&import.comments);
+            self.keep_blank_lines(import.span);
+            self.nodes.push(SerializeNode::new_text("Import"));
+            self.nodes.push(SerializeNode::new_space());
+            self.nodes.push(SerializeNode::new_text(&("\"".to_string() + &import.path + "\"")));
+            self.nodes.push(SerializeNode::new_no_space());
+            self.nodes.push(SerializeNode::new_text(";"));
+            self.serialize_trailing_comment(&import.trailing_comment);
+            self.nodes.push(SerializeNode::new_newline());
+        }
+        if let Some(header) = &model.header {
+            self.last_end = None;
+            self.serialize_header(header);
+        }
+        for (i, field) in model.features.iter().enumerate() {
+            self.last_end = None;
+            if i > 0 || model.header.is_some() {
+                if i > 0 {
+                    self.set_newlines(self.options.blank_lines_between_features + 1);
+                }
+                self.nodes.push(SerializeNode::new_text("---"));
+                self.nodes.push(SerializeNode::new_newline());
+            }
             self.serialize_feature(field);
         }
     }
 
-    fn serialize_feature(&mut self, feature: &Feature) {
-        self.nodes.push(SerializeNode::new_text("Name"));
-        self.nodes.push(SerializeNode::new_no_space());
-        self.nodes.push(SerializeNode::new_text(":"));
-        self.nodes.push(SerializeNode::new_space());
-        self.nodes.push(SerializeNode::new_text(&("\"".to_string() + &feature.name + "\"")));
+    fn serialize_header(&mut self, header: &Header) {
+        self.serialize_comments(&header.comments);
 
-        self.nodes.push(SerializeNode::new_newline());
+        self.serialize_string_field("FeatureSet", &header.name, &header.name_comment);
+        if !header.description.is_empty() || header.description_comment.is_some() {
+            self.serialize_string_field("Description", &header.description, &header.description_comment);
+        }
+        if !header.source.is_empty() || header.source_comment.is_some() {
+            self.serialize_string_field("Source", &header.source, &header.source_comment);
+        }
+        // the header ends with its last field, the blank lines are counted from there
+        self.last_end = Some(header.span.end);
+        self.serialize_comments(&header.trailing_comments);
+    }
 
-        self.nodes.push(SerializeNode::new_text("Description"));
+    fn serialize_string_field(&mut self, key: &str, value: &str, comment: &Option<Comment>) {
+        self.nodes.push(SerializeNode::new_text(key));
         self.nodes.push(SerializeNode::new_no_space());
         self.nodes.push(SerializeNode::new_text(":"));
         self.nodes.push(SerializeNode::new_space());
-        self.nodes.push(SerializeNode::new_text(&("\"".to_string() + &feature.description + "\"")));
+        self.nodes.push(SerializeNode::new_text(&("\"".to_string() + value + "\"")));
+        self.nodes.push(SerializeNode::new_no_space());
+        self.nodes.push(SerializeNode::new_text(";"));
+        self.serialize_trailing_comment(comment);
         self.nodes.push(SerializeNode::new_newline());
+    }
+
+    fn serialize_feature(&mut self, feature: &Feature) {
+        self.serialize_comments(&feature.comments);
+
+        self.serialize_string_field("Name", &feature.name, &feature.name_comment);
+        if !feature.base_type.is_empty() || feature.type_comment.is_some() {
+            self.serialize_string_field("Type", &feature.base_type, &feature.type_comment);
+        }
+        if !feature.description.is_empty() || feature.description_comment.is_some() {
+            self.serialize_string_field("Description", &feature.description, &feature.description_comment);
+        }
 
-        if feature.modifiers.len() > 0 {
+        if !feature.definitions.is_empty() {
+            self.nodes.push(SerializeNode::new_text("Definitions"));
+            self.nodes.push(SerializeNode::new_no_space());
+            self.nodes.push(SerializeNode::new_text(":"));
+            self.nodes.push(SerializeNode::Whitespace(WhitespaceOptions::new_newline().with_indent_incr(1)));
+            self.last_end = None;
+
+            for definition in &feature.definitions {
+                self.serialize_definition(definition);
+            }
+            if feature.modifiers.is_empty() {
+                self.serialize_comments(&feature.trailing_comments);
+            }
+            self.decrease_indent(1);
+        }
+
+        if !feature.modifiers.is_empty() {
             self.nodes.push(SerializeNode::new_text("Modifiers"));
             self.nodes.push(SerializeNode::new_no_space());
             self.nodes.push(SerializeNode::new_text(":"));
             self.nodes.push(SerializeNode::Whitespace(WhitespaceOptions::new_newline().with_indent_incr(1)));
+            self.last_end = None;
 
             for modifier in &feature.modifiers {
                 self.serialize_modifier(modifier);
             }
+            self.serialize_comments(&feature.trailing_comments);
             self.decrease_indent(1);
+        } else if feature.definitions.is_empty() {
+            // without definitions and modifiers, the feature ends with its last field
+            self.last_end = Some(feature.span.end);
+            self.serialize_comments(&feature.trailing_comments);
         }
     }
 
-    fn serialize_modifier(&mut self, modifier: &Modifier) {
-        match modifier.value {
-            ModifierValue::SimpleBonus(v) => {
-                if v >= 0 {
-                    self.nodes.push(SerializeNode::new_text(&("+".to_string() + &v.to_string())));
-                } else {
-                    self.nodes.push(SerializeNode::new_text(&v.to_string()));
-                }
-                self.nodes.push(SerializeNode::new_space());
-                self.nodes.push(SerializeNode::new_text(&modifier.referencing.name));
+    fn serialize_definition(&mut self, definition: &Definition) {
+        self.serialize_comments(&definition.comments);
+        self.keep_blank_lines(definition.span);
+
+        self.nodes.push(SerializeNode::new_text(&definition.property.qualified_name()));
+        if let Some(selector) = &definition.selector {
+            self.nodes.push(SerializeNode::new_no_space());
+            self.nodes.push(SerializeNode::new_text(":"));
+            self.nodes.push(SerializeNode::new_space());
+            self.serialize_invocation(&selector.identifier, &selector.arguments);
+
+            for limiter in &definition.limiters {
                 self.nodes.push(SerializeNode::new_no_space());
-                self.nodes.push(SerializeNode::new_text(";"));
-                self.nodes.push(SerializeNode::new_newline());
-            },
-            ModifierValue::Bonus(v) => {
-                self.nodes.push(SerializeNode::new_text("bonus"));
-                self.nodes.push(SerializeNode::new_space());
-                self.nodes.push(SerializeNode::new_text("to"));
-                self.nodes.push(SerializeNode::new_space());
-                self.nodes.push(SerializeNode::new_text(&modifier.referencing.name));
+                self.nodes.push(SerializeNode::new_text(","));
                 self.nodes.push(SerializeNode::new_space());
-                self.nodes.push(SerializeNode::new_text("of"));
-                self.nodes.push(SerializeNode::new_space());
-                self.nodes.push(SerializeNode::new_text(&(v.to_string())));
+                self.serialize_invocation(&limiter.identifier, &limiter.arguments);
+            }
+            if let Some(rounding) = definition.rounding {
                 self.nodes.push(SerializeNode::new_no_space());
-                self.nodes.push(SerializeNode::new_text(";"));
-                self.nodes.push(SerializeNode::new_newline());
-            },
-            ModifierValue::Set(v) => {
-                self.nodes.push(SerializeNode::new_text("set"));
-                self.nodes.push(SerializeNode::new_space());
-                self.nodes.push(SerializeNode::new_text(&modifier.referencing.name));
+                self.nodes.push(SerializeNode::new_text(","));
                 self.nodes.push(SerializeNode::new_space());
-                self.nodes.push(SerializeNode::new_text("to"));
-                self.nodes.push(SerializeNode::new_space());
-                self.nodes.push(SerializeNode::new_text(&(v.to_string())));
-                self.nodes.push(SerializeNode::new_no_space());
-                self.nodes.push(SerializeNode::new_text(";"));
-                self.nodes.push(SerializeNode::new_newline());
+                self.serialize_invocation("round", &[rounding.name().to_string()]);
             }
         }
+        self.nodes.push(SerializeNode::new_no_space());
+        self.nodes.push(SerializeNode::new_text(";"));
+
+        self.serialize_trailing_comment(&definition.trailing_comment);
+        self.nodes.push(SerializeNode::new_newline());
+    }
+
+    fn serialize_invocation(&mut self, identifier: &str, arguments: &[String]) {
+        self.nodes.push(SerializeNode::new_text(identifier));
+        for argument in arguments {
+            self.nodes.push(SerializeNode::new_space());
+            if is_plain_argument(argument) {
+                self.nodes.push(SerializeNode::new_text(argument));
+            } else {
+                self.nodes.push(SerializeNode::new_text(&("\"".to_string() + argument + "\"")));
+            }
+        }
+    }
+
+    fn serialize_comments(&mut self, comments: &[Comment]) {
+        for comment in comments {
+            self.keep_blank_lines(comment.span);
+            self.nodes.push(SerializeNode::new_text(&comment.text));
+            self.nodes.push(SerializeNode::new_newline());
+        }
+    }
+
+    fn serialize_trailing_comment(&mut self, comment: &Option<Comment>) {
+        if let Some(comment) = comment {
+            self.nodes.push(SerializeNode::new_space());
+            self.nodes.push(SerializeNode::new_text(&comment.text));
+            self.last_end = Some(comment.span.end);
+        }
+    }
+
+    fn serialize_modifier(&mut self, modifier: &Modifier) {
+        self.serialize_comments(&modifier.comments);
+        self.keep_blank_lines(modifier.span);
+
+        let style = self.options.modifier_style;
+        let wrapped = match &modifier.value {
+            ModifierValue::SimpleBonus(v) if style == ModifierStyle::Long => {
+                self.serialize_bonus(vec![v.to_string()], modifier)
+            },
+            ModifierValue::SimpleDiceBonus(d) if style == ModifierStyle::Long => {
+                self.serialize_bonus(vec![d.to_string()], modifier)
+            },
+            ModifierValue::SimpleBonus(v) => self.serialize_simple_bonus(&signed(*v), modifier),
+            ModifierValue::Bonus(v) if style == ModifierStyle::Short => {
+                self.serialize_simple_bonus(&signed(*v), modifier)
+            },
+            ModifierValue::SimpleDiceBonus(d) => self.serialize_simple_dice_bonus(d, modifier),
+            ModifierValue::BonusExpression(e) => match (e.number(), e.dice()) {
+                (Some(v), _) if style == ModifierStyle::Short => self.serialize_simple_bonus(&signed(v), modifier),
+                (_, Some(d)) if style == ModifierStyle::Short && d.dice.len() == 1 && d.bonus == 0 => {
+                    self.serialize_simple_dice_bonus(&d, modifier)
+                },
+                _ => {
+                    let prefix = format!("bonus to {} of ", modifier.referencing.qualified_name());
+                    let parts = self.wrap(e, prefix.len());
+                    self.serialize_bonus(parts, modifier)
+                },
+            },
+            ModifierValue::Bonus(v) => self.serialize_bonus(vec![v.to_string()], modifier),
+            ModifierValue::Set(v) => self.serialize_set(vec![v.to_string()], modifier),
+            ModifierValue::SetExpression(e) => {
+                let prefix = format!("set {} to ", modifier.referencing.qualified_name());
+                let parts = self.wrap(e, prefix.len());
+                self.serialize_set(parts, modifier)
+            },
+        };
+
+        self.serialize_trailing_comment(&modifier.trailing_comment);
+        self.nodes.push(SerializeNode::new_newline());
+        if wrapped {
+            self.decrease_indent(1);
+        }
+    }
+
+    /// Splits the expression in front of its operators, if the line of the modifier would be
+    /// longer than the line width with it, e.g. `8`, `+ constitution`, `+ Fighter.level`.
+    fn wrap(&self, expression: &Expression, prefix: usize) -> Vec<String> {
+        // `+5` is read as the number 5
+        if let Some(number) = expression.number() {
+            return vec![number.to_string()];
+        }
+        let whole = expression.to_string();
+        // modifiers are always indented once and end with a `;`
+        if self.options.indent_width + prefix + whole.len() < self.options.line_width {
+            return vec![whole];
+        }
+
+        let parenthesized = |e: &Expression, op: &str, right: bool| {
+            if e.binds_weaker_than(op, right) { format!("({})", e) } else { e.to_string() }
+        };
+        let mut parts = vec![];
+        let mut current = expression;
+        let first = loop {
+            match &current.kind {
+                ExpressionKind::Binary(op, left, right) => {
+                    parts.push(format!("{} {}", op, parenthesized(right, op, true)));
+                    if left.binds_weaker_than(op, false) {
+                        break parenthesized(left, op, false);
+                    }
+                    current = left;
+                },
+                _ => break current.to_string(),
+            }
+        };
+        parts.push(first);
+        parts.reverse();
+        parts
+    }
+
+    /// Writes the parts of a value with a line break between them, returns whether there were
+    /// any line breaks.
+    fn serialize_value(&mut self, parts: Vec<String>) -> bool {
+        let wrapped = parts.len() > 1;
+        for (i, part) in parts.into_iter().enumerate() {
+            if i == 1 {
+                self.nodes.push(SerializeNode::Whitespace(WhitespaceOptions::new_line_break().with_indent_incr(1)));
+            } else if i > 1 {
+                self.nodes.push(SerializeNode::Whitespace(WhitespaceOptions::new_line_break()));
+            }
+            self.nodes.push(SerializeNode::Text(part));
+        }
+        wrapped
+    }
+
+    fn serialize_simple_dice_bonus(&mut self, dice: &DiceValue, modifier: &Modifier) -> bool {
+        let dice = dice.to_string();
+        if dice.starts_with('-') {
+            self.serialize_simple_bonus(&dice, modifier)
+        } else {
+            self.serialize_simple_bonus(&("+".to_string() + &dice), modifier)
+        }
+    }
+
+    fn serialize_simple_bonus(&mut self, value: &str, modifier: &Modifier) -> bool {
+        self.nodes.push(SerializeNode::new_text(value));
+        self.nodes.push(SerializeNode::new_space());
+        self.nodes.push(SerializeNode::new_text(&modifier.referencing.qualified_name()));
+        self.nodes.push(SerializeNode::new_no_space());
+        self.nodes.push(SerializeNode::new_text(";"));
+        false
+    }
+
+    fn serialize_bonus(&mut self, value: Vec<String>, modifier: &Modifier) -> bool {
+        self.nodes.push(SerializeNode::new_text("bonus"));
+        self.nodes.push(SerializeNode::new_space());
+        self.nodes.push(SerializeNode::new_text("to"));
+        self.nodes.push(SerializeNode::new_space());
+        self.nodes.push(SerializeNode::new_text(&modifier.referencing.qualified_name()));
+        self.nodes.push(SerializeNode::new_space());
+        self.nodes.push(SerializeNode::new_text("of"));
+        self.nodes.push(SerializeNode::new_space());
+        let wrapped = self.serialize_value(value);
+        self.nodes.push(SerializeNode::new_no_space());
+        self.nodes.push(SerializeNode::new_text(";"));
+        wrapped
+    }
+
+    fn serialize_set(&mut self, value: Vec<String>, modifier: &Modifier) -> bool {
+        self.nodes.push(SerializeNode::new_text("set"));
+        self.nodes.push(SerializeNode::new_space());
+        self.nodes.push(SerializeNode::new_text(&modifier.referencing.qualified_name()));
+        self.nodes.push(SerializeNode::new_space());
+        self.nodes.push(SerializeNode::new_text("to"));
+        self.nodes.push(SerializeNode::new_space());
+        let wrapped = self.serialize_value(value);
+        self.nodes.push(SerializeNode::new_no_space());
+        self.nodes.push(SerializeNode::new_text(";"));
+        wrapped
+    }
+}
+
+/// The number with its sign, e.g. `+2` or `-1/2`.
+fn signed(value: Fraction) -> String {
+    if value >= Fraction::from_integer(0) {
+        "+".to_string() + &value.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Whether the argument can be written without quotes, because it is lexed as a single identifier
+/// or (possibly negative) number.
+fn is_plain_argument(argument: &str) -> bool {
+    // numbers are normalized by the lexer, so only canonical ones can be written as they are
+    let is_number = !argument.contains('/')
+        && argument.parse::<Fraction>().is_ok_and(|n| n.to_string() == argument);
+    let is_identifier = argument.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && argument.chars().all(|c| c.is_ascii_alphabetic() || c == '_' || c == '-');
+    is_number || is_identifier
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format, serialize, serialize_with, FormatOptions, ModifierStyle};
+    use crate::parser::parse;
+    use crate::tokenizer::lex;
+
+    fn format_text(input: &str, options: &FormatOptions) -> String {
+        format(&parse(&lex(input)).unwrap().ast, input, options)
+    }
+
+    #[test]
+    fn keep_comments() {
+        let input = r#"# Races from the basic rules, p. 18
+Name: "Dwarf";
+Description: "Short and sturdy";
+Modifiers:
+  // ability score increase
+  +2 constitution; # p. 20
+  /* speed is not reduced
+     by heavy armor */
+  set speed to 25;
+  # darkvision is missing
+---
+/* Feats */
+Name: "Toughness";
+// no modifiers yet
+"#;
+        let ast = parse(&lex(input)).unwrap().ast;
+        assert_eq!(serialize(&ast), input);
+    }
+
+    #[test]
+    fn definitions() {
+        let input = r#"Name: "Attributes";
+Definitions:
+  # the six abilities
+  strength: highest, min 0, max 20;
+  Attributes.points: sum, step 0.5, round nearest; // half points
+  luck: first "of all", oneOf -1 "very lucky";
+  speed;
+Modifiers:
+  +2 strength;
+"#;
+        let ast = parse(&lex(input)).unwrap().ast;
+        assert_eq!(serialize(&ast), input);
+    }
+
+    #[test]
+    fn expressions() {
+        let input = r#"Name: "Rogue";
+Modifiers:
+  bonus to damage of 1d6 + Rogue.level;
+  set hp to 8 + max(constitution, -1) * 2;
+  +2d4x=4 sneak_attack;
+  -1d4 speed;
+  set ac to -(10 - dexterity) / 2;
+"#;
+        let ast = parse(&lex(input)).unwrap().ast;
+        assert_eq!(serialize(&ast), input);
+    }
+
+    #[test]
+    fn header() {
+        let input = r#"# everything from the basic rules
+FeatureSet: "Basic rules";
+Description: "The free rules";
+Source: "Basic rules, p. 18";
+---
+Name: "Dwarf";
+Type: "race";
+Description: "Short and sturdy";
+"#;
+        let ast = parse(&lex(input)).unwrap().ast;
+        assert_eq!(serialize(&ast), input);
+
+        let input = "FeatureSet: \"Empty\";\n// nothing here yet\n";
+        let ast = parse(&lex(input)).unwrap().ast;
+        assert_eq!(serialize(&ast), input);
+    }
+
+    #[test]
+    fn field_comments() {
+        let input = r#"FeatureSet: "Core"; // trailing
+Description: ""; # empty for now
+Source: "p. 18"; /* page */
+---
+Name: "Dwarf"; # name comment
+Type: "race"; // type comment
+Description: "Short and sturdy"; # description comment
+Definitions:
+  speed;
+"#;
+        let ast = parse(&lex(input)).unwrap().ast;
+        assert_eq!(ast.model.features[0].name_comment.as_ref().unwrap().text, "# name comment");
+        assert!(ast.model.features[0].definitions[0].comments.is_empty());
+        assert_eq!(serialize(&ast), input);
+    }
+
+    #[test]
+    fn format_options() {
+        let input = r#"Name: "Dwarf";
+Modifiers:
+  +2 constitution;
+  bonus to speed of -5;
+  bonus to damage of 1d6;
+  -1d4 stealth;
+  bonus to hp of 1d8 + 2;
+---
+Name: "Elf";
+Definitions:
+  dexterity: highest;
+"#;
+        let ast = parse(&lex(input)).unwrap().ast;
+        let options = FormatOptions {
+            use_tabs: true,
+            blank_lines_between_features: 1,
+            modifier_style: ModifierStyle::Short,
+            ..FormatOptions::default()
+        };
+        assert_eq!(serialize_with(&ast, &options), r#"Name: "Dwarf";
+Modifiers:
+	+2 constitution;
+	-5 speed;
+	+1d6 damage;
+	-1d4 stealth;
+	bonus to hp of 1d8 + 2;
+
+---
+Name: "Elf";
+Definitions:
+	dexterity: highest;
+"#);
+
+        let options = FormatOptions {
+            indent_width: 4,
+            modifier_style: ModifierStyle::Long,
+            ..FormatOptions::default()
+        };
+        assert_eq!(serialize_with(&ast, &options), r#"Name: "Dwarf";
+Modifiers:
+    bonus to constitution of 2;
+    bonus to speed of -5;
+    bonus to damage of 1d6;
+    bonus to stealth of -1d4;
+    bonus to hp of 1d8 + 2;
+---
+Name: "Elf";
+Definitions:
+    dexterity: highest;
+"#);
+    }
+
+    #[test]
+    fn blank_lines() {
+        let input = r#"Import "a.cs";
+
+
+Import "b.cs";
+Name: "Dwarf";
+
+Modifiers:
+  +2 constitution; // not the last comment
+
+  # speed
+
+
+  set speed to 25;
+  +1 hp;
+
+  // done
+"#;
+        let expected = r#"Import "a.cs";
+
+Import "b.cs";
+Name: "Dwarf";
+Modifiers:
+  +2 constitution; // not the last comment
+
+  # speed
+
+  set speed to 25;
+  +1 hp;
+
+  // done
+"#;
+        assert_eq!(format_text(input, &FormatOptions::default()), expected);
+
+        let options = FormatOptions { max_blank_lines: 0, ..FormatOptions::default() };
+        assert_eq!(format_text(input, &options), serialize(&parse(&lex(input)).unwrap().ast));
+    }
+
+    #[test]
+    fn wrap_long_values() {
+        let input = r#"Name: "Fighter";
+Modifiers:
+  set attack to strength + proficiency + Fighter.weapon_bonus - (1 + 2);
+  // the long form wraps, the short form is always a single line
+  bonus to damage of 1d8 + strength;
+"#;
+        let options = FormatOptions { line_width: 40, ..FormatOptions::default() };
+        assert_eq!(format_text(input, &options), r#"Name: "Fighter";
+Modifiers:
+  set attack to strength
+    + proficiency
+    + Fighter.weapon_bonus
+    - (1 + 2);
+  // the long form wraps, the short form is always a single line
+  bonus to damage of 1d8 + strength;
+"#);
+    }
+
+    #[test]
+    fn idempotent() {
+        let input = r#"# races
+Import "core.cs";
+FeatureSet: "Races"; Source: "p. 18";
+---
+Name: "Dwarf"; Type: "race";
+Description: "";
+Definitions: speed: highest, min 0;
+
+
+  luck;
+Modifiers: bonus to constitution of 2; +1d4 hp; # tough
+
+
+  /* slow */ set speed to 25 + floor(Dwarf.level / 2) * 5 - max(0, dexterity);
+--- Name: "Elf"; Modifiers: -1 constitution;
+"#;
+        let all_options = [
+            FormatOptions::default(),
+            FormatOptions { use_tabs: true, modifier_style: ModifierStyle::Short, ..FormatOptions::default() },
+            FormatOptions {
+                indent_width: 4,
+                blank_lines_between_features: 2,
+                max_blank_lines: 3,
+                modifier_style: ModifierStyle::Long,
+                line_width: 20,
+                ..FormatOptions::default()
+            },
+        ];
+        for options in &all_options {
+            let once = format_text(input, options);
+            assert_eq!(format_text(&once, options), once, "{:?}", options);
+        }
+
+        // comments after the fields, with and without blank lines in front of them
+        for input in [
+            "# h\nFeatureSet: \"Core\"; // a\n---\nName: \"D\";\n",
+            "# h\nFeatureSet: \"Core\";\n// a\n---\n# b\nName: \"D\";\nType: \"x\";\n// c\n",
+            "# h\nFeatureSet: \"Core\";\n\n// a\n---\n# b\nName: \"D\"; // d\n\n// c\n",
+        ] {
+            assert_eq!(format_text(input, &FormatOptions::default()), input);
+        }
+    }
+
+    #[test]
+    fn unterminated_comment() {
+        let input = "Name: \"Dwarf\";\n/* Modifiers:\n  +2 constitution;\n";
+        let errors = parse(&lex(input)).unwrap_err().errors;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "Unterminated block comment");
+        assert_eq!(errors[0].span().start, input.find("/*").unwrap());
     }
 }
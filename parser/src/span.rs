@@ -0,0 +1,182 @@
+use std::fmt;
+
+/// A range of bytes in the source text, `end` is exclusive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// The smallest span containing both spans.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+/// A position in the source text. Both values start at 0.
+/// The column counts characters, not bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct LineColumn {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for LineColumn {
+    /// Formats the position the way editors show it, starting at 1.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line + 1, self.column + 1)
+    }
+}
+
+/// Maps byte offsets in a source text to lines and columns.
+#[derive(Debug, Clone)]
+pub struct LineIndex<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(source: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        LineIndex {
+            source,
+            line_starts,
+        }
+    }
+
+    /// The line and column of a byte offset.
+    /// Offsets past the end or inside of a character are moved back to the closest character.
+    pub fn line_column(&self, offset: usize) -> LineColumn {
+        let mut offset = offset.min(self.source.len());
+        while !self.source.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let line = self.line_starts.partition_point(|start| *start <= offset) - 1;
+        let column = self.source[self.line_starts[line]..offset].chars().count();
+        LineColumn { line, column }
+    }
+
+    /// The text of a line without the line break.
+    pub fn line(&self, line: usize) -> &'a str {
+        let start = self.line_starts[line];
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .copied()
+            .unwrap_or(self.source.len());
+        self.source[start..end].trim_end_matches(['\n', '\r'])
+    }
+
+    /// Renders a message together with the source line of the span, which is underlined.
+    /// Spans over multiple lines are underlined until the end of their first line.
+    ///
+    /// ```text
+    /// error: Unexpected token
+    ///  --> 3:5
+    ///   |
+    /// 3 |     +2 constitution
+    ///   |     ^^
+    /// ```
+    pub fn render(&self, span: Span, message: &str) -> String {
+        self.render_labeled(span, "error", message)
+    }
+
+    /// Like [`render`](LineIndex::render), but with a different label than `error`.
+    pub fn render_labeled(&self, span: Span, label: &str, message: &str) -> String {
+        self.render_at(None, span, label, message)
+    }
+
+    /// Like [`render_labeled`](LineIndex::render_labeled), but the location starts with the name
+    /// of the file, e.g. `--> races.cs:3:5`.
+    pub fn render_in_file(&self, file: &str, span: Span, label: &str, message: &str) -> String {
+        self.render_at(Some(file), span, label, message)
+    }
+
+    fn render_at(&self, file: Option<&str>, span: Span, label: &str, message: &str) -> String {
+        let start = self.line_column(span.start);
+        let end = self.line_column(span.end);
+        let text = self.line(start.line);
+        let underlined = if end.line == start.line {
+            end.column.saturating_sub(start.column).max(1)
+        } else {
+            (text.chars().count() - start.column).max(1)
+        };
+        // keep tabs, so that the underline is aligned with the text
+        let indent: String = text
+            .chars()
+            .take(start.column)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        let number = (start.line + 1).to_string();
+        let gutter = " ".repeat(number.len());
+        let location = match file {
+            Some(file) => format!("{}:{}", file, start),
+            None => start.to_string(),
+        };
+        format!(
+            "{label}: {message}\n{gutter}--> {location}\n{gutter} |\n{number} | {text}\n{gutter} | {indent}{}",
+            "^".repeat(underlined)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LineColumn, LineIndex, Span};
+
+    #[test]
+    fn line_columns() {
+        let index = LineIndex::new("ab\nçd€f\n\nx");
+        let at = |offset| {
+            let position = index.line_column(offset);
+            (position.line, position.column)
+        };
+        assert_eq!(at(0), (0, 0));
+        assert_eq!(at(2), (0, 2));
+        assert_eq!(at(3), (1, 0));
+        assert_eq!(at(5), (1, 1), "ç is two bytes long");
+        assert_eq!(at(9), (1, 3), "€ is three bytes long");
+        assert_eq!(at(7), (1, 2), "the middle of € is moved to its start");
+        assert_eq!(at(11), (2, 0));
+        assert_eq!(at(12), (3, 0));
+        assert_eq!(at(100), (3, 1));
+        assert_eq!(index.line(1), "çd€f");
+        assert_eq!(
+            LineColumn { line: 2, column: 0 }.to_string(),
+            "3:1",
+            "lines and columns are shown starting at 1"
+        );
+    }
+
+    #[test]
+    fn render() {
+        let source = "Name: \"Dwarf\";\nModifiers:\n    +2 €constitution;\n";
+        let index = LineIndex::new(source);
+        let start = source.find('€').unwrap();
+        assert_eq!(
+            index.render(Span::new(start, start + "€constitution".len()), "Unknown property"),
+            "error: Unknown property\n --> 3:8\n  |\n3 |     +2 €constitution;\n  |        ^^^^^^^^^^^^^"
+        );
+        assert_eq!(
+            index.render(
+                Span::new(source.len(), source.len()),
+                "Unexpected end of input"
+            ),
+            "error: Unexpected end of input\n --> 4:1\n  |\n4 | \n  | ^"
+        );
+    }
+}
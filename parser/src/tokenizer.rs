@@ -2,17 +2,21 @@ use regex::Regex;
 
 use thiserror::Error;
 
+use types::fraction::Fraction;
+
+use crate::span::Span;
+
 #[derive(Debug,Clone,PartialEq,Eq,Hash)]
 pub struct Token {
     pub token_type: TokenType,
-    pub offset: i32,
+    pub span: Span,
 }
 
 impl Token {
-    pub fn new(token_type: TokenType, offset: i32) -> Self {
+    pub fn new(token_type: TokenType, span: Span) -> Self {
         Self {
             token_type,
-            offset
+            span
         }
     }
 }
@@ -21,15 +25,20 @@ impl Token {
 pub enum TokenType {
     Identifier(String),
     Dice(String),
-    Number(i32),
+    Number(Fraction),
     String(String),
     OpeningBracket,
     ClosingBracket,
+    OpeningParenthesis,
+    ClosingParenthesis,
     Colon,
     Semicolon,
+    Comma,
+    Dot,
     Section, // ---
     Operator(String),
     Whitespace(String),
+    Comment(String), // including the `#`, `//` or `/* */`
     EndOfInput,
     Unknown(String),
 }
@@ -43,11 +52,16 @@ impl TokenType {
             TokenType::String(text) => text.clone(),
             TokenType::OpeningBracket => "{".to_string(),
             TokenType::ClosingBracket => "}".to_string(),
+            TokenType::OpeningParenthesis => "(".to_string(),
+            TokenType::ClosingParenthesis => ")".to_string(),
             TokenType::Colon => ":".to_string(),
             TokenType::Semicolon => ";".to_string(),
+            TokenType::Comma => ",".to_string(),
+            TokenType::Dot => ".".to_string(),
             TokenType::Section => "---".to_string(),
             TokenType::Operator(text) => text.clone(),
             TokenType::Whitespace(text) => text.clone(),
+            TokenType::Comment(text) => text.clone(),
             TokenType::EndOfInput => "End of Input".to_string(),
             TokenType::Unknown(text) => text.clone(),
         }
@@ -61,6 +75,7 @@ impl TokenType {
             (TokenType::String(_), TokenType::String(_)) => true,
             (TokenType::Operator(_), TokenType::Operator(_)) => true,
             (TokenType::Whitespace(_), TokenType::Whitespace(_)) => true,
+            (TokenType::Comment(_), TokenType::Comment(_)) => true,
             (TokenType::Unknown(_), TokenType::Unknown(_)) => true,
             (a, b) if a == b => true,
             (_, _) => false,
@@ -71,7 +86,11 @@ impl TokenType {
 #[derive(Debug,Error)]
 pub enum TokenizationIssue {
     #[error("Unknown token found: '{1}'")]
-    UnknownToken(i32, String),
+    UnknownToken(Span, String),
+    #[error("Unterminated block comment")]
+    UnterminatedComment(Span),
+    #[error("The number {1} is too large")]
+    NumberTooLarge(Span, String),
 }
 
 pub fn lex(input: &str) -> Vec<Token> {
@@ -80,83 +99,162 @@ pub fn lex(input: &str) -> Vec<Token> {
     let mut offset = 0;
 
     let identifier_regex: Regex = Regex::new(r"^[a-zA-Z_][a-zA-Z_-]*").unwrap();
-    let dice_regex: Regex = Regex::new(r"^\d+d\d+([+-]\d+d\d+)*([+-]\d+)?").unwrap();
+    // a single group of dice with its modifiers, sums of dice are expressions
+    let dice_regex: Regex = Regex::new(r"^\d+d\d+([kdrxc][hla<>=]?\d*)*").unwrap();
     let number_regex: Regex = Regex::new(r"^\d+([,.]\d+)?").unwrap();
+    // in the arguments of a call the `,` separates the arguments, e.g. `max(1,2)`, and in a
+    // definition the selector and limiters, e.g. `speed: last, oneOf 2,4`
+    let argument_number_regex: Regex = Regex::new(r"^\d+(\.\d+)?").unwrap();
     let string_regex: Regex = Regex::new(r#"^"(([^"]|\\")*)""#).unwrap();
     let opening_bracket_regex: Regex = Regex::new(r"^\{").unwrap();
     let closing_bracket_regex: Regex = Regex::new(r"^}").unwrap();
+    let opening_parenthesis_regex: Regex = Regex::new(r"^\(").unwrap();
+    let closing_parenthesis_regex: Regex = Regex::new(r"^\)").unwrap();
     let colon_regex: Regex = Regex::new(r"^:").unwrap();
     let semicolon_regex: Regex = Regex::new(r"^;").unwrap();
+    let comma_regex: Regex = Regex::new(r"^,").unwrap();
+    let dot_regex: Regex = Regex::new(r"^\.").unwrap();
     let section_regex: Regex = Regex::new(r"^---").unwrap();
     let operator_regex: Regex = Regex::new(r"^[+\-*/]").unwrap();
     let whitespace_regex: Regex = Regex::new(r"^\s+").unwrap();
+    let line_comment_regex: Regex = Regex::new(r"^(#|//)[^\r\n]*").unwrap();
+    let block_comment_regex: Regex = Regex::new(r"^/\*(?s:.*?)(\*/|$)").unwrap();
+
+    // whether each open parenthesis starts the arguments of a call, and how many of them do
+    let mut parentheses: Vec<bool> = Vec::new();
+    let mut open_calls = 0;
+    // from `Definitions` up to `Modifiers` or the end of the feature
+    let mut in_definitions = false;
 
     while !remaining.is_empty() {
         let len: usize;
+        let token_type: TokenType;
+        let number_regex = if open_calls > 0 || in_definitions { &argument_number_regex } else { &number_regex };
         if let Some(captures) = identifier_regex.captures(remaining) {
             let matched = captures.get(0).unwrap().as_str();
-            tokens.push(Token::new(TokenType::Identifier(matched.to_string()), offset));
+            token_type = TokenType::Identifier(matched.to_string());
             len = matched.len();
         } else if let Some(captures) = dice_regex.captures(remaining) {
             let matched = captures.get(0).unwrap().as_str();
-            tokens.push(Token::new(TokenType::Dice(matched.to_string()), offset));
+            token_type = TokenType::Dice(matched.to_string());
             len = matched.len();
         } else if let Some(captures) = number_regex.captures(remaining) {
             let matched = captures.get(0).unwrap().as_str();
-            tokens.push(Token::new(TokenType::Number(matched.parse().unwrap()), offset));
+            // the regex only matches valid numbers, so parsing can only fail if it is too large
+            token_type = match matched.parse() {
+                Ok(number) => TokenType::Number(number),
+                Err(_) => TokenType::Unknown(matched.to_string()),
+            };
             len = matched.len();
         } else if let Some(captures) = string_regex.captures(remaining) {
             let matched = captures.get(1).unwrap().as_str();
-            tokens.push(Token::new(TokenType::String(matched.to_string()), offset));
+            token_type = TokenType::String(matched.to_string());
             len = captures.get(0).unwrap().len();
         } else if let Some(captures) = opening_bracket_regex.captures(remaining) {
             let matched = captures.get(0).unwrap().as_str();
-            tokens.push(Token::new(TokenType::OpeningBracket,offset));
+            token_type = TokenType::OpeningBracket;
             len = matched.len();
         } else if let Some(captures) = closing_bracket_regex.captures(remaining) {
             let matched = captures.get(0).unwrap().as_str();
-            tokens.push(Token::new(TokenType::ClosingBracket,offset));
+            token_type = TokenType::ClosingBracket;
+            len = matched.len();
+        } else if let Some(captures) = opening_parenthesis_regex.captures(remaining) {
+            let matched = captures.get(0).unwrap().as_str();
+            token_type = TokenType::OpeningParenthesis;
+            len = matched.len();
+        } else if let Some(captures) = closing_parenthesis_regex.captures(remaining) {
+            let matched = captures.get(0).unwrap().as_str();
+            token_type = TokenType::ClosingParenthesis;
             len = matched.len();
         } else if let Some(captures) = colon_regex.captures(remaining) {
             let matched = captures.get(0).unwrap().as_str();
-            tokens.push(Token::new(TokenType::Colon, offset));
+            token_type = TokenType::Colon;
             len = matched.len();
         } else if let Some(captures) = semicolon_regex.captures(remaining) {
             let matched = captures.get(0).unwrap().as_str();
-            tokens.push(Token::new(TokenType::Semicolon, offset));
+            token_type = TokenType::Semicolon;
+            len = matched.len();
+        } else if let Some(captures) = comma_regex.captures(remaining) {
+            let matched = captures.get(0).unwrap().as_str();
+            token_type = TokenType::Comma;
+            len = matched.len();
+        } else if let Some(captures) = dot_regex.captures(remaining) {
+            let matched = captures.get(0).unwrap().as_str();
+            token_type = TokenType::Dot;
             len = matched.len();
         } else if let Some(captures) = section_regex.captures(remaining) {
             let matched = captures.get(0).unwrap().as_str();
-            tokens.push(Token::new(TokenType::Section, offset));
+            token_type = TokenType::Section;
+            len = matched.len();
+        } else if let Some(captures) = line_comment_regex.captures(remaining) {
+            let matched = captures.get(0).unwrap().as_str();
+            token_type = TokenType::Comment(matched.to_string());
+            len = matched.len();
+        } else if let Some(captures) = block_comment_regex.captures(remaining) {
+            let matched = captures.get(0).unwrap().as_str();
+            token_type = TokenType::Comment(matched.to_string());
             len = matched.len();
         } else if let Some(captures) = operator_regex.captures(remaining) {
             let matched = captures.get(0).unwrap().as_str();
-            tokens.push(Token::new(TokenType::Operator(matched.to_string()), offset));
+            token_type = TokenType::Operator(matched.to_string());
             len = matched.len();
         } else if let Some(captures) = whitespace_regex.captures(remaining) {
             let matched = captures.get(0).unwrap().as_str();
-            tokens.push(Token::new(TokenType::Whitespace(matched.to_string()), offset));
+            token_type = TokenType::Whitespace(matched.to_string());
             len = matched.len();
         } else if let Some(next_char) = remaining.chars().next() {
-            tokens.push(Token::new(TokenType::Unknown(next_char.to_string()), offset));
-            len = 1;
+            token_type = TokenType::Unknown(next_char.to_string());
+            len = next_char.len_utf8();
         } else {
             // can never happen
-            len = 0;
+            break;
         }
-        offset += len as i32;
+        match &token_type {
+            TokenType::Identifier(identifier) if identifier == "Definitions" => in_definitions = true,
+            TokenType::Identifier(identifier) if identifier == "Modifiers" => in_definitions = false,
+            TokenType::Section => in_definitions = false,
+            TokenType::OpeningParenthesis => {
+                let call = is_call(&tokens);
+                open_calls += usize::from(call);
+                parentheses.push(call);
+            }
+            TokenType::ClosingParenthesis if parentheses.pop() == Some(true) => {
+                open_calls -= 1;
+            }
+            _ => {}
+        }
+        tokens.push(Token::new(token_type, Span::new(offset, offset + len)));
+        offset += len;
         remaining = &remaining[len..];
     }
     
-    tokens.push(Token::new(TokenType::EndOfInput, offset));
+    tokens.push(Token::new(TokenType::EndOfInput, Span::new(offset, offset)));
     tokens
 }
 
+/// Whether a `(` after the tokens starts the arguments of a call, i.e. follows the name of a
+/// function. `to` and `of` are followed by a value instead, e.g. `bonus to hp of (1,5 + 2);`.
+fn is_call(tokens: &[Token]) -> bool {
+    let previous = tokens
+        .iter()
+        .rev()
+        .find(|t| !matches!(t.token_type, TokenType::Whitespace(_) | TokenType::Comment(_)));
+    matches!(previous, Some(Token { token_type: TokenType::Identifier(name), .. }) if name != "to" && name != "of")
+}
+
 pub fn validate(tokens: &[Token]) -> Option<Vec<TokenizationIssue>> {
     let unknown_tokens = tokens
         .iter()
-        .filter(|t| matches!(t.token_type, TokenType::Unknown(_)))
-        .map(|t| TokenizationIssue::UnknownToken(t.offset, t.token_type.get_string()) )
+        .filter_map(|t| match &t.token_type {
+            TokenType::Unknown(text) if text.starts_with(|c: char| c.is_ascii_digit()) => {
+                Some(TokenizationIssue::NumberTooLarge(t.span, text.clone()))
+            }
+            TokenType::Unknown(text) => Some(TokenizationIssue::UnknownToken(t.span, text.clone())),
+            TokenType::Comment(text) if text.starts_with("/*") && (text.len() < 4 || !text.ends_with("*/")) => {
+                Some(TokenizationIssue::UnterminatedComment(t.span))
+            }
+            _ => None,
+        })
         .collect::<Vec<_>>();
 
     if unknown_tokens.is_empty() {
@@ -1,6 +1,8 @@
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
+use crate::fraction::Fraction;
+
 /// As the name implies a feature set bundles a bunch of features together.
 /// In most games this may be anything from classes to races to items or even spells in some cases.
 #[cfg_attr(
@@ -54,6 +56,29 @@ pub struct PropertyDefinition {
     pub selector: Selector,
     /// The limiters limit the possible values of this property.
     pub limiters: Vec<Limiter>,
+    /// How fractional values of this property are rounded to whole numbers.
+    /// Without a rounding mode, fractional values are kept as they are.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub rounding: Option<RoundingMode>,
+}
+
+/// How a fractional value is rounded to a whole number.
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "camelCase", deny_unknown_fields)
+)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RoundingMode {
+    /// Towards negative infinity, e.g. `2.5` to `2` and `-2.5` to `-3`.
+    Down,
+    /// Towards positive infinity, e.g. `2.5` to `3` and `-2.5` to `-2`.
+    Up,
+    /// To the closest whole number and halfway values away from zero, e.g. `2.5` to `3` and
+    /// `-2.5` to `-3`.
+    Nearest,
+    /// Drops the fractional part, e.g. `2.5` to `2` and `-2.5` to `-2`.
+    TowardZero,
 }
 
 /// A selector selects a given value out of a list of possible ones.
@@ -130,6 +155,8 @@ impl Default for CalculatedValue {
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum StaticValueType {
     Number(i32),
+    /// A value that is not a whole number, or a whole number that does not fit into a `Number`.
+    Fraction(Fraction),
     Dice(DiceValue),
 }
 
@@ -285,7 +312,7 @@ mod tests {
     }
 
     fn get_example_features() -> Vec<FeatureSet> {
-        return vec![FeatureSet {
+        vec![FeatureSet {
             name: "feature_set1".to_string(),
             description: "This is feature set 1.".to_string(),
             source: "Basic rules".to_string(),
@@ -303,6 +330,7 @@ mod tests {
                         identifier: "limiter1".to_string(),
                         arguments: vec!["arg1".to_string()],
                     }],
+                    rounding: None,
                 }],
                 modifiers: vec![
                     FeatureModifier {
@@ -336,6 +364,6 @@ mod tests {
                     },
                 ],
             }],
-        }];
+        }]
     }
 }
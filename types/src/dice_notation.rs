@@ -0,0 +1,522 @@
+//! Standard dice notation for [`DiceValue`]s, e.g. `4d6kh3+1d4-2`.
+//!
+//! # Syntax
+//!
+//! A dice value is a sum of dice groups and numbers, e.g. `2d6 + 1d4 - 2`. Numbers are added to
+//! the bonus, a group that is subtracted gets a negative amount.
+//!
+//! A group is written `<amount>d<sides>` (the amount defaults to 1), followed by any number of
+//! modifiers. A modifier is one of the letters of [`DiceModifier`] followed by a selector:
+//!
+//! | Selector     | Notation | Example    |
+//! |--------------|----------|------------|
+//! | `Highest(n)` | `hn`     | `4d6kh3`   |
+//! | `Lowest(n)`  | `ln`     | `4d6dl1`   |
+//! | `HigherThan` | `>n`     | `5d10c>7`  |
+//! | `LowerThan`  | `<n`     | `1d6r<2`   |
+//! | `Exactly`    | `=n`     | `1d6x=6`   |
+//! | `All`        | `a`      | `3d6ka`    |
+//!
+//! The following short forms are accepted, but never written:
+//!
+//! * a number without prefix means `Exactly`, e.g. `1d20r1`
+//! * `h` and `l` without a number select 1 die, e.g. `2d20kh`
+//! * `x` without a selector explodes on the highest side, e.g. `3d6x`
+//! * `c` without a selector counts all dice
+//!
+//! Whitespace between the parts is ignored. Formatting a [`DiceValue`] always writes the
+//! canonical notation without whitespace, which parses back to the same value.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::character_sheet_collection::{Dice, DiceModifier, DiceSelector, DiceValue};
+
+/// An error while parsing dice notation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiceParseError {
+    /// Byte offset into the input at which the error occured.
+    pub position: usize,
+    pub kind: DiceParseErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiceParseErrorKind {
+    /// The input does not contain any dice or numbers.
+    Empty,
+    /// A number, dice group or modifier was expected, but something else was found.
+    Unexpected {
+        found: String,
+        expected: &'static str,
+    },
+    /// A number does not fit into the value model.
+    NumberTooLarge(String),
+    /// Dice need at least one side.
+    NoSides,
+    /// Dice groups must have at least one die.
+    NoDice,
+}
+
+impl fmt::Display for DiceParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at position {})", self.kind, self.position)
+    }
+}
+
+impl fmt::Display for DiceParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiceParseErrorKind::Empty => write!(f, "Expected dice, but the input is empty"),
+            DiceParseErrorKind::Unexpected { found, expected } => {
+                write!(f, "Unexpected {}, expected {}", found, expected)
+            }
+            DiceParseErrorKind::NumberTooLarge(number) => {
+                write!(f, "The number {} is too large", number)
+            }
+            DiceParseErrorKind::NoSides => write!(f, "Dice need at least one side"),
+            DiceParseErrorKind::NoDice => write!(f, "A dice group needs at least one die"),
+        }
+    }
+}
+
+impl std::error::Error for DiceParseError {}
+
+impl FromStr for DiceValue {
+    type Err = DiceParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = NotationParser {
+            input: s,
+            position: 0,
+        };
+        parser.dice_value()
+    }
+}
+
+impl fmt::Display for DiceValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, dice) in self.dice.iter().enumerate() {
+            if index > 0 && dice.amount >= 0 {
+                write!(f, "+")?;
+            }
+            write!(f, "{}", dice)?;
+        }
+        if self.dice.is_empty() {
+            write!(f, "{}", self.bonus)?;
+        } else if self.bonus > 0 {
+            write!(f, "+{}", self.bonus)?;
+        } else if self.bonus < 0 {
+            write!(f, "{}", self.bonus)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Dice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}d{}", self.amount, self.sides)?;
+        for modifier in &self.modifiers {
+            write!(f, "{}", modifier)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DiceModifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiceModifier::Keep(s) => write!(f, "k{}", s),
+            DiceModifier::Drop(s) => write!(f, "d{}", s),
+            DiceModifier::Reroll(s) => write!(f, "r{}", s),
+            DiceModifier::Explode(s) => write!(f, "x{}", s),
+            DiceModifier::Count(s) => write!(f, "c{}", s),
+        }
+    }
+}
+
+impl fmt::Display for DiceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiceSelector::Highest(n) => write!(f, "h{}", n),
+            DiceSelector::Lowest(n) => write!(f, "l{}", n),
+            DiceSelector::HigherThan(n) => write!(f, ">{}", n),
+            DiceSelector::LowerThan(n) => write!(f, "<{}", n),
+            DiceSelector::Exactly(n) => write!(f, "={}", n),
+            DiceSelector::All => write!(f, "a"),
+        }
+    }
+}
+
+struct NotationParser<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl<'a> NotationParser<'a> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.input[self.position..];
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.position..].chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.position += c.len_utf8();
+            return true;
+        }
+        false
+    }
+
+    fn unexpected(&self, expected: &'static str) -> DiceParseError {
+        let found = match self.peek() {
+            Some(c) => format!("`{}`", c),
+            None => "end of input".to_string(),
+        };
+        DiceParseError {
+            position: self.position,
+            kind: DiceParseErrorKind::Unexpected { found, expected },
+        }
+    }
+
+    /// Reads the digits at the current position, if there are any.
+    fn digits(&mut self) -> Option<(usize, &'a str)> {
+        let start = self.position;
+        let length = self.input[start..]
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.input.len() - start);
+        if length == 0 {
+            return None;
+        }
+        self.position += length;
+        Some((start, &self.input[start..start + length]))
+    }
+
+    fn number<T: FromStr>(&mut self, expected: &'static str) -> Result<T, DiceParseError> {
+        let (start, digits) = self.digits().ok_or_else(|| self.unexpected(expected))?;
+        digits.parse().map_err(|_| DiceParseError {
+            position: start,
+            kind: DiceParseErrorKind::NumberTooLarge(digits.to_string()),
+        })
+    }
+
+    fn dice_value(&mut self) -> Result<DiceValue, DiceParseError> {
+        let mut value = DiceValue::default();
+        self.skip_whitespace();
+        if self.peek().is_none() {
+            return Err(DiceParseError {
+                position: self.position,
+                kind: DiceParseErrorKind::Empty,
+            });
+        }
+
+        let mut negative = self.eat('-');
+        if !negative {
+            self.eat('+');
+        }
+        loop {
+            self.skip_whitespace();
+            self.term(negative, &mut value)?;
+            self.skip_whitespace();
+            if self.eat('+') {
+                negative = false;
+            } else if self.eat('-') {
+                negative = true;
+            } else if self.peek().is_none() {
+                return Ok(value);
+            } else {
+                return Err(self.unexpected("`+`, `-` or a modifier"));
+            }
+        }
+    }
+
+    /// Parses a dice group or a number and adds it to the value.
+    fn term(&mut self, negative: bool, value: &mut DiceValue) -> Result<(), DiceParseError> {
+        let start = self.position;
+        let amount: Option<i32> = match self.peek() {
+            Some(c) if c.is_ascii_digit() => Some(self.number("a number")?),
+            _ => None,
+        };
+
+        if !self.eat('d') {
+            let number = amount.ok_or_else(|| self.unexpected("a number or dice"))?;
+            let number = if negative { -number } else { number };
+            value.bonus = value.bonus.checked_add(number).ok_or(DiceParseError {
+                position: start,
+                kind: DiceParseErrorKind::NumberTooLarge(
+                    self.input[start..self.position].to_string(),
+                ),
+            })?;
+            return Ok(());
+        }
+
+        let amount = amount.unwrap_or(1);
+        if amount == 0 {
+            return Err(DiceParseError {
+                position: start,
+                kind: DiceParseErrorKind::NoDice,
+            });
+        }
+        let sides: u32 = self.number("the amount of sides")?;
+        if sides == 0 {
+            return Err(DiceParseError {
+                position: start,
+                kind: DiceParseErrorKind::NoSides,
+            });
+        }
+
+        let mut modifiers = vec![];
+        while let Some(modifier) = self.modifier(sides)? {
+            modifiers.push(modifier);
+        }
+        value.dice.push(Dice {
+            amount: if negative { -amount } else { amount },
+            sides,
+            modifiers,
+        });
+        Ok(())
+    }
+
+    fn modifier(&mut self, sides: u32) -> Result<Option<DiceModifier>, DiceParseError> {
+        let letter = match self.peek() {
+            Some(c @ ('k' | 'd' | 'r' | 'x' | 'c')) => c,
+            _ => return Ok(None),
+        };
+        self.position += 1;
+
+        let selector = match (letter, self.selector()?) {
+            (_, Some(selector)) => selector,
+            ('x', None) => DiceSelector::Exactly(sides.min(u16::MAX as u32) as u16),
+            ('c', None) => DiceSelector::All,
+            (_, None) => return Err(self.unexpected("a selector")),
+        };
+        Ok(Some(match letter {
+            'k' => DiceModifier::Keep(selector),
+            'd' => DiceModifier::Drop(selector),
+            'r' => DiceModifier::Reroll(selector),
+            'x' => DiceModifier::Explode(selector),
+            _ => DiceModifier::Count(selector),
+        }))
+    }
+
+    fn selector(&mut self) -> Result<Option<DiceSelector>, DiceParseError> {
+        let selector = match self.peek() {
+            Some('h') => {
+                self.position += 1;
+                DiceSelector::Highest(self.optional_count()?)
+            }
+            Some('l') => {
+                self.position += 1;
+                DiceSelector::Lowest(self.optional_count()?)
+            }
+            Some('>') => {
+                self.position += 1;
+                DiceSelector::HigherThan(self.number("a number")?)
+            }
+            Some('<') => {
+                self.position += 1;
+                DiceSelector::LowerThan(self.number("a number")?)
+            }
+            Some('=') => {
+                self.position += 1;
+                DiceSelector::Exactly(self.number("a number")?)
+            }
+            Some('a') => {
+                self.position += 1;
+                DiceSelector::All
+            }
+            Some(c) if c.is_ascii_digit() => DiceSelector::Exactly(self.number("a number")?),
+            _ => return Ok(None),
+        };
+        Ok(Some(selector))
+    }
+
+    fn optional_count(&mut self) -> Result<u16, DiceParseError> {
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() => self.number("a number"),
+            _ => Ok(1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::character_sheet_collection::{Dice, DiceModifier, DiceSelector, DiceValue};
+
+    use super::{DiceParseError, DiceParseErrorKind};
+
+    fn dice(amount: i32, sides: u32, modifiers: Vec<DiceModifier>) -> Dice {
+        Dice {
+            amount,
+            sides,
+            modifiers,
+        }
+    }
+
+    fn parse(s: &str) -> Result<DiceValue, DiceParseError> {
+        s.parse()
+    }
+
+    #[test]
+    fn parse_groups_and_bonus() {
+        assert_eq!(
+            parse("2d6+1d4-2"),
+            Ok(DiceValue {
+                dice: vec![dice(2, 6, vec![]), dice(1, 4, vec![])],
+                bonus: -2,
+            })
+        );
+        assert_eq!(
+            parse(" -d8 + 3 + 2d10 - 1 "),
+            Ok(DiceValue {
+                dice: vec![dice(-1, 8, vec![]), dice(2, 10, vec![])],
+                bonus: 2,
+            })
+        );
+        assert_eq!(
+            parse("5"),
+            Ok(DiceValue {
+                dice: vec![],
+                bonus: 5,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_modifiers() {
+        assert_eq!(
+            parse("4d6kh3"),
+            Ok(DiceValue {
+                dice: vec![dice(
+                    4,
+                    6,
+                    vec![DiceModifier::Keep(DiceSelector::Highest(3))]
+                )],
+                bonus: 0,
+            })
+        );
+        assert_eq!(
+            parse("2d20kl+1d6x+4d6dl1r<2c>4ka"),
+            Ok(DiceValue {
+                dice: vec![
+                    dice(2, 20, vec![DiceModifier::Keep(DiceSelector::Lowest(1))]),
+                    dice(1, 6, vec![DiceModifier::Explode(DiceSelector::Exactly(6))]),
+                    dice(
+                        4,
+                        6,
+                        vec![
+                            DiceModifier::Drop(DiceSelector::Lowest(1)),
+                            DiceModifier::Reroll(DiceSelector::LowerThan(2)),
+                            DiceModifier::Count(DiceSelector::HigherThan(4)),
+                            DiceModifier::Keep(DiceSelector::All),
+                        ]
+                    ),
+                ],
+                bonus: 0,
+            })
+        );
+        assert_eq!(
+            parse("1d20r1+5d10c"),
+            Ok(DiceValue {
+                dice: vec![
+                    dice(1, 20, vec![DiceModifier::Reroll(DiceSelector::Exactly(1))]),
+                    dice(5, 10, vec![DiceModifier::Count(DiceSelector::All)]),
+                ],
+                bonus: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let all_selectors = [
+            DiceSelector::Highest(2),
+            DiceSelector::Lowest(1),
+            DiceSelector::HigherThan(5),
+            DiceSelector::LowerThan(2),
+            DiceSelector::Exactly(6),
+            DiceSelector::All,
+        ];
+        let mut modifiers = vec![];
+        for selector in all_selectors {
+            modifiers.push(DiceModifier::Keep(selector.clone()));
+            modifiers.push(DiceModifier::Drop(selector.clone()));
+            modifiers.push(DiceModifier::Reroll(selector.clone()));
+            modifiers.push(DiceModifier::Explode(selector.clone()));
+            modifiers.push(DiceModifier::Count(selector));
+        }
+
+        let values = [
+            DiceValue::default(),
+            DiceValue {
+                dice: vec![],
+                bonus: -4,
+            },
+            DiceValue {
+                dice: vec![dice(-2, 4, vec![]), dice(3, 8, vec![])],
+                bonus: 7,
+            },
+            DiceValue {
+                dice: vec![dice(10, 10, modifiers), dice(-1, 100, vec![])],
+                bonus: -1,
+            },
+        ];
+        for value in values {
+            let text = value.to_string();
+            assert_eq!(parse(&text), Ok(value), "Round trip of {}", text);
+        }
+
+        assert_eq!(parse("2d6 - 1d4 + 2").unwrap().to_string(), "2d6-1d4+2");
+        assert_eq!(parse("4d6kh3").unwrap().to_string(), "4d6kh3");
+        assert_eq!(parse("3d6x").unwrap().to_string(), "3d6x=6");
+    }
+
+    #[test]
+    fn errors() {
+        let error = |s: &str| parse(s).unwrap_err();
+
+        assert_eq!(error("  ").kind, DiceParseErrorKind::Empty);
+        assert_eq!(
+            error("2d6 +"),
+            DiceParseError {
+                position: 5,
+                kind: DiceParseErrorKind::Unexpected {
+                    found: "end of input".to_string(),
+                    expected: "a number or dice"
+                }
+            }
+        );
+        assert_eq!(
+            error("2d"),
+            DiceParseError {
+                position: 2,
+                kind: DiceParseErrorKind::Unexpected {
+                    found: "end of input".to_string(),
+                    expected: "the amount of sides"
+                }
+            }
+        );
+        assert_eq!(
+            error("4d6k"),
+            DiceParseError {
+                position: 4,
+                kind: DiceParseErrorKind::Unexpected {
+                    found: "end of input".to_string(),
+                    expected: "a selector"
+                }
+            }
+        );
+        assert_eq!(
+            error("4d6q").to_string(),
+            "Unexpected `q`, expected `+`, `-` or a modifier (at position 3)"
+        );
+        assert_eq!(error("1d0").kind, DiceParseErrorKind::NoSides);
+        assert_eq!(error("0d6").kind, DiceParseErrorKind::NoDice);
+        assert_eq!(
+            error("1d6kh70000").kind,
+            DiceParseErrorKind::NumberTooLarge("70000".to_string())
+        );
+    }
+}
@@ -0,0 +1,469 @@
+//! Exact fractions for values that are not whole numbers, e.g. half points or a damage multiplier
+//! of `1.5`.
+//!
+//! # Syntax
+//!
+//! Fractions are written as decimals with a `.` or `,` as separator (`1.5`, `-0,25`) or as
+//! quotient of two whole numbers (`1/3`). Formatting a [`Fraction`] writes a decimal with `.` if
+//! the value has a finite decimal representation and a quotient otherwise, both of which parse
+//! back to the same value.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::character_sheet_collection::{RoundingMode, StaticValueType};
+
+/// An exact rational number.
+/// Always stored in lowest terms with a positive denominator, so that equal values are also
+/// structurally equal.
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "camelCase", try_from = "RawFraction")
+)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fraction {
+    numerator: i64,
+    denominator: i64,
+}
+
+/// A fraction as it is deserialized, before it is reduced to lowest terms.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct RawFraction {
+    numerator: i64,
+    denominator: i64,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<RawFraction> for Fraction {
+    type Error = String;
+
+    fn try_from(raw: RawFraction) -> Result<Self, Self::Error> {
+        Fraction::new(raw.numerator, raw.denominator).ok_or_else(|| {
+            format!(
+                "{}/{} is not a valid fraction",
+                raw.numerator, raw.denominator
+            )
+        })
+    }
+}
+
+impl Fraction {
+    pub fn from_integer(value: i64) -> Fraction {
+        Fraction {
+            numerator: value,
+            denominator: 1,
+        }
+    }
+
+    /// Creates the fraction `numerator / denominator` in lowest terms.
+    /// Returns `None` if the denominator is 0 or the fraction can not be represented.
+    pub fn new(numerator: i64, denominator: i64) -> Option<Fraction> {
+        if denominator == 0 {
+            return None;
+        }
+        let sign = if denominator < 0 { -1 } else { 1 };
+        let divisor = gcd(numerator, denominator).max(1);
+        Some(Fraction {
+            numerator: numerator.checked_div(divisor)?.checked_mul(sign)?,
+            denominator: denominator.checked_div(divisor)?.checked_mul(sign)?,
+        })
+    }
+
+    pub fn numerator(&self) -> i64 {
+        self.numerator
+    }
+
+    /// Always positive.
+    pub fn denominator(&self) -> i64 {
+        self.denominator
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.denominator == 1
+    }
+
+    pub fn checked_add(self, other: Fraction) -> Option<Fraction> {
+        let numerator = self
+            .numerator
+            .checked_mul(other.denominator)?
+            .checked_add(other.numerator.checked_mul(self.denominator)?)?;
+        Fraction::new(numerator, self.denominator.checked_mul(other.denominator)?)
+    }
+
+    pub fn checked_sub(self, other: Fraction) -> Option<Fraction> {
+        self.checked_add(other.checked_neg()?)
+    }
+
+    pub fn checked_mul(self, other: Fraction) -> Option<Fraction> {
+        Fraction::new(
+            self.numerator.checked_mul(other.numerator)?,
+            self.denominator.checked_mul(other.denominator)?,
+        )
+    }
+
+    pub fn checked_div(self, other: Fraction) -> Option<Fraction> {
+        Fraction::new(
+            self.numerator.checked_mul(other.denominator)?,
+            self.denominator.checked_mul(other.numerator)?,
+        )
+    }
+
+    /// The remainder of a division that truncates toward zero, like `%` on integers.
+    pub fn checked_rem(self, other: Fraction) -> Option<Fraction> {
+        let quotient = self.checked_div(other)?.trunc();
+        self.checked_sub(other.checked_mul(quotient)?)
+    }
+
+    pub fn checked_neg(self) -> Option<Fraction> {
+        Some(Fraction {
+            numerator: self.numerator.checked_neg()?,
+            denominator: self.denominator,
+        })
+    }
+
+    pub fn floor(self) -> Fraction {
+        Fraction::from_integer(self.numerator.div_euclid(self.denominator))
+    }
+
+    pub fn ceil(self) -> Fraction {
+        let floor = self.numerator.div_euclid(self.denominator);
+        if self.numerator.rem_euclid(self.denominator) == 0 {
+            Fraction::from_integer(floor)
+        } else {
+            Fraction::from_integer(floor + 1)
+        }
+    }
+
+    pub fn trunc(self) -> Fraction {
+        Fraction::from_integer(self.numerator / self.denominator)
+    }
+
+    /// Rounds half away from zero.
+    pub fn round(self) -> Fraction {
+        // `remainder * 2 >= denominator` without overflowing
+        let remainder = (self.numerator % self.denominator).abs();
+        if remainder >= self.denominator - remainder {
+            Fraction::from_integer(self.trunc().numerator + self.numerator.signum())
+        } else {
+            self.trunc()
+        }
+    }
+
+    /// Rounds to a whole number with the given rounding mode.
+    pub fn round_with(self, mode: RoundingMode) -> Fraction {
+        match mode {
+            RoundingMode::Down => self.floor(),
+            RoundingMode::Up => self.ceil(),
+            RoundingMode::Nearest => self.round(),
+            RoundingMode::TowardZero => self.trunc(),
+        }
+    }
+
+    /// The decimal representation, or `None` if it is infinitely (or unreasonably) long, e.g. for
+    /// `1/3`.
+    pub fn to_decimal(&self) -> Option<String> {
+        // the denominator is a divisor of a power of 10 if it only consists of 2s and 5s
+        let (mut rest, mut twos, mut fives) = (self.denominator, 0u32, 0u32);
+        while rest % 2 == 0 {
+            rest /= 2;
+            twos += 1;
+        }
+        while rest % 5 == 0 {
+            rest /= 5;
+            fives += 1;
+        }
+        if rest != 1 {
+            return None;
+        }
+        let digits = twos.max(fives);
+        if digits == 0 {
+            return Some(self.numerator.to_string());
+        }
+
+        let power = 10u128.checked_pow(digits)?;
+        let scaled = (self.numerator.unsigned_abs() as u128)
+            .checked_mul(power / self.denominator as u128)?;
+        let sign = if self.numerator < 0 { "-" } else { "" };
+        Some(format!(
+            "{}{}.{:0width$}",
+            sign,
+            scaled / power,
+            scaled % power,
+            width = digits as usize
+        ))
+    }
+}
+
+impl PartialOrd for Fraction {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Fraction {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // denominators are positive, so cross multiplying keeps the order
+        let left = self.numerator as i128 * other.denominator as i128;
+        let right = other.numerator as i128 * self.denominator as i128;
+        left.cmp(&right)
+    }
+}
+
+impl From<i32> for Fraction {
+    fn from(value: i32) -> Self {
+        Fraction::from_integer(value.into())
+    }
+}
+
+impl fmt::Display for Fraction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.to_decimal() {
+            Some(decimal) => write!(f, "{}", decimal),
+            None => write!(f, "{}/{}", self.numerator, self.denominator),
+        }
+    }
+}
+
+/// An error while parsing a fraction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FractionParseError {
+    /// The input is not a decimal or quotient.
+    Invalid(String),
+    /// The number does not fit into the value model.
+    NumberTooLarge(String),
+    /// A quotient with a denominator of 0.
+    DivisionByZero(String),
+}
+
+impl fmt::Display for FractionParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FractionParseError::Invalid(input) => write!(f, "`{}` is not a number", input),
+            FractionParseError::NumberTooLarge(input) => {
+                write!(f, "The number {} is too large", input)
+            }
+            FractionParseError::DivisionByZero(input) => {
+                write!(f, "`{}` divides by zero", input)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FractionParseError {}
+
+impl FromStr for Fraction {
+    type Err = FractionParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let input = s.trim();
+        if let Some((numerator, denominator)) = input.split_once('/') {
+            let numerator = parse_integer(numerator.trim(), s)?;
+            let denominator = parse_integer(denominator.trim(), s)?;
+            if denominator == 0 {
+                return Err(FractionParseError::DivisionByZero(s.to_string()));
+            }
+            return Fraction::new(numerator, denominator)
+                .ok_or_else(|| FractionParseError::NumberTooLarge(s.to_string()));
+        }
+
+        let (whole, decimals) = input.split_once(['.', ',']).unwrap_or((input, ""));
+        let unsigned = whole.strip_prefix(['-', '+']).unwrap_or(whole);
+        let is_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+        if unsigned.is_empty() || !is_digits(unsigned) || !is_digits(decimals) {
+            return Err(FractionParseError::Invalid(s.to_string()));
+        }
+        if input.ends_with(['.', ',']) {
+            return Err(FractionParseError::Invalid(s.to_string()));
+        }
+
+        let too_large = || FractionParseError::NumberTooLarge(s.to_string());
+        let denominator = u32::try_from(decimals.len())
+            .ok()
+            .and_then(|digits| 10i64.checked_pow(digits))
+            .ok_or_else(too_large)?;
+        let numerator = parse_integer(&format!("{}{}", whole, decimals), s)?;
+        Fraction::new(numerator, denominator).ok_or_else(too_large)
+    }
+}
+
+fn parse_integer(text: &str, input: &str) -> Result<i64, FractionParseError> {
+    let unsigned = text.strip_prefix(['-', '+']).unwrap_or(text);
+    if unsigned.is_empty() || !unsigned.chars().all(|c| c.is_ascii_digit()) {
+        return Err(FractionParseError::Invalid(input.to_string()));
+    }
+    text.parse()
+        .map_err(|_| FractionParseError::NumberTooLarge(input.to_string()))
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a.min(i64::MAX as u64) as i64
+}
+
+impl From<Fraction> for StaticValueType {
+    /// Whole numbers that fit into a [`StaticValueType::Number`] become one, all other values a
+    /// [`StaticValueType::Fraction`].
+    fn from(value: Fraction) -> Self {
+        match i32::try_from(value.numerator) {
+            Ok(n) if value.is_integer() => StaticValueType::Number(n),
+            _ => StaticValueType::Fraction(value),
+        }
+    }
+}
+
+impl RoundingMode {
+    pub const ALL: [RoundingMode; 4] = [
+        RoundingMode::Down,
+        RoundingMode::Up,
+        RoundingMode::Nearest,
+        RoundingMode::TowardZero,
+    ];
+
+    /// The name used in arguments, e.g. of the `average` selector.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RoundingMode::Down => "down",
+            RoundingMode::Up => "up",
+            RoundingMode::Nearest => "nearest",
+            RoundingMode::TowardZero => "towardZero",
+        }
+    }
+}
+
+impl fmt::Display for RoundingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for RoundingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RoundingMode::ALL
+            .into_iter()
+            .find(|mode| mode.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown rounding `{}`, expected `down`, `up`, `nearest` or `towardZero`",
+                    s
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fraction, FractionParseError};
+    use crate::character_sheet_collection::{RoundingMode, StaticValueType};
+
+    fn fraction(numerator: i64, denominator: i64) -> Fraction {
+        Fraction::new(numerator, denominator).unwrap()
+    }
+
+    #[test]
+    fn parse_and_format() {
+        let cases = [
+            ("1.5", fraction(3, 2), "1.5"),
+            ("1,5", fraction(3, 2), "1.5"),
+            ("-0.25", fraction(-1, 4), "-0.25"),
+            ("2.50", fraction(5, 2), "2.5"),
+            ("7", fraction(7, 1), "7"),
+            ("+3", fraction(3, 1), "3"),
+            ("1/3", fraction(1, 3), "1/3"),
+            ("4 / -6", fraction(-2, 3), "-2/3"),
+            ("0.125", fraction(1, 8), "0.125"),
+            ("-7/20", fraction(-7, 20), "-0.35"),
+        ];
+        for (input, expected, formatted) in cases {
+            let parsed: Fraction = input.parse().unwrap();
+            assert_eq!(parsed, expected, "{}", input);
+            assert_eq!(parsed.to_string(), formatted, "{}", input);
+            assert_eq!(formatted.parse::<Fraction>(), Ok(parsed), "{}", formatted);
+        }
+
+        assert_eq!(
+            "1.".parse::<Fraction>(),
+            Err(FractionParseError::Invalid("1.".to_string()))
+        );
+        assert_eq!(
+            "one".parse::<Fraction>(),
+            Err(FractionParseError::Invalid("one".to_string()))
+        );
+        assert_eq!(
+            "1/0".parse::<Fraction>(),
+            Err(FractionParseError::DivisionByZero("1/0".to_string()))
+        );
+        assert_eq!(
+            "99999999999999999999".parse::<Fraction>(),
+            Err(FractionParseError::NumberTooLarge(
+                "99999999999999999999".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn rounding() {
+        let round = |value: &str, mode: RoundingMode| {
+            value
+                .parse::<Fraction>()
+                .unwrap()
+                .round_with(mode)
+                .numerator()
+        };
+        let cases = [
+            ("2.5", [2, 3, 3, 2]),
+            ("-2.5", [-3, -2, -3, -2]),
+            ("2.4", [2, 3, 2, 2]),
+            ("-2.6", [-3, -2, -3, -2]),
+            ("3", [3, 3, 3, 3]),
+            ("9223372036854775806/9223372036854775807", [0, 1, 1, 0]),
+            ("-9223372036854775806/9223372036854775807", [-1, 0, -1, 0]),
+            ("4611686018427387903/9223372036854775807", [0, 1, 0, 0]),
+        ];
+        for (value, expected) in cases {
+            for (mode, expected) in RoundingMode::ALL.into_iter().zip(expected) {
+                assert_eq!(round(value, mode), expected, "{} {}", value, mode);
+            }
+        }
+        assert_eq!("towardZero".parse(), Ok(RoundingMode::TowardZero));
+    }
+
+    #[test]
+    fn static_values() {
+        assert_eq!(
+            StaticValueType::from(fraction(4, 2)),
+            StaticValueType::Number(2)
+        );
+        assert_eq!(
+            StaticValueType::from(fraction(3, 2)),
+            StaticValueType::Fraction(fraction(3, 2))
+        );
+        assert_eq!(
+            StaticValueType::from(Fraction::from_integer(1 << 40)),
+            StaticValueType::Fraction(Fraction::from_integer(1 << 40))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde_json")]
+    fn serde() {
+        let json = serde_json::to_string(&fraction(3, 2)).unwrap();
+        assert_eq!(json, r#"{"numerator":3,"denominator":2}"#);
+        assert_eq!(
+            serde_json::from_str::<Fraction>(r#"{"numerator":6,"denominator":-4}"#).unwrap(),
+            fraction(-3, 2)
+        );
+        assert!(serde_json::from_str::<Fraction>(r#"{"numerator":1,"denominator":0}"#).is_err());
+    }
+}
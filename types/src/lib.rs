@@ -1 +1,3 @@
 pub mod character_sheet_collection;
+pub mod dice_notation;
+pub mod fraction;